@@ -1,9 +1,32 @@
 use clap::{Parser, Subcommand};
-use mid_text_converter::instruments::{InstrumentKind, Instruments};
+use mid_text_converter::abc::{self, AbcOptions};
+use mid_text_converter::budget::{self, Budgeted};
+use mid_text_converter::chunk::{self, Chunk};
+use mid_text_converter::datapack::{self, DatapackOptions};
+use mid_text_converter::diff;
+use mid_text_converter::instruments::{InstrumentKind, Instruments, Track};
+use mid_text_converter::lua::{self, LuaOptions};
+use mid_text_converter::midi2;
+use mid_text_converter::mml::{self, MmlOptions};
+use mid_text_converter::musicxml;
+use mid_text_converter::nbs::{self, NbsMeta};
+use mid_text_converter::note::Note;
+use mid_text_converter::notelist::{self, NoteListFormat};
+use mid_text_converter::optimize::{self, Nudged};
+use mid_text_converter::pianoroll::{self, PianoRollOptions};
+use mid_text_converter::player::{self, PlayerOptions};
 use mid_text_converter::song::mid::mid_to_track;
+use mid_text_converter::render::{self, MixOptions};
+use mid_text_converter::samples::SamplePack;
+use mid_text_converter::smf;
 use mid_text_converter::song::Song;
+use mid_text_converter::structure::{self, StructureOptions};
+use mid_text_converter::tab::{self, TabOptions};
+use mid_text_converter::transcribe::{self, TranscribeOptions};
+use mid_text_converter::tracker::{self, InstrumentMap, TrackerError};
 use mid_text_converter::utils;
 use arboard::Clipboard;
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[command(version)]
@@ -27,6 +50,7 @@ struct Args {
 enum Mode {
     #[clap(arg_required_else_help = true)]
     #[clap(about = "midiファイルから曲を作る")]
+    #[clap(after_help = "対応する形式: .mid, .midi2 (MIDI 2.0のクリップ), .musicxml/.xml/.mxl (score.mxl#P1 でパートや声部を選ぶ), .mml (song.mml#2 でチャンネルを選ぶ), .abc (tune.abc#2 で声部を選ぶ), .csv/.json (音符の一覧かmidicsv), .mod/.xm/.it (song.xm#3 でサンプルを選ぶ), .tab (riff.tab#DADGAD でチューニングを変える), .wav/.ogg (単音の録音から音程を聞き取る。実験的)")]
     #[clap(visible_alias = "c")]
     Create(Box<InstArgs>),
     #[clap(arg_required_else_help = true)]
@@ -41,59 +65,437 @@ enum Mode {
         #[arg(short = 'c', long)]
         copy: bool,
     },
+    #[clap(arg_required_else_help = true)]
+    #[clap(about = "複数の文字列を順番に繋げる")]
+    #[clap(visible_alias = "cat")]
+    Concat {
+        /// 繋げたい二個以上の文字列
+        #[arg(required = true, num_args = 2..)]
+        songs: Vec<String>,
+
+        /// 曲と曲の間に入れる無音のtick数
+        #[arg(short = 'g', long, default_value_t = 0, conflicts_with = "gap_seconds")]
+        gap: u32,
+
+        /// 曲と曲の間に入れる無音の秒数
+        #[arg(short = 's', long)]
+        gap_seconds: Option<f64>,
+
+        /// 各曲の開始位置をこのtick数の小節の頭に揃える
+        #[arg(short = 'b', long)]
+        bar: Option<u32>,
+
+        /// 繋げた文字列をクリップボードにコピーする
+        #[arg(short = 'c', long)]
+        copy: bool,
+    },
+    #[clap(arg_required_else_help = true)]
+    #[clap(about = "長い文字列を指定した文字数以下に分割する")]
+    #[clap(visible_alias = "s")]
+    Split {
+        /// 分割したい文字列
+        song: String,
+
+        /// 一つの文字列の最大文字数
+        #[arg(short = 'l', long)]
+        max_len: usize,
+
+        /// 分割する時に優先する小節のtick数
+        #[arg(short = 'b', long)]
+        bar: Option<u32>,
+
+        /// 分割した文字列を一つずつクリップボードにコピーする
+        #[arg(short = 'c', long)]
+        copy: bool,
+    },
+    #[clap(arg_required_else_help = true)]
+    #[clap(about = "重要度の低い音を削って文字列を指定した文字数以下に収める")]
+    #[clap(visible_alias = "f")]
+    Fit {
+        /// 収めたい文字列
+        song: String,
+
+        /// 最大文字数
+        #[arg(short = 'l', long)]
+        max_len: usize,
+
+        /// 削った音を一つずつ表示する
+        #[arg(short = 'd', long)]
+        detail: bool,
+
+        /// 収めた文字列をクリップボードにコピーする
+        #[arg(short = 'c', long)]
+        copy: bool,
+    },
+    #[clap(arg_required_else_help = true)]
+    #[clap(about = "音を少しずらして文字列を短くする")]
+    #[clap(visible_alias = "o")]
+    Optimize {
+        /// 短くしたい文字列
+        song: String,
+
+        /// 音をずらしてよい最大のtick数
+        #[arg(short = 'n', long, default_value_t = 1)]
+        nudge: u32,
+
+        /// 短くした文字列をクリップボードにコピーする
+        #[arg(short = 'c', long)]
+        copy: bool,
+    },
+    #[clap(arg_required_else_help = true)]
+    #[clap(about = "文字列をmidiファイルに書き出す")]
+    #[clap(visible_alias = "tm")]
+    ToMidi {
+        /// 書き出したい文字列
+        song: String,
+
+        /// 書き出すmidファイル
+        #[arg(short = 'o', long)]
+        output: PathBuf,
+
+        /// 全ての音の長さをこのtick数にする。指定しない場合は次の音まで伸ばす
+        #[arg(short = 'l', long)]
+        note_length: Option<u32>,
+
+        /// 楽器ごとに別のmidファイルへ書き出す
+        #[arg(short = 's', long)]
+        stems: bool,
+    },
+    #[clap(arg_required_else_help = true)]
+    #[clap(about = "文字列をNote Block Studioのnbsファイルに書き出す")]
+    #[clap(visible_alias = "tn")]
+    ToNbs {
+        /// 書き出したい文字列
+        song: String,
+
+        /// 書き出すnbsファイル
+        #[arg(short = 'o', long)]
+        output: PathBuf,
+
+        /// 曲名。指定しない場合はファイル名を使う
+        #[arg(short = 'n', long)]
+        name: Option<String>,
+
+        /// 作者
+        #[arg(short = 'a', long, default_value = "")]
+        author: String,
+
+        /// 原曲の作者
+        #[arg(long, default_value = "")]
+        original_author: String,
+
+        /// 説明
+        #[arg(short = 'd', long, default_value = "")]
+        description: String,
+    },
+    #[clap(arg_required_else_help = true)]
+    #[clap(about = "文字列をplaysoundで演奏するデータパックに書き出す")]
+    #[clap(visible_alias = "tdp")]
+    ToDatapack {
+        /// 書き出したい文字列
+        song: String,
+
+        /// 書き出すデータパックのフォルダ
+        #[arg(short = 'o', long)]
+        output: PathBuf,
+
+        /// 関数の名前空間
+        #[arg(long, default_value = "mid2text")]
+        namespace: String,
+
+        /// 曲の関数をまとめるフォルダ名
+        #[arg(short = 'n', long, default_value = "song")]
+        name: String,
+
+        /// 曲を聞かせるプレイヤーのセレクター
+        #[arg(short = 's', long, default_value = "@a")]
+        selector: String,
+
+        /// pack.mcmetaのpack_format
+        #[arg(long, default_value_t = 48)]
+        pack_format: u32,
+    },
+    #[clap(arg_required_else_help = true)]
+    #[clap(about = "文字列を音符ブロックの回路としてストラクチャーのnbtファイルに書き出す")]
+    #[clap(visible_alias = "ts")]
+    ToStructure {
+        /// 書き出したい文字列
+        song: String,
+
+        /// 書き出すnbtファイル
+        #[arg(short = 'o', long)]
+        output: PathBuf,
+
+        /// 回路の最大の幅(ブロック)
+        #[arg(short = 'w', long, default_value_t = 48)]
+        max_width: u32,
+
+        /// 回路の最大の長さ(ブロック)
+        #[arg(short = 'l', long)]
+        max_length: Option<u32>,
+    },
+    #[clap(arg_required_else_help = true)]
+    #[clap(about = "文字列をCC:Tweakedのスピーカーで演奏するLuaプログラムに書き出す")]
+    #[clap(visible_alias = "tl")]
+    ToLua {
+        /// 書き出したい文字列
+        song: String,
+
+        /// 書き出すluaファイル
+        #[arg(short = 'o', long)]
+        output: PathBuf,
+
+        /// 音量(0.0〜3.0)
+        #[arg(short = 'v', long, default_value_t = 1.0)]
+        volume: f32,
+
+        /// 1つのデータテーブルに入れる音の数
+        #[arg(long, default_value_t = 2000)]
+        part_size: usize,
+    },
+    #[clap(arg_required_else_help = true)]
+    #[clap(about = "文字列をMMLに書き出す")]
+    #[clap(visible_alias = "tmml")]
+    ToMml {
+        /// 書き出したい文字列
+        song: String,
+
+        /// 書き出すファイル。指定しない場合は標準出力に表示する
+        #[arg(short = 'o', long)]
+        output: Option<PathBuf>,
+
+        /// テンポ(1分あたりの4分音符の数)
+        #[arg(short = 't', long, default_value_t = 150)]
+        tempo: u32,
+
+        /// 最も短い音符(16なら16分音符)。音符はこの長さにそろえられる
+        #[arg(short = 's', long, default_value_t = 16)]
+        shortest: u32,
+    },
+    #[clap(arg_required_else_help = true)]
+    #[clap(about = "文字列をABC記譜法の楽譜に書き出す")]
+    #[clap(visible_alias = "tabc")]
+    ToAbc {
+        /// 書き出したい文字列
+        song: String,
+
+        /// 書き出すファイル。指定しない場合は標準出力に表示する
+        #[arg(short = 'o', long)]
+        output: Option<PathBuf>,
+
+        /// 曲名
+        #[arg(short = 't', long, default_value = "")]
+        title: String,
+
+        /// 1小節の長さ(tick)。指定しない場合は音の間隔から推定する
+        #[arg(short = 'b', long)]
+        bar: Option<u32>,
+    },
+    #[clap(arg_required_else_help = true)]
+    #[clap(about = "文字列を音符の一覧(csv, json, midicsv)に書き出す")]
+    #[clap(visible_alias = "tnl")]
+    ToNoteList {
+        /// 書き出したい文字列
+        song: String,
+
+        /// 書き出すファイル
+        #[arg(short = 'o', long)]
+        output: PathBuf,
+
+        /// 形式(csv, json, midicsv)。指定しない場合は拡張子から決める
+        #[arg(short = 'f', long)]
+        format: Option<String>,
+
+        /// 全ての音の長さをこのtick数にする。指定しない場合は次の音まで伸ばす
+        #[arg(short = 'l', long)]
+        note_length: Option<u32>,
+    },
+    #[clap(arg_required_else_help = true)]
+    #[clap(about = "文字列をピアノロールのsvgファイルに書き出す")]
+    #[clap(visible_alias = "tsvg")]
+    ToSvg {
+        /// 書き出したい文字列
+        song: String,
+
+        /// 書き出すsvgファイル
+        #[arg(short = 'o', long)]
+        output: PathBuf,
+
+        /// 1小節の長さ(tick)。指定しない場合は音の間隔から推定する
+        #[arg(short = 'b', long)]
+        bar: Option<u32>,
+
+        /// 1tickの幅(px)
+        #[arg(long, default_value_t = 6.0)]
+        tick_width: f32,
+
+        /// 1音の高さ(px)
+        #[arg(long, default_value_t = 6.0)]
+        key_height: f32,
+    },
+    #[clap(arg_required_else_help = true)]
+    #[clap(about = "文字列をブラウザで再生できる1つのhtmlファイルに書き出す")]
+    #[clap(visible_alias = "thtml")]
+    ToHtml {
+        /// 書き出したい文字列
+        song: String,
+
+        /// 書き出すhtmlファイル
+        #[arg(short = 'o', long)]
+        output: PathBuf,
+
+        /// 曲名
+        #[arg(short = 't', long, default_value = "")]
+        title: String,
+
+        /// コピー用の文字列をこの文字数以下に分割する
+        #[arg(short = 'l', long)]
+        max_len: Option<usize>,
+
+        /// 1小節の長さ(tick)。指定しない場合は音の間隔から推定する
+        #[arg(short = 'b', long)]
+        bar: Option<u32>,
+    },
+    #[clap(arg_required_else_help = true)]
+    #[clap(about = "文字列をwavファイルに書き出す")]
+    #[clap(visible_alias = "r")]
+    Render {
+        /// 書き出したい文字列
+        song: String,
+
+        /// 書き出すwavファイル
+        #[arg(short = 'o', long)]
+        output: PathBuf,
+
+        /// 楽器ごとの音源(harp.oggなど)が入ったフォルダ。指定しない場合は内蔵シンセサイザーを使う
+        #[arg(short = 's', long)]
+        samples: Option<PathBuf>,
+
+        /// 同時に鳴らせる音の数
+        #[arg(short = 'p', long)]
+        polyphony: Option<usize>,
+
+        /// 音量をこの最大値に揃える
+        #[arg(long, default_value_t = 0.9, conflicts_with = "no_normalize")]
+        peak: f32,
+
+        /// 音量を揃えない
+        #[arg(long)]
+        no_normalize: bool,
+    },
+    #[clap(arg_required_else_help = true)]
+    #[clap(about = "二つの文字列の音楽的な違いを表示する")]
+    #[clap(visible_alias = "d")]
+    Diff {
+        /// 変更前の文字列
+        old: String,
+        /// 変更後の文字列
+        new: String,
+
+        /// 追加・削除された音を一つずつ表示する
+        #[arg(short = 'd', long)]
+        detail: bool,
+    },
 }
 
 #[derive(Debug, clap::Args)]
 struct InstArgs {
-    /// plingに変換するmidファイル
+    /// plingに変換するファイル
     #[arg(short = 'p', long, num_args = 0..)]
     pling: Vec<String>,
-    /// hatに変換するmidファイル
+    /// hatに変換するファイル
     #[arg(short = 'h', long, num_args = 0..)]
     hat: Vec<String>,
-    /// snareに変換するmidファイル
+    /// snareに変換するファイル
     #[arg(short = 's', long, num_args = 0..)]
     snare: Vec<String>,
-    /// bassdrumに変換するmidファイル
+    /// bassdrumに変換するファイル
     #[arg(short = 'b', long, num_args = 0..)]
     bassdrum: Vec<String>,
-    /// bassに変換するmidファイル
+    /// bassに変換するファイル
     #[arg(long, num_args = 0..)]
     bass: Vec<String>,
-    /// bellに変換するmidファイル
+    /// bellに変換するファイル
     #[arg(long, num_args = 0..)]
     bell: Vec<String>,
-    /// chimeに変換するmidファイル
+    /// chimeに変換するファイル
     #[arg(long, num_args = 0..)]
     chime: Vec<String>,
-    /// fluteに変換するmidファイル
+    /// fluteに変換するファイル
     #[arg(short = 'f', long, num_args = 0..)]
     flute: Vec<String>,
-    /// guitarに変換するmidファイル
+    /// guitarに変換するファイル
     #[arg(short = 'g', long, num_args = 0..)]
     guitar: Vec<String>,
-    /// harpに変換するmidiファイル
+    /// harpに変換するファイル
     #[arg(long, num_args = 0..)]
     harp: Vec<String>,
-    /// xylophoneに変換するmidファイル
+    /// xylophoneに変換するファイル
     #[arg(short = 'x', long, num_args = 0..)]
     xylophone: Vec<String>,
+    /// そのまま曲に追加するNote Block Studioのnbsファイル
+    #[arg(long, num_args = 0..)]
+    nbs: Vec<String>,
+    /// 楽器ごとにそのまま曲に追加する音符の一覧(csv, json, midicsv)
+    #[arg(long, num_args = 0..)]
+    note_list: Vec<String>,
+    /// 楽器ごとにそのまま曲に追加するトラッカーのモジュール(.mod, .xm, .it)
+    #[arg(long, num_args = 0..)]
+    module: Vec<String>,
+    /// モジュールのサンプル番号と楽器の対応 (例: 1=harp,2=bassdrum)
+    #[arg(long, num_args = 0.., requires = "module")]
+    module_map: Vec<String>,
+    /// 対応を指定していないサンプルの楽器。指定しなければその音は捨てる
+    #[arg(long, requires = "module")]
+    module_default: Option<String>,
+    /// タブ譜(.tab)の弦のチューニング。低い弦から (例: EADGBE, DADGAD, E1A1D2G2)
+    #[arg(long, default_value = "EADGBE")]
+    tab_tuning: String,
+    /// タブ譜の1文字分のtick数
+    #[arg(long, default_value_t = 1)]
+    tab_ticks: u32,
+    /// 録音(.wav, .ogg)の音程をこの信頼度(0〜1)以上の時だけ音にする
+    #[arg(long, default_value_t = 0.85)]
+    wav_confidence: f32,
+    /// 録音でこの音量(RMS)より小さい所を無音とみなす
+    #[arg(long, default_value_t = 0.02)]
+    wav_silence: f32,
 
     /// 範囲外の音を範囲内のオクターブへ相対的に移動する
     #[arg(short = 'r', long, )]
     relative: bool,
     
+    /// 範囲外の音を移動する前の曲をピアノロールのsvgファイルに書き出す
+    #[arg(long)]
+    svg: Option<PathBuf>,
+
     /// 作成した文字列をクリップボードにコピーする
     #[arg(short = 'c', long)]
     copy: bool,
+
+    /// 文字列をこの文字数以下に分割する
+    #[arg(short = 'l', long)]
+    max_len: Option<usize>,
+
+    /// 分割する時に優先する小節のtick数
+    #[arg(long, requires = "max_len")]
+    bar: Option<u32>,
+
+    /// 重要度の低い音を削って文字列をこの文字数以下に収める
+    #[arg(long, conflicts_with = "max_len")]
+    budget: Option<usize>,
+
+    /// 文字列が短くなるなら音をこのtick数まで前後にずらす
+    #[arg(short = 'n', long, conflicts_with = "budget")]
+    nudge: Option<u32>,
 }
 
 macro_rules! add_instruments {
-    ($song:expr, $args:expr, $( ($field:ident, $kind:expr) ),* ) => {
+    ($song:expr, $args:expr, $options:expr, $( ($field:ident, $kind:expr) ),* ) => {
         $(
             if !$args.$field.is_empty() {
                 for path in &$args.$field {
-                    let track = mid_to_track(path)?;
+                    let track = load_track(path, $options)?;
                     let instrument = Instruments::new($kind, track);
                     $song.add_track(instrument);
                 }
@@ -102,6 +504,75 @@ macro_rules! add_instruments {
     };
 }
 
+/// 拡張子ごとの読み込みの設定
+struct LoadOptions {
+    tab: TabOptions,
+    transcribe: TranscribeOptions,
+}
+
+/// 拡張子に合わせてファイルを読み込む。`#`の後ろでパートやチャンネルを選べる
+fn load_track(path: &str, options: &LoadOptions) -> Result<Track, Box<dyn std::error::Error>> {
+    let file = path.split('#').next().unwrap_or(path).to_lowercase();
+    if file.ends_with(".musicxml") || file.ends_with(".xml") || file.ends_with(".mxl") {
+        musicxml::musicxml_to_track(path)
+    } else if file.ends_with(".mml") {
+        mml::mml_to_track(path)
+    } else if file.ends_with(".abc") {
+        abc::abc_to_track(path)
+    } else if file.ends_with(".csv") || file.ends_with(".json") {
+        notelist::note_list_to_track(path)
+    } else if file.ends_with(".mod") || file.ends_with(".xm") || file.ends_with(".it") {
+        tracker::module_to_track(path)
+    } else if file.ends_with(".tab") {
+        tab::tab_to_track(path, &options.tab)
+    } else if file.ends_with(".midi2") {
+        midi2::clip_to_track(path)
+    } else if file.ends_with(".wav") || file.ends_with(".ogg") {
+        transcribe::recording_to_track(path, &options.transcribe)
+    } else {
+        mid_to_track(path)
+    }
+}
+
+/// 分割した文字列に番号を付けて表示する。`copy`の時はEnterを押すごとに次の文字列をコピーする
+fn print_chunks(chunks: &[Chunk], copy: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let mut clipboard = if copy { Some(Clipboard::new()?) } else { None };
+    for (i, chunk) in chunks.iter().enumerate() {
+        println!("[{}/{}] (tick {}) {}", i + 1, chunks.len(), chunk.start, chunk.text);
+        if let Some(clipboard) = clipboard.as_mut() {
+            clipboard.set_text(chunk.text.clone())?;
+            if i + 1 < chunks.len() {
+                println!("コピーしました。Enterで次へ");
+                std::io::stdin().read_line(&mut String::new())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 削った音の数を理由ごとに標準エラーへ表示する
+fn print_budgeted(result: &Budgeted, detail: bool) {
+    for (reason, count) in result.summary() {
+        eprintln!("removed {} {}", count, reason);
+    }
+    if detail {
+        for note in &result.removed {
+            eprintln!("  {:?} tick {} {} ({})", note.kind, note.tick, Note::key_name(note.key), note.reason);
+        }
+    }
+}
+
+/// 短くなった文字数とずらした量を標準エラーへ表示する
+fn print_nudged(result: &Nudged) {
+    eprintln!("saved {} characters, moved {} ticks by up to {} ticks", result.saved, result.moved, result.max_shift);
+}
+
+/// 音符ブロックの音域外でオクターブ移動して鳴る音の数を標準エラーへ表示する
+fn print_out_of_range(count: usize) {
+    if count > 0 {
+        eprintln!("{} notes are out of the note block range and play moved by octaves", count);
+    }
+}
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
@@ -109,10 +580,15 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     match &args.mode {
         Some(Mode::Create(create_args)) => {
             let mut song = Song::new();
+            let load_options = LoadOptions {
+                tab: TabOptions { tuning: tab::parse_tuning(&create_args.tab_tuning)?, column_ticks: create_args.tab_ticks },
+                transcribe: TranscribeOptions { confidence: create_args.wav_confidence, silence: create_args.wav_silence, ..TranscribeOptions::default() },
+            };
 
             add_instruments!(
                 song,
                 create_args,
+                &load_options,
                 (pling, InstrumentKind::Pling),
                 (hat, InstrumentKind::Hat),
                 (snare, InstrumentKind::Snare),
@@ -126,6 +602,84 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 (xylophone, InstrumentKind::Xylophone)
             );
 
+            for path in &create_args.nbs {
+                let (nbs_song, _) = nbs::read_nbs(path)?;
+                song.end = song.end.max(nbs_song.end);
+                for track in nbs_song.tracks {
+                    song.add_track(track);
+                }
+            }
+
+            for path in &create_args.note_list {
+                let list_song = notelist::read_note_list(std::path::Path::new(path))?;
+                song.end = song.end.max(list_song.end);
+                for track in list_song.tracks {
+                    song.add_track(track);
+                }
+            }
+
+            if !create_args.module.is_empty() {
+                let default = match &create_args.module_default {
+                    Some(name) => Some(InstrumentKind::from_name(name).ok_or_else(|| TrackerError::InvalidMapping(name.clone()))?),
+                    None => None,
+                };
+                let map = InstrumentMap::parse(&create_args.module_map, default)?;
+                for path in &create_args.module {
+                    let module = tracker::read_module(std::path::Path::new(path))?;
+                    for (number, name) in &module.names {
+                        if map.kind(*number).is_none() {
+                            eprintln!("{}: sample {} ({}) is not mapped", path, number, name);
+                        }
+                    }
+                    let module_song = tracker::module_to_song(&module, &map);
+                    song.end = song.end.max(module_song.end);
+                    for track in module_song.tracks {
+                        song.add_track(track);
+                    }
+                }
+            }
+
+            if let Some(path) = &create_args.svg {
+                pianoroll::write_svg(&song, path, &PianoRollOptions { bar: create_args.bar, ..PianoRollOptions::default() })?;
+                eprintln!("{}", path.display());
+            }
+
+            if let Some(max_nudge) = create_args.nudge {
+                let result = optimize::nudge(&song.to_text(create_args.relative)?, max_nudge);
+                print_nudged(&result);
+                if let Some(max_len) = create_args.max_len {
+                    print_chunks(&chunk::chunk_string(&result.text, max_len, create_args.bar)?, create_args.copy)?;
+                } else {
+                    println!("{}", &result.text);
+                    if create_args.copy {
+                        let mut clipboard = Clipboard::new()?;
+                        clipboard.set_text(result.text)?;
+                    }
+                }
+                return Ok(());
+            }
+
+            if let Some(max_len) = create_args.max_len {
+                let chunks = song.to_chunks(create_args.relative, max_len, create_args.bar)?;
+                if chunks.is_empty() {
+                    println!("Midi file is empty");
+                } else {
+                    print_chunks(&chunks, create_args.copy)?;
+                }
+                return Ok(());
+            }
+
+            if let Some(budget) = create_args.budget {
+                let result = budget::fit_to_budget(&song, budget, create_args.relative)?;
+                print_budgeted(&result, false);
+                println!("{}", &result.text);
+                if create_args.copy {
+                    let mut clipboard = Clipboard::new()?;
+                    clipboard.set_text(result.text)?;
+                }
+                return Ok(());
+            }
+
             let result = song.to_text(create_args.relative);
             
             match result {
@@ -157,6 +711,184 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
             Ok(())
         }
+        Some(Mode::Concat { songs, gap, gap_seconds, bar, copy }) => {
+            let gap = gap_seconds.map(utils::seconds_to_ticks).unwrap_or(*gap);
+            let songs: Vec<&str> = songs.iter().map(String::as_str).collect();
+            let result = Song::concat_texts(&songs, gap, *bar)?;
+            println!("{}", &result);
+            if *copy {
+                let mut clipboard = Clipboard::new()?;
+                clipboard.set_text(result)?;
+            }
+            Ok(())
+        }
+        Some(Mode::Split { song, max_len, bar, copy }) => {
+            let chunks = chunk::chunk_string(song, *max_len, *bar)?;
+            print_chunks(&chunks, *copy)?;
+            Ok(())
+        }
+        Some(Mode::Fit { song, max_len, detail, copy }) => {
+            let result = budget::fit_to_budget(&Song::from_text(song)?, *max_len, false)?;
+            print_budgeted(&result, *detail);
+            println!("{}", &result.text);
+            if *copy {
+                let mut clipboard = Clipboard::new()?;
+                clipboard.set_text(result.text)?;
+            }
+            Ok(())
+        }
+        Some(Mode::Optimize { song, nudge, copy }) => {
+            let result = optimize::nudge(song, *nudge);
+            print_nudged(&result);
+            println!("{}", &result.text);
+            if *copy {
+                let mut clipboard = Clipboard::new()?;
+                clipboard.set_text(result.text)?;
+            }
+            Ok(())
+        }
+        Some(Mode::ToMidi { song, output, note_length, stems }) => {
+            let song = Song::from_text(song)?;
+            if *stems {
+                for path in smf::write_stems(&song, output, *note_length)? {
+                    println!("{}", path.display());
+                }
+            } else {
+                smf::write_song(&song, output, *note_length)?;
+                println!("{}", output.display());
+            }
+            Ok(())
+        }
+        Some(Mode::ToNbs { song, output, name, author, original_author, description }) => {
+            let meta = NbsMeta {
+                name: name.clone().unwrap_or_else(|| {
+                    output.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default()
+                }),
+                author: author.clone(),
+                original_author: original_author.clone(),
+                description: description.clone(),
+            };
+            nbs::write_nbs(&Song::from_text(song)?, output, &meta)?;
+            println!("{}", output.display());
+            Ok(())
+        }
+        Some(Mode::ToDatapack { song, output, namespace, name, selector, pack_format }) => {
+            let options = DatapackOptions {
+                namespace: namespace.clone(),
+                name: name.clone(),
+                selector: selector.clone(),
+                pack_format: *pack_format,
+            };
+            let song = Song::from_text(song)?;
+            datapack::write_datapack(&song, output, &options)?;
+            println!("/function {}:{}/start", namespace, name);
+            println!("/function {}:{}/stop", namespace, name);
+            print_out_of_range(song.out_of_range_notes());
+            Ok(())
+        }
+        Some(Mode::ToStructure { song, output, max_width, max_length }) => {
+            let options = StructureOptions { max_width: *max_width, max_length: *max_length, ..Default::default() };
+            let report = structure::write_structure(&Song::from_text(song)?, output, &options)?;
+            println!("{}", output.display());
+            println!(
+                "size {}x{}x{}, {} lines, {} note blocks",
+                report.size[0], report.size[1], report.size[2], report.lines, report.note_blocks
+            );
+            println!("start: power the dust at {} {} {}", report.start[0], report.start[1], report.start[2]);
+            if report.late > 0 {
+                eprintln!("{} notes play late, by up to {} ticks", report.late, report.max_late);
+            }
+            print_out_of_range(report.moved);
+            Ok(())
+        }
+        Some(Mode::ToLua { song, output, volume, part_size }) => {
+            let options = LuaOptions { volume: *volume, part_size: *part_size };
+            let song = Song::from_text(song)?;
+            lua::write_lua(&song, output, &options)?;
+            println!("{}", output.display());
+            print_out_of_range(song.out_of_range_notes());
+            Ok(())
+        }
+        Some(Mode::ToMml { song, output, tempo, shortest }) => {
+            let options = MmlOptions { tempo: *tempo, shortest: *shortest };
+            let result = mml::song_to_mml(&Song::from_text(song)?, &options)?;
+            match output {
+                Some(output) => {
+                    std::fs::write(output, result.to_string())?;
+                    println!("{}", output.display());
+                }
+                None => print!("{}", result),
+            }
+            for (i, (kind, _)) in result.channels.iter().enumerate() {
+                eprintln!("channel {}: {}", i + 1, kind.name());
+            }
+            eprintln!("{}", result.quantization);
+            Ok(())
+        }
+        Some(Mode::ToAbc { song, output, title, bar }) => {
+            let options = AbcOptions { title: title.clone(), bar: *bar };
+            let result = abc::song_to_abc(&Song::from_text(song)?, &options);
+            match output {
+                Some(output) => {
+                    std::fs::write(output, result)?;
+                    println!("{}", output.display());
+                }
+                None => print!("{}", result),
+            }
+            Ok(())
+        }
+        Some(Mode::ToNoteList { song, output, format, note_length }) => {
+            let format = match format {
+                Some(name) => NoteListFormat::from_name(name).ok_or(format!("Unknown format: {}", name))?,
+                None => NoteListFormat::from_path(output).unwrap_or(NoteListFormat::Csv),
+            };
+            notelist::write_note_list(&Song::from_text(song)?, output, format, *note_length)?;
+            println!("{}", output.display());
+            Ok(())
+        }
+        Some(Mode::ToSvg { song, output, bar, tick_width, key_height }) => {
+            let options = PianoRollOptions { bar: *bar, tick_width: *tick_width, key_height: *key_height };
+            pianoroll::write_svg(&Song::from_text(song)?, output, &options)?;
+            println!("{}", output.display());
+            Ok(())
+        }
+        Some(Mode::ToHtml { song, output, title, max_len, bar }) => {
+            let options = PlayerOptions { title: title.clone(), max_len: *max_len, bar: *bar };
+            player::write_html(&Song::from_text(song)?, output, &options)?;
+            println!("{}", output.display());
+            Ok(())
+        }
+        Some(Mode::Render { song, output, samples, polyphony, peak, no_normalize }) => {
+            let song = Song::from_text(song)?;
+            let options = MixOptions {
+                polyphony: *polyphony,
+                normalize: if *no_normalize { None } else { Some(*peak) },
+            };
+            let pack = match samples {
+                Some(dir) => {
+                    let pack = SamplePack::load(dir)?;
+                    for kind in pack.missing().into_iter().filter(|k| song.tracks.iter().any(|t| t.kind() == *k)) {
+                        eprintln!("no sample for {}, using the built-in synthesizer", kind.name());
+                    }
+                    pack
+                }
+                None => SamplePack::new(),
+            };
+            render::write_wav(&pack.render(&song, &options), output)?;
+            println!("{}", output.display());
+            Ok(())
+        }
+        Some(Mode::Diff { old, new, detail }) => {
+            let result = diff::diff(&Song::from_text(old)?, &Song::from_text(new)?);
+            if result.is_empty() {
+                println!("No differences");
+            } else if *detail {
+                print!("{}", result.detailed());
+            } else {
+                print!("{}", result);
+            }
+            Ok(())
+        }
         _ => {
             unreachable!()
         }
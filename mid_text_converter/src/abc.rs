@@ -0,0 +1,941 @@
+use crate::instruments::Track;
+use crate::musicxml::{self, Barlines, Measure};
+use crate::note::Note;
+use crate::song::Song;
+use crate::utils::{self, TempoMap};
+use midly::num::u7;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use thiserror::Error;
+
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum AbcError {
+    #[error("No voice {0}, available: {1}")]
+    UnknownVoice(String, String),
+}
+
+/// Tempo of tunes without a `Q:` field, in quarter notes per minute.
+pub const DEFAULT_TEMPO: f64 = 120.0;
+
+const LETTERS: [char; 7] = ['C', 'D', 'E', 'F', 'G', 'A', 'B'];
+const NATURALS: [i32; 7] = [0, 2, 4, 5, 7, 9, 11];
+/// Letters in the order sharps and flats are added to a key signature.
+const SHARP_ORDER: [usize; 7] = [3, 0, 4, 1, 5, 2, 6];
+const FLAT_ORDER: [usize; 7] = [6, 2, 5, 1, 4, 0, 3];
+
+const MAJOR_PROFILE: [f64; 12] = [6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88];
+const MINOR_PROFILE: [f64; 12] = [6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17];
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct AbcOptions {
+    pub title: String,
+    /// Bar length in ticks. Estimated from the most common gap between notes if not given.
+    pub bar: Option<u32>,
+}
+
+/// Key signature as the number of sharps (positive) or flats (negative).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Key {
+    pub fifths: i32,
+    pub minor: bool,
+}
+
+impl Key {
+    /// Name used in the `K:` field, e.g. `Bb` or `F#m`.
+    pub fn name(&self) -> &'static str {
+        const MAJOR: [&str; 15] = ["Cb", "Gb", "Db", "Ab", "Eb", "Bb", "F", "C", "G", "D", "A", "E", "B", "F#", "C#"];
+        const MINOR: [&str; 15] = ["Abm", "Ebm", "Bbm", "Fm", "Cm", "Gm", "Dm", "Am", "Em", "Bm", "F#m", "C#m", "G#m", "D#m", "A#m"];
+        let index = (self.fifths.clamp(-7, 7) + 7) as usize;
+        if self.minor { MINOR[index] } else { MAJOR[index] }
+    }
+
+    /// Alteration the key signature gives to a letter.
+    fn alteration(&self, letter: usize) -> i32 {
+        if self.fifths >= 0 {
+            if SHARP_ORDER[..self.fifths as usize].contains(&letter) { 1 } else { 0 }
+        } else if FLAT_ORDER[..(-self.fifths) as usize].contains(&letter) {
+            -1
+        } else {
+            0
+        }
+    }
+
+    /// Letter, alteration and octave of a key. Scale notes follow the key signature;
+    /// other black keys are sharps in sharp keys and flats in flat keys.
+    pub fn spell(&self, key: u8) -> (usize, i32, i32) {
+        let pc = key as i32 % 12;
+        let letter_alt = (0..7)
+            .map(|l| (l, self.alteration(l)))
+            .find(|(l, alt)| (NATURALS[*l] + alt).rem_euclid(12) == pc)
+            .or_else(|| NATURALS.iter().position(|n| *n == pc).map(|l| (l, 0)))
+            .unwrap_or_else(|| {
+                if self.fifths >= 0 {
+                    (NATURALS.iter().position(|n| *n == pc - 1).unwrap(), 1)
+                } else {
+                    (NATURALS.iter().position(|n| *n == pc + 1).unwrap(), -1)
+                }
+            });
+        let (letter, alt) = letter_alt;
+        (letter, alt, (key as i32 - NATURALS[letter] - alt).div_euclid(12) - 1)
+    }
+}
+
+/// Estimates the key with the Krumhansl-Schmuckler profiles. Drums are ignored.
+pub fn estimate_key(song: &Song) -> Key {
+    let mut histogram = [0.0; 12];
+    for instrument in song.tracks.iter().filter(|t| !t.kind().is_drum()) {
+        for note in instrument.track().iter() {
+            histogram[note.key.as_int() as usize % 12] += 1.0;
+        }
+    }
+    if histogram.iter().all(|h| *h == 0.0) {
+        return Key { fifths: 0, minor: false };
+    }
+
+    let correlation = |profile: &[f64; 12], tonic: usize| {
+        let mean_h = histogram.iter().sum::<f64>() / 12.0;
+        let mean_p = profile.iter().sum::<f64>() / 12.0;
+        let (mut num, mut den_h, mut den_p) = (0.0, 0.0, 0.0);
+        for pc in 0..12 {
+            let h = histogram[(pc + tonic) % 12] - mean_h;
+            let p = profile[pc] - mean_p;
+            num += h * p;
+            den_h += h * h;
+            den_p += p * p;
+        }
+        num / (den_h * den_p).sqrt()
+    };
+
+    let mut best = (f64::MIN, Key { fifths: 0, minor: false });
+    for tonic in 0..12 {
+        for minor in [false, true] {
+            let score = correlation(if minor { &MINOR_PROFILE } else { &MAJOR_PROFILE }, tonic);
+            let major_tonic = if minor { (tonic + 3) % 12 } else { tonic };
+            let mut fifths = (major_tonic as i32 * 7) % 12;
+            if fifths > 6 {
+                fifths -= 12;
+            }
+            if score > best.0 + 1e-9 {
+                best = (score, Key { fifths, minor });
+            }
+        }
+    }
+    best.1
+}
+
+/// Note length, meter and bar of the tune.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Meter {
+    /// Ticks in one unit note length.
+    pub unit: u32,
+    /// Denominator of the unit note length `L:1/n`.
+    pub length: u32,
+    pub numerator: u32,
+    pub denominator: u32,
+    /// Units in one beat.
+    pub beat: u32,
+    /// Units in one bar.
+    pub bar: u32,
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+/// Picks a unit that divides every note tick, and reads the most common gap between notes as a beat.
+pub fn estimate_meter(song: &Song, bar: Option<u32>) -> Meter {
+    let bar = bar.filter(|b| *b > 0);
+    let mut ticks: Vec<u32> = song.tracks.iter().flat_map(|t| t.track().iter().map(|n| n.start_timing)).collect();
+    ticks.sort();
+    ticks.dedup();
+    let unit = ticks.iter().chain(bar.iter()).fold(0, |g, t| gcd(g, *t)).max(1);
+
+    let mut gaps: BTreeMap<u32, usize> = BTreeMap::new();
+    for pair in ticks.windows(2) {
+        *gaps.entry((pair[1] - pair[0]) / unit).or_default() += 1;
+    }
+    let beat = gaps.iter().rev().max_by_key(|(_, count)| **count).map(|(gap, _)| *gap).unwrap_or(4);
+
+    let mut meter = if beat.is_power_of_two() {
+        Meter { unit, length: 4 * beat, numerator: 4, denominator: 4, beat, bar: 4 * beat }
+    } else if beat % 3 == 0 && (beat / 3).is_power_of_two() {
+        Meter { unit, length: 8 * beat / 3, numerator: 6, denominator: 8, beat, bar: 2 * beat }
+    } else {
+        Meter { unit, length: 16, numerator: 4, denominator: 4, beat: 4, bar: 16 }
+    };
+
+    if let Some(bar) = bar {
+        let (mut numerator, mut denominator) = (bar / unit, meter.length);
+        while numerator % 2 == 0 && denominator > 4 {
+            numerator /= 2;
+            denominator /= 2;
+        }
+        meter = Meter { numerator, denominator, bar: bar / unit, ..meter };
+    }
+    meter
+}
+
+/// Splits a length into note lengths that can be written without a tuplet.
+fn note_lengths(units: u32) -> Vec<u32> {
+    let mut lengths = Vec::new();
+    let mut rest = units;
+    while rest > 0 {
+        let power = 1 << (31 - rest.leading_zeros());
+        let length = if power >= 2 && power + power / 2 <= rest { power + power / 2 } else { power };
+        lengths.push(length);
+        rest -= length;
+    }
+    lengths
+}
+
+fn length_suffix(units: u32) -> String {
+    if units == 1 { String::new() } else { units.to_string() }
+}
+
+struct VoiceWriter<'a> {
+    key: Key,
+    meter: &'a Meter,
+    text: String,
+    position: u32,
+    bars: u32,
+    /// Accidentals written in the current bar, by letter and octave.
+    accidentals: HashMap<(usize, i32), i32>,
+}
+
+impl VoiceWriter<'_> {
+    fn note(&mut self, key: u8) -> String {
+        let (letter, alt, octave) = self.key.spell(key);
+        let current = self.accidentals.get(&(letter, octave)).copied().unwrap_or(self.key.alteration(letter));
+        let mut text = String::new();
+        if current != alt {
+            text.push_str(match alt {
+                1 => "^",
+                -1 => "_",
+                _ => "=",
+            });
+            self.accidentals.insert((letter, octave), alt);
+        }
+        if octave >= 5 {
+            text.push(LETTERS[letter].to_ascii_lowercase());
+            text.push_str(&"'".repeat((octave - 5) as usize));
+        } else {
+            text.push(LETTERS[letter]);
+            text.push_str(&",".repeat((4 - octave).max(0) as usize));
+        }
+        text
+    }
+
+    /// Writes a chord, or a rest if `keys` is empty, splitting it at bar lines.
+    fn event(&mut self, keys: &[u8], units: u32) {
+        let mut remaining = units;
+        while remaining > 0 {
+            let take = remaining.min(self.meter.bar - self.position % self.meter.bar);
+            let lengths = note_lengths(take);
+            for (i, length) in lengths.iter().enumerate() {
+                if self.position.is_multiple_of(self.meter.beat) && !self.position.is_multiple_of(self.meter.bar) {
+                    self.text.push(' ');
+                }
+                if keys.is_empty() {
+                    write!(self.text, "z{}", length_suffix(*length)).unwrap();
+                } else {
+                    let notes: Vec<String> = keys.iter().map(|k| self.note(*k)).collect();
+                    if notes.len() == 1 {
+                        self.text.push_str(&notes[0]);
+                    } else {
+                        write!(self.text, "[{}]", notes.concat()).unwrap();
+                    }
+                    self.text.push_str(&length_suffix(*length));
+                    if i + 1 < lengths.len() || take < remaining {
+                        self.text.push('-');
+                    }
+                }
+                self.position += length;
+            }
+            remaining -= take;
+            if self.position.is_multiple_of(self.meter.bar) {
+                self.bar_line();
+            }
+        }
+    }
+
+    fn bar_line(&mut self) {
+        self.bars += 1;
+        self.accidentals.clear();
+        self.text.push_str(if self.bars.is_multiple_of(4) { " |\n" } else { " | " });
+    }
+}
+
+/// Writes the song as an ABC tune with one voice per instrument track.
+///
+/// Notes last until the next note of the voice, at most one beat; the rest of the gap is a rest.
+pub fn song_to_abc(song: &Song, options: &AbcOptions) -> String {
+    let key = estimate_key(song);
+    let meter = estimate_meter(song, options.bar);
+    let end = song.length().div_ceil(meter.unit);
+    let quarter = meter.unit * meter.length / 4;
+
+    let mut abc = String::new();
+    writeln!(abc, "X:1").unwrap();
+    writeln!(abc, "T:{}", options.title).unwrap();
+    writeln!(abc, "M:{}/{}", meter.numerator, meter.denominator).unwrap();
+    writeln!(abc, "L:1/{}", meter.length).unwrap();
+    writeln!(abc, "Q:1/4={}", (60 * utils::TICKS_PER_SECOND + quarter / 2) / quarter.max(1)).unwrap();
+    writeln!(abc, "K:{}", key.name()).unwrap();
+
+    for (i, instrument) in song.tracks.iter().enumerate() {
+        let mut chords: BTreeMap<u32, Vec<u8>> = BTreeMap::new();
+        for note in instrument.track().iter() {
+            let keys = chords.entry(note.start_timing / meter.unit).or_default();
+            if !keys.contains(&note.key.as_int()) {
+                keys.push(note.key.as_int());
+            }
+        }
+        if chords.is_empty() {
+            continue;
+        }
+        let keys: Vec<u8> = chords.values().flatten().copied().collect();
+        let average = keys.iter().map(|k| *k as u32).sum::<u32>() / keys.len() as u32;
+        let clef = if average < 57 { " clef=bass" } else { "" };
+        writeln!(abc, "V:{} name=\"{}\"{}", i + 1, instrument.kind().name(), clef).unwrap();
+
+        let mut writer = VoiceWriter { key, meter: &meter, text: String::new(), position: 0, bars: 0, accidentals: HashMap::new() };
+        let starts: Vec<u32> = chords.keys().copied().collect();
+        writer.event(&[], starts[0]);
+        for (j, (start, keys)) in chords.iter_mut().enumerate() {
+            let next = starts.get(j + 1).copied().unwrap_or(end.max(start + 1));
+            let length = (next - start).min(meter.beat);
+            keys.sort();
+            writer.event(keys, length);
+            writer.event(&[], next - start - length);
+        }
+        let text = writer.text.trim_end().trim_end_matches('|').trim_end();
+        writeln!(abc, "{} |]", text).unwrap();
+    }
+    abc
+}
+
+/// Reads a `K:` field. Modes are supported; explicit accidentals after the key are ignored.
+pub fn parse_key(field: &str) -> Key {
+    let mut tokens = field.split_whitespace();
+    let Some(tonic) = tokens.next().filter(|t| t.starts_with(|c: char| ('A'..='G').contains(&c))) else {
+        return Key { fifths: 0, minor: false };
+    };
+    let mut chars = tonic.chars();
+    let first = chars.next();
+    let letter = LETTERS.iter().position(|l| Some(*l) == first).unwrap();
+    let rest = chars.as_str();
+    let (accidental, rest) = match rest.chars().next() {
+        Some('#') => (1, &rest[1..]),
+        Some('b') => (-1, &rest[1..]),
+        _ => (0, rest),
+    };
+    let mode = if rest.is_empty() {
+        tokens.next().filter(|t| t.chars().all(|c| c.is_ascii_alphabetic())).unwrap_or("")
+    } else {
+        rest
+    };
+    let mode = mode.to_ascii_lowercase();
+    let shift = match mode.get(..3).unwrap_or(&mode) {
+        "m" | "min" | "aeo" => -3,
+        "mix" => -1,
+        "dor" => -2,
+        "phr" => -4,
+        "loc" => -5,
+        "lyd" => 1,
+        _ => 0,
+    };
+    const TONIC_FIFTHS: [i32; 7] = [0, 2, 4, -1, 1, 3, 5];
+    Key { fifths: (TONIC_FIFTHS[letter] + 7 * accidental + shift).clamp(-7, 7), minor: shift == -3 }
+}
+
+/// Parses a fraction like `3/8`, or a whole number.
+fn parse_fraction(text: &str) -> Option<f64> {
+    match text.trim().split_once('/') {
+        Some((num, den)) => Some(num.trim().parse::<f64>().ok()? / den.trim().parse::<f64>().ok()?),
+        None => text.trim().parse().ok(),
+    }
+}
+
+/// Reads an `M:` field as the bar length in whole notes.
+fn parse_meter(field: &str) -> Option<f64> {
+    match field.trim() {
+        "C" => Some(1.0),
+        "C|" => Some(1.0),
+        "none" | "" => None,
+        meter => parse_fraction(meter),
+    }
+}
+
+/// Reads a `Q:` field as quarter notes per minute. `unit` is the unit note length in whole notes,
+/// used by the old `Q:120` form.
+fn parse_tempo(field: &str, unit: f64) -> Option<f64> {
+    let field = field.split('"').step_by(2).collect::<String>();
+    match field.split_once('=') {
+        Some((beats, bpm)) => {
+            let beat: f64 = beats.split_whitespace().filter_map(parse_fraction).sum();
+            let beat = if beat > 0.0 { beat } else { 0.25 };
+            Some(bpm.trim().parse::<f64>().ok()? * beat * 4.0)
+        }
+        None => Some(field.trim().parse::<f64>().ok()? * unit * 4.0),
+    }
+}
+
+/// Reading state of one voice.
+struct Voice {
+    id: String,
+    key: Key,
+    /// Unit note length in whole notes.
+    unit: f64,
+    /// Bar length in whole notes.
+    meter: f64,
+    measures: Vec<Measure>,
+    current: Measure,
+    position: f64,
+    /// Accidentals set in the current bar, by letter and octave.
+    accidentals: HashMap<(usize, i32), i32>,
+    /// Keys tied into the next note.
+    tied: Vec<u8>,
+    /// Start and length of the last note or rest, for broken rhythms.
+    last: Option<(f64, f64)>,
+    /// Length factor of the next note, set by a broken rhythm.
+    broken: f64,
+    /// Length factor and remaining notes of a tuplet.
+    tuplet: Option<(f64, u32)>,
+    ending: Vec<u32>,
+}
+
+impl Voice {
+    fn new(id: &str, key: Key, unit: f64, meter: f64) -> Self {
+        Self {
+            id: id.to_string(),
+            key,
+            unit,
+            meter,
+            measures: Vec::new(),
+            current: Measure::default(),
+            position: 0.0,
+            accidentals: HashMap::new(),
+            tied: Vec::new(),
+            last: None,
+            broken: 1.0,
+            tuplet: None,
+            ending: Vec::new(),
+        }
+    }
+
+    /// Applies broken rhythm and tuplet factors, and moves past an item of `units` unit lengths.
+    /// Returns the start of the item.
+    fn advance(&mut self, units: f64) -> f64 {
+        let mut length = units * self.unit * 4.0 * self.broken;
+        self.broken = 1.0;
+        if let Some((factor, remaining)) = self.tuplet {
+            length *= factor;
+            self.tuplet = if remaining > 1 { Some((factor, remaining - 1)) } else { None };
+        }
+        let start = self.position;
+        self.position += length;
+        self.current.length = self.current.length.max(self.position);
+        self.last = Some((start, length));
+        start
+    }
+
+    fn play(&mut self, start: f64, keys: &[u8]) {
+        for key in keys {
+            if !self.tied.contains(key) {
+                self.current.notes.push((start, *key, self.id.clone()));
+            }
+        }
+        self.tied.clear();
+    }
+
+    fn broken_rhythm(&mut self, symbol: char, count: i32) {
+        let short = 0.5f64.powi(count);
+        let (this, next) = if symbol == '>' { (2.0 - short, short) } else { (short, 2.0 - short) };
+        if let Some((start, length)) = self.last {
+            self.position = start + length * this;
+            self.current.length = self.current.length.max(self.position);
+        }
+        self.broken = next;
+    }
+
+    /// Ends the current bar. `token` is the bar line, like `|`, `:|`, `|:` or `||`.
+    fn bar(&mut self, token: &str, ending: Vec<u32>) {
+        let backward = token.starts_with(':');
+        let forward = token.ends_with(':');
+        let mut measure = std::mem::take(&mut self.current);
+        if backward {
+            measure.barlines.backward = Some(2);
+        }
+        if measure.notes.is_empty() && measure.length == 0.0 && measure.tempos.is_empty() {
+            if backward && let Some(previous) = self.measures.last_mut() {
+                previous.barlines.backward = Some(2);
+            }
+            self.current.barlines.forward = measure.barlines.forward;
+        } else {
+            self.measures.push(measure);
+        }
+
+        if backward || forward || token.contains("||") || token.contains(']') || token.contains("[|") {
+            self.ending.clear();
+        }
+        if !ending.is_empty() {
+            self.ending = ending;
+        }
+        self.current.barlines.forward |= forward;
+        self.current.barlines.ending = self.ending.clone();
+        self.position = 0.0;
+        self.last = None;
+        self.accidentals.clear();
+    }
+
+    /// Parses a note at `chars[*i]`, returning its key and length in unit lengths.
+    fn note(&mut self, chars: &[char], i: &mut usize) -> Option<(u8, f64)> {
+        let mut explicit = None;
+        while let Some(c) = chars.get(*i).filter(|c| matches!(c, '^' | '_' | '=')) {
+            explicit = Some(explicit.unwrap_or(0) + match c {
+                '^' => 1,
+                '_' => -1,
+                _ => 0,
+            });
+            *i += 1;
+        }
+        let c = *chars.get(*i)?;
+        let letter = LETTERS.iter().position(|l| *l == c.to_ascii_uppercase())?;
+        *i += 1;
+        let mut octave = if c.is_ascii_lowercase() { 5 } else { 4 };
+        while let Some(mark) = chars.get(*i).filter(|c| matches!(c, '\'' | ',')) {
+            octave += if *mark == '\'' { 1 } else { -1 };
+            *i += 1;
+        }
+        let alt = match explicit {
+            Some(alt) => {
+                self.accidentals.insert((letter, octave), alt);
+                alt
+            }
+            None => self.accidentals.get(&(letter, octave)).copied().unwrap_or(self.key.alteration(letter)),
+        };
+        let key = ((octave + 1) * 12 + NATURALS[letter] + alt).clamp(0, 127) as u8;
+        Some((key, read_length(chars, i)))
+    }
+
+    fn finish(mut self) -> Vec<Measure> {
+        if !self.current.notes.is_empty() || self.current.length > 0.0 {
+            self.measures.push(self.current);
+        }
+        self.measures
+    }
+}
+
+fn read_number(chars: &[char], i: &mut usize) -> Option<u32> {
+    let start = *i;
+    while chars.get(*i).is_some_and(|c| c.is_ascii_digit()) {
+        *i += 1;
+    }
+    chars[start..*i].iter().collect::<String>().parse().ok()
+}
+
+/// Reads a length multiplier like `3`, `/`, `//`, `/4` or `3/2`.
+fn read_length(chars: &[char], i: &mut usize) -> f64 {
+    let numerator = read_number(chars, i).unwrap_or(1) as f64;
+    let mut denominator = 1.0;
+    while chars.get(*i) == Some(&'/') {
+        *i += 1;
+        denominator *= read_number(chars, i).unwrap_or(2) as f64;
+    }
+    numerator / denominator
+}
+
+/// Reads volta numbers like `1`, `1,2` or `1-3`.
+fn read_ending(chars: &[char], i: &mut usize) -> Vec<u32> {
+    let mut numbers = Vec::new();
+    while let Some(first) = read_number(chars, i) {
+        let mut last = first;
+        if chars.get(*i) == Some(&'-') {
+            *i += 1;
+            last = read_number(chars, i).unwrap_or(first);
+        }
+        numbers.extend(first..=last);
+        if chars.get(*i) != Some(&',') {
+            break;
+        }
+        *i += 1;
+    }
+    numbers
+}
+
+fn skip_to(chars: &[char], i: &mut usize, end: char) {
+    while *i < chars.len() && chars[*i] != end {
+        *i += 1;
+    }
+    *i += 1;
+}
+
+/// Global header values, used by voices created later.
+struct Header {
+    key: Key,
+    unit: Option<f64>,
+    meter: f64,
+    tempo: Option<f64>,
+}
+
+impl Header {
+    fn unit(&self) -> f64 {
+        self.unit.unwrap_or(if self.meter < 0.75 { 1.0 / 16.0 } else { 1.0 / 8.0 })
+    }
+}
+
+fn voice_index(voices: &mut Vec<Voice>, id: &str, header: &Header) -> usize {
+    match voices.iter().position(|v| v.id == id) {
+        Some(index) => index,
+        None => {
+            voices.push(Voice::new(id, header.key, header.unit(), header.meter));
+            voices.len() - 1
+        }
+    }
+}
+
+/// Applies a header field, either for the whole tune or the current voice.
+fn apply_field(name: char, value: &str, header: &mut Header, voices: &mut Vec<Voice>, current: &mut usize, in_body: bool) {
+    match name {
+        'V' => {
+            let id = value.split_whitespace().next().unwrap_or("1");
+            *current = voice_index(voices, id, header);
+        }
+        'K' if in_body => voices[*current].key = parse_key(value),
+        'K' => header.key = parse_key(value),
+        'L' => {
+            if let Some(unit) = parse_fraction(value) {
+                if in_body {
+                    voices[*current].unit = unit;
+                } else {
+                    header.unit = Some(unit);
+                }
+            }
+        }
+        'M' => {
+            let meter = parse_meter(value).unwrap_or(1.0);
+            if in_body {
+                voices[*current].meter = meter;
+            } else {
+                header.meter = meter;
+            }
+        }
+        'Q' => {
+            let unit = if in_body { voices[*current].unit } else { header.unit() };
+            if let Some(tempo) = parse_tempo(value, unit) {
+                if in_body {
+                    let voice = &mut voices[*current];
+                    voice.current.tempos.push((voice.position, tempo));
+                } else {
+                    header.tempo = Some(tempo);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Reads the music of one line into the current voice.
+fn parse_music(line: &str, header: &mut Header, voices: &mut Vec<Voice>, current: &mut usize) {
+    let chars: Vec<char> = line.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '|' | ':' => {
+                let start = i;
+                while i < chars.len() && matches!(chars[i], '|' | ':' | ']') {
+                    i += 1;
+                }
+                let token: String = chars[start..i].iter().collect();
+                let ending = read_ending(&chars, &mut i);
+                voices[*current].bar(&token, ending);
+            }
+            '[' if chars.get(i + 1) == Some(&'|') => {
+                i += 2;
+                voices[*current].bar("[|", Vec::new());
+            }
+            '[' if chars.get(i + 1).is_some_and(|c| c.is_ascii_digit()) => {
+                i += 1;
+                let ending = read_ending(&chars, &mut i);
+                let voice = &mut voices[*current];
+                voice.ending = ending.clone();
+                voice.current.barlines.ending = ending;
+            }
+            '[' if chars.get(i + 2) == Some(&':') && chars.get(i + 1).is_some_and(|c| c.is_ascii_alphabetic()) => {
+                let end = chars[i..].iter().position(|c| *c == ']').map(|p| i + p).unwrap_or(chars.len());
+                let value: String = chars[i + 3..end].iter().collect();
+                apply_field(chars[i + 1], &value, header, voices, current, true);
+                i = end + 1;
+            }
+            '[' => {
+                i += 1;
+                let voice = &mut voices[*current];
+                let mut keys = Vec::new();
+                let mut first_length = None;
+                while i < chars.len() && chars[i] != ']' {
+                    match voice.note(&chars, &mut i) {
+                        Some((key, length)) => {
+                            keys.push(key);
+                            first_length.get_or_insert(length);
+                        }
+                        None => i += 1,
+                    }
+                }
+                i += 1;
+                let length = first_length.unwrap_or(1.0) * read_length(&chars, &mut i);
+                let start = voice.advance(length);
+                voice.play(start, &keys);
+                if chars.get(i) == Some(&'-') {
+                    i += 1;
+                    voice.tied = keys;
+                }
+            }
+            '^' | '_' | '=' | 'A'..='G' | 'a'..='g' => {
+                let voice = &mut voices[*current];
+                match voice.note(&chars, &mut i) {
+                    Some((key, length)) => {
+                        let start = voice.advance(length);
+                        voice.play(start, &[key]);
+                        if chars.get(i) == Some(&'-') {
+                            i += 1;
+                            voice.tied = vec![key];
+                        }
+                    }
+                    None => i += 1,
+                }
+            }
+            'z' | 'x' => {
+                i += 1;
+                let length = read_length(&chars, &mut i);
+                let voice = &mut voices[*current];
+                voice.advance(length);
+                voice.tied.clear();
+            }
+            'Z' | 'X' => {
+                i += 1;
+                let bars = read_number(&chars, &mut i).unwrap_or(1) as f64;
+                let voice = &mut voices[*current];
+                let units = bars * voice.meter / voice.unit;
+                voice.advance(units);
+            }
+            '>' | '<' => {
+                let mut count = 0;
+                while chars.get(i) == Some(&c) {
+                    count += 1;
+                    i += 1;
+                }
+                voices[*current].broken_rhythm(c, count);
+            }
+            '(' if chars.get(i + 1).is_some_and(|c| c.is_ascii_digit()) => {
+                i += 1;
+                let p = read_number(&chars, &mut i).unwrap_or(3);
+                let mut q = match p {
+                    3 | 6 => 2,
+                    2 | 4 | 8 => 3,
+                    _ => 2,
+                };
+                let mut r = p;
+                if chars.get(i) == Some(&':') {
+                    i += 1;
+                    q = read_number(&chars, &mut i).unwrap_or(q);
+                    if chars.get(i) == Some(&':') {
+                        i += 1;
+                        r = read_number(&chars, &mut i).unwrap_or(r);
+                    }
+                }
+                voices[*current].tuplet = Some((q as f64 / p as f64, r));
+            }
+            '"' | '!' | '+' => {
+                i += 1;
+                skip_to(&chars, &mut i, c);
+            }
+            '{' => skip_to(&chars, &mut i, '}'),
+            _ => i += 1,
+        }
+    }
+}
+
+/// Reads the first tune of an ABC file into one track per voice, in the order the voices appear.
+///
+/// Key signatures, accidentals within bars, ties, chords, broken rhythms, tuplets, repeats
+/// and first and second endings are supported. Grace notes, decorations and lyrics are skipped.
+pub fn parse_abc(text: &str) -> Vec<(String, Track)> {
+    let mut header = Header { key: Key { fifths: 0, minor: false }, unit: None, meter: 1.0, tempo: None };
+    let mut voices: Vec<Voice> = Vec::new();
+    let mut current = 0;
+    let mut in_body = false;
+    let mut started = false;
+
+    for line in text.lines() {
+        let line = line.split('%').next().unwrap_or("").trim_end_matches('\\');
+        let mut chars = line.chars();
+        let field = match (chars.next(), chars.next()) {
+            (Some(name), Some(':')) if name.is_ascii_alphabetic() => Some((name, chars.as_str().trim())),
+            _ => None,
+        };
+        match field {
+            Some(('X', _)) if started => break,
+            Some(('X', _)) => started = true,
+            Some((name, value)) => {
+                if name == 'V' || in_body {
+                    if voices.is_empty() && name != 'V' {
+                        voices.push(Voice::new("1", header.key, header.unit(), header.meter));
+                    }
+                    apply_field(name, value, &mut header, &mut voices, &mut current, in_body || name == 'V');
+                } else {
+                    apply_field(name, value, &mut header, &mut voices, &mut current, false);
+                }
+                if name == 'K' && !in_body {
+                    in_body = true;
+                    for voice in &mut voices {
+                        voice.key = header.key;
+                        voice.unit = header.unit();
+                        voice.meter = header.meter;
+                    }
+                    if voices.is_empty() {
+                        voices.push(Voice::new("1", header.key, header.unit(), header.meter));
+                    }
+                    current = 0;
+                }
+            }
+            None if in_body => parse_music(line, &mut header, &mut voices, &mut current),
+            None => {}
+        }
+    }
+
+    let mut tempos = Vec::new();
+    let mut parsed = Vec::new();
+    for voice in voices {
+        let id = voice.id.clone();
+        let measures = voice.finish();
+        let barlines: Vec<Barlines> = measures.iter().map(|m| m.barlines.clone()).collect();
+        let mut notes = Vec::new();
+        let mut position = 0.0;
+        for i in musicxml::play_order(&barlines) {
+            let measure = &measures[i];
+            notes.extend(measure.notes.iter().map(|(at, key, _)| (position + at, *key)));
+            tempos.extend(measure.tempos.iter().map(|(at, tempo)| (position + at, *tempo)));
+            position += measure.length;
+        }
+        parsed.push((id, notes));
+    }
+
+    let tempo_map = TempoMap::new(header.tempo.unwrap_or(DEFAULT_TEMPO), tempos);
+    parsed.into_iter()
+        .map(|(id, notes)| {
+            let mut track = Track::new();
+            for (at, key) in notes {
+                track.push(Note::new(u7::from(key), tempo_map.ticks(at)));
+            }
+            track.sort_by_key(|n| n.start_timing);
+            (id, track)
+        })
+        .collect()
+}
+
+/// Reads an ABC file like `mid_to_track`. `tune.abc#2` takes only voice `2`, by id or number;
+/// without it every voice is merged.
+pub fn abc_to_track(path: &str) -> Result<Track, Box<dyn std::error::Error>> {
+    let (file, selector) = path.split_once('#').unwrap_or((path, ""));
+    let voices = parse_abc(&std::fs::read_to_string(file)?);
+    if selector.is_empty() {
+        let mut track = Track::new();
+        for (_, voice) in voices {
+            track.merge(voice);
+        }
+        return Ok(track);
+    }
+    let found = voices.iter().enumerate()
+        .find(|(i, (id, _))| id == selector || (i + 1).to_string() == selector)
+        .map(|(_, (_, track))| track.clone());
+    match found {
+        Some(track) => Ok(track),
+        None => {
+            let ids: Vec<&str> = voices.iter().map(|(id, _)| id.as_str()).collect();
+            Err(AbcError::UnknownVoice(selector.to_string(), ids.join(", ")).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys_and_ticks(track: &Track) -> Vec<(u8, u32)> {
+        track.iter().map(|n| (n.key.as_int(), n.start_timing)).collect()
+    }
+
+    #[test]
+    fn test_parse_key() {
+        assert_eq!(parse_key("G"), Key { fifths: 1, minor: false });
+        assert_eq!(parse_key("Bbm"), Key { fifths: -5, minor: true });
+        assert_eq!(parse_key("D mix"), Key { fifths: 1, minor: false });
+        assert_eq!(parse_key("Edor clef=bass"), Key { fifths: 2, minor: false });
+        assert_eq!(parse_key("none"), Key { fifths: 0, minor: false });
+        assert_eq!(parse_key("C#").name(), "C#");
+        assert_eq!(parse_key("Gb").name(), "Gb");
+        assert_eq!(parse_key("A#m").name(), "A#m");
+        assert_eq!(parse_key("Abm").name(), "Abm");
+    }
+
+    #[test]
+    fn test_parse_abc() {
+        let tune = "X:1\nT:test\nM:4/4\nL:1/8\nQ:1/4=150\nK:G\nF2 ^c=c c | f>g (3abc [CE]2- [CE]c | z4 F4-|F2 |]\n";
+        let voices = parse_abc(tune);
+        assert_eq!(voices.len(), 1);
+        assert_eq!(keys_and_ticks(&voices[0].1), vec![
+            (66, 0), (73, 8), (72, 12), (72, 16),
+            (78, 20), (79, 26), (81, 28), (83, 31), (72, 33), (60, 36), (64, 36), (72, 48),
+            (66, 68),
+        ]);
+    }
+
+    #[test]
+    fn test_parse_abc_repeats_and_voices() {
+        let tune = "X:1\nL:1/4\nQ:1/4=150\nK:C\nV:1\n|: C D |1 E :|2 F |]\nV:2\nC,4 |\n";
+        let voices = parse_abc(tune);
+        let ids: Vec<&str> = voices.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(ids, vec!["1", "2"]);
+        assert_eq!(keys_and_ticks(&voices[0].1), vec![(60, 0), (62, 8), (64, 16), (60, 24), (62, 32), (65, 40)]);
+        assert_eq!(keys_and_ticks(&voices[1].1), vec![(48, 0)]);
+    }
+
+    #[test]
+    fn test_estimate_key() {
+        assert_eq!(estimate_key(&Song::from_text("G2K2N2G2K2N").unwrap()).name(), "C");
+        assert_eq!(estimate_key(&Song::from_text("N2R2I2N2N2R2I2M2N").unwrap()).name(), "G");
+    }
+
+    #[test]
+    fn test_spell() {
+        let g = Key { fifths: 1, minor: false };
+        assert_eq!(g.spell(66), (3, 1, 4));
+        assert_eq!(g.spell(65), (3, 0, 4));
+        assert_eq!(Key { fifths: -2, minor: false }.spell(70), (6, -1, 4));
+        assert_eq!(Key { fifths: 0, minor: false }.spell(61), (0, 1, 4));
+    }
+
+    #[test]
+    fn test_note_lengths() {
+        assert_eq!(note_lengths(5), vec![4, 1]);
+        assert_eq!(note_lengths(6), vec![6]);
+        assert_eq!(note_lengths(7), vec![6, 1]);
+    }
+
+    #[test]
+    fn test_song_to_abc() {
+        let song = Song::from_text("G2I2K2L2G2I2K2LG").unwrap();
+        let abc = song_to_abc(&song, &AbcOptions { title: "test".to_string(), bar: None });
+        assert_eq!(abc, "X:1\nT:test\nM:4/4\nL:1/4\nQ:1/4=300\nK:C\nV:1 name=\"pling\"\nC D E F | C D E [CF] |]\n");
+    }
+
+    #[test]
+    fn test_accidentals_and_ties() {
+        let song = Song::from_text("GKN4H2G4H").unwrap();
+        let abc = song_to_abc(&song, &AbcOptions { title: String::new(), bar: Some(16) });
+        assert!(abc.contains("M:2/4\nL:1/8\n"));
+        assert!(abc.contains("K:C\n"));
+        assert!(abc.contains("[CEG]2 ^C=C- | C^C |]"), "{}", abc);
+    }
+}
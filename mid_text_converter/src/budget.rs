@@ -0,0 +1,275 @@
+use crate::instruments::{InstrumentKind, Instruments, Track};
+use crate::note::Note;
+use crate::song::Song;
+use std::collections::HashSet;
+use std::fmt;
+use thiserror::Error;
+
+/// Gap in ticks to the next note at or below which a note counts as an ornament.
+const ORNAMENT_TICKS: u32 = 1;
+
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum BudgetError {
+    #[error("Cannot fit into {max_len} characters, still {len} after removing all optional notes")]
+    CannotFit { len: usize, max_len: usize },
+}
+
+/// Why a note was removed, from least to most important.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Reduction {
+    /// Same instrument, key and tick as another note.
+    Duplicate,
+    /// Neither the lowest nor the highest note of a chord.
+    InnerVoice,
+    /// A grace note: alone on its tick, followed by the next note of the same instrument within
+    /// `ORNAMENT_TICKS`, and shorter than that note.
+    Ornament,
+    /// A drum hit on a tick where a more important drum already plays.
+    DoubledDrum,
+}
+
+impl fmt::Display for Reduction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Reduction::Duplicate => "duplicate",
+            Reduction::InnerVoice => "inner chord voice",
+            Reduction::Ornament => "ornament",
+            Reduction::DoubledDrum => "doubled drum",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemovedNote {
+    pub kind: InstrumentKind,
+    pub tick: u32,
+    pub key: u8,
+    pub reason: Reduction,
+}
+
+/// A song reduced to fit a length, with the notes that were dropped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Budgeted {
+    pub song: Song,
+    pub text: String,
+    pub removed: Vec<RemovedNote>,
+}
+
+impl Budgeted {
+    /// Number of removed notes per reason, in removal order.
+    pub fn summary(&self) -> Vec<(Reduction, usize)> {
+        let mut summary: Vec<(Reduction, usize)> = Vec::new();
+        for note in &self.removed {
+            match summary.iter_mut().find(|(r, _)| *r == note.reason) {
+                Some((_, count)) => *count += 1,
+                None => summary.push((note.reason, 1)),
+            }
+        }
+        summary
+    }
+}
+
+fn drum_rank(kind: InstrumentKind) -> Option<u8> {
+    match kind {
+        InstrumentKind::BassDrum => Some(0),
+        InstrumentKind::Snare => Some(1),
+        InstrumentKind::Hat => Some(2),
+        _ => None,
+    }
+}
+
+/// Notes of the song grouped by instrument, sorted by tick then key.
+fn group_by_kind(song: &Song) -> Vec<(InstrumentKind, Vec<Note>)> {
+    let mut groups: Vec<(InstrumentKind, Vec<Note>)> = Vec::new();
+    for track in &song.tracks {
+        let notes = track.track().iter().cloned();
+        match groups.iter_mut().find(|(k, _)| *k == track.kind()) {
+            Some((_, group)) => group.extend(notes),
+            None => groups.push((track.kind(), notes.collect())),
+        }
+    }
+    for (_, notes) in &mut groups {
+        notes.sort_by_key(|n| (n.start_timing, n.key));
+    }
+    groups
+}
+
+/// Classifies every optional note as `(group, index, reason)`, ordered by the reason.
+fn classify(groups: &[(InstrumentKind, Vec<Note>)]) -> Vec<(usize, usize, Reduction)> {
+    let mut candidates = Vec::new();
+    let mut taken = HashSet::new();
+    let mut push = |candidates: &mut Vec<_>, g: usize, i: usize, reason: Reduction| {
+        if taken.insert((g, i)) {
+            candidates.push((g, i, reason));
+        }
+    };
+
+    for (g, (_, notes)) in groups.iter().enumerate() {
+        for i in 1..notes.len() {
+            if notes[i] == notes[i - 1] {
+                push(&mut candidates, g, i, Reduction::Duplicate);
+            }
+        }
+    }
+
+    for (g, (kind, notes)) in groups.iter().enumerate() {
+        if kind.is_drum() {
+            continue;
+        }
+        let mut first = 0;
+        while first < notes.len() {
+            let tick = notes[first].start_timing;
+            let last = first + notes[first..].iter().take_while(|n| n.start_timing == tick).count();
+            for i in first + 1..last.saturating_sub(1) {
+                push(&mut candidates, g, i, Reduction::InnerVoice);
+            }
+            first = last;
+        }
+    }
+
+    for (g, (_, notes)) in groups.iter().enumerate() {
+        let mut ticks: Vec<u32> = notes.iter().map(|n| n.start_timing).collect();
+        ticks.dedup();
+        // the last tick rings on, so nothing before it is longer
+        let length = |t: usize| ticks.get(t + 1).map_or(u32::MAX, |next| next - ticks[t]);
+        for (t, &tick) in ticks.iter().enumerate() {
+            let mut on_tick = notes.iter().enumerate().filter(|(_, n)| n.start_timing == tick);
+            let (Some((i, _)), None) = (on_tick.next(), on_tick.next()) else {
+                continue;
+            };
+            if length(t) <= ORNAMENT_TICKS && length(t) < length(t + 1) {
+                push(&mut candidates, g, i, Reduction::Ornament);
+            }
+        }
+    }
+
+    let mut drums: Vec<(u32, u8, usize, usize)> = groups.iter().enumerate()
+        .filter_map(|(g, (kind, notes))| drum_rank(*kind).map(|rank| (g, rank, notes)))
+        .flat_map(|(g, rank, notes)| notes.iter().enumerate().map(move |(i, n)| (n.start_timing, rank, g, i)))
+        .collect();
+    drums.sort();
+    for w in 1..drums.len() {
+        if drums[w].0 == drums[w - 1].0 {
+            push(&mut candidates, drums[w].2, drums[w].3, Reduction::DoubledDrum);
+        }
+    }
+
+    candidates.sort_by_key(|&(_, _, reason)| reason);
+    candidates
+}
+
+fn build_song(groups: &[(InstrumentKind, Vec<Note>)], removed: &HashSet<(usize, usize)>, end: u32) -> Song {
+    let mut song = Song::new();
+    for (g, (kind, notes)) in groups.iter().enumerate() {
+        let track = Track(notes.iter().enumerate()
+            .filter(|(i, _)| !removed.contains(&(g, *i)))
+            .map(|(_, n)| n.clone())
+            .collect());
+        song.add_track(Instruments::new(*kind, track));
+    }
+    song.end = end;
+    song
+}
+
+/// Removes the least important notes until `song.to_text` is at most `max_len` characters long.
+///
+/// Notes are dropped in the order of `Reduction`, as few as needed. Each reason is tried as a
+/// whole first, and only the reason that makes the song fit is searched for the fewest notes.
+/// If the song still does not fit after every optional note is gone, `BudgetError::CannotFit`
+/// is returned.
+pub fn fit_to_budget(song: &Song, max_len: usize, relative_move: bool) -> Result<Budgeted, Box<dyn std::error::Error>> {
+    let groups = group_by_kind(song);
+    let candidates = classify(&groups);
+    let text_without = |count: usize| {
+        let removed = candidates[..count].iter().map(|&(g, i, _)| (g, i)).collect();
+        build_song(&groups, &removed, song.end).to_text(relative_move)
+    };
+
+    let mut text = song.to_text(relative_move)?;
+    let mut count = 0;
+    while text.len() > max_len && count < candidates.len() {
+        let reason = candidates[count].2;
+        let end = count + candidates[count..].iter().take_while(|c| c.2 == reason).count();
+        let all = text_without(end)?;
+        if all.len() > max_len {
+            (text, count) = (all, end);
+            continue;
+        }
+        let (mut low, mut high) = (count + 1, end);
+        text = all;
+        while low < high {
+            let middle = (low + high) / 2;
+            let shorter = text_without(middle)?;
+            if shorter.len() <= max_len {
+                (text, high) = (shorter, middle);
+            } else {
+                low = middle + 1;
+            }
+        }
+        count = high;
+    }
+
+    if text.len() > max_len {
+        return Err(Box::new(BudgetError::CannotFit { len: text.len(), max_len }));
+    }
+
+    let removed: HashSet<(usize, usize)> = candidates[..count].iter().map(|&(g, i, _)| (g, i)).collect();
+    let removed_notes = candidates[..count].iter()
+        .map(|&(g, i, reason)| {
+            let (kind, notes) = &groups[g];
+            RemovedNote { kind: *kind, tick: notes[i].start_timing, key: notes[i].key.as_int(), reason }
+        })
+        .collect();
+    Ok(Budgeted { song: build_song(&groups, &removed, song.end), text, removed: removed_notes })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fits_without_removal() {
+        let song = Song::from_text("GKN2I").unwrap();
+        let result = fit_to_budget(&song, 10, false).unwrap();
+        assert_eq!(result.text, "GKN2I");
+        assert!(result.removed.is_empty());
+    }
+
+    #[test]
+    fn test_removes_inner_voice_first() {
+        let song = Song::from_text("GKN.I2!G=G").unwrap();
+        let result = fit_to_budget(&song, 9, false).unwrap();
+        assert_eq!(result.text, "GN.I2!G=G");
+        assert_eq!(result.removed, vec![RemovedNote { kind: InstrumentKind::Pling, tick: 0, key: 64, reason: Reduction::InnerVoice }]);
+
+        // the lowest and highest notes of the chord are kept
+        let result = fit_to_budget(&song, 7, false).unwrap();
+        assert_eq!(result.text, "GN.I2=G");
+        assert_eq!(result.summary(), vec![
+            (Reduction::InnerVoice, 1),
+            (Reduction::DoubledDrum, 1),
+        ]);
+        assert!(fit_to_budget(&song, 6, false).is_err());
+    }
+
+    #[test]
+    fn test_removes_grace_notes() {
+        // G leads into I, which is held longer, and I is as long as K
+        let song = Song::from_text("G.I2K2M").unwrap();
+        let result = fit_to_budget(&song, 6, false).unwrap();
+        assert_eq!(result.text, ".I2K2M");
+        assert_eq!(result.removed, vec![RemovedNote { kind: InstrumentKind::Pling, tick: 0, key: 60, reason: Reduction::Ornament }]);
+
+        // G is as long as I, which leads into K
+        let result = fit_to_budget(&Song::from_text("G.I.K2M").unwrap(), 6, false).unwrap();
+        assert_eq!(result.text, "G1K2M");
+        assert_eq!(result.removed[0].key, 62);
+    }
+
+    #[test]
+    fn test_cannot_fit() {
+        let song = Song::from_text("G2I2K").unwrap();
+        assert!(fit_to_budget(&song, 3, false).is_err());
+    }
+}
@@ -0,0 +1,128 @@
+use crate::utils;
+use thiserror::Error;
+
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum ChunkError {
+    #[error("Notes on tick {tick} need {len} characters, more than the limit of {max_len}")]
+    TickTooLong { tick: u32, len: usize, max_len: usize },
+}
+
+/// One piece of a song that fits into a single message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    /// Tick of the whole song at which this chunk starts.
+    pub start: u32,
+    pub text: String,
+}
+
+/// Length of the text for `groups` played from `start`, followed by silence until `until`.
+fn chunk_len(groups: &[(u32, String)], start: u32, until: u32) -> usize {
+    let mut len = 0;
+    let mut prev = start;
+    for (tick, chars) in groups {
+        len += utils::tick_to_string(tick - prev).len() + chars.len();
+        prev = *tick;
+    }
+    len + utils::tick_to_string(until - prev).len()
+}
+
+fn chunk_text(groups: &[(u32, String)], start: u32, until: u32) -> String {
+    let mut text = String::new();
+    let mut prev = start;
+    for (tick, chars) in groups {
+        text.push_str(&utils::tick_to_string(tick - prev));
+        text.push_str(chars);
+        prev = *tick;
+    }
+    text.push_str(&utils::tick_to_string(until - prev));
+    text
+}
+
+/// Splits a string into chunks of at most `max_len` characters.
+///
+/// Chunks are only cut between ticks and each one ends with the silence until the next,
+/// so playing them back to back keeps the original timing. Among the cuts that still fill
+/// a chunk to at least three quarters, bar lines (every `bar` ticks) are preferred, then longer rests.
+pub fn chunk_string(text: &str, max_len: usize, bar: Option<u32>) -> Result<Vec<Chunk>, ChunkError> {
+    let (groups, end) = utils::split_ticks(text);
+    let mut chunks = Vec::new();
+    let mut first = 0;
+    let mut start = 0;
+
+    while first < groups.len() {
+        let until = |last: usize| groups.get(last).map(|g| g.0).unwrap_or(end);
+
+        let mut last = first;
+        while last < groups.len() && chunk_len(&groups[first..=last], start, until(last + 1)) <= max_len {
+            last += 1;
+        }
+        if last == first {
+            let (tick, _) = groups[first];
+            return Err(ChunkError::TickTooLong {
+                tick,
+                len: chunk_len(&groups[first..=first], start, until(first + 1)),
+                max_len,
+            });
+        }
+
+        if last < groups.len() {
+            let longest = chunk_len(&groups[first..last], start, until(last));
+            last = (first + 1..=last)
+                .filter(|&l| chunk_len(&groups[first..l], start, until(l)) * 4 >= longest * 3)
+                .max_by_key(|&l| {
+                    let tick = groups[l].0;
+                    let on_bar = bar.is_some_and(|b| b > 0 && tick % b == 0);
+                    (on_bar, tick - groups[l - 1].0, l)
+                })
+                .unwrap_or(last);
+        }
+
+        chunks.push(Chunk { start, text: chunk_text(&groups[first..last], start, until(last)) });
+        start = until(last);
+        first = last;
+    }
+
+    Ok(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_fits() {
+        let chunks = chunk_string("G2I2K", 10, None).unwrap();
+        assert_eq!(chunks, vec![Chunk { start: 0, text: "G2I2K".to_string() }]);
+    }
+
+    #[test]
+    fn test_chunk_split_keeps_timing() {
+        let text = "G2I2K2L2M2N2O2P";
+        let chunks = chunk_string(text, 6, None).unwrap();
+        assert!(chunks.iter().all(|c| c.text.len() <= 6));
+        assert_eq!(chunks.iter().map(|c| c.text.as_str()).collect::<String>(), text);
+        assert_eq!(chunks[1].start, 12);
+    }
+
+    #[test]
+    fn test_chunk_prefers_rest_and_bar() {
+        let chunks = chunk_string("G1G1I5K1L1M", 8, None).unwrap();
+        assert_eq!(chunks[0].text, "G1G1I5");
+        assert_eq!(chunks[1].start, 14);
+
+        let chunks = chunk_string("G1I1K1L1M1N", 8, Some(6)).unwrap();
+        assert_eq!(chunks[0].text, "G1I1K1");
+        assert_eq!(chunks[1].start, 6);
+        let chunks = chunk_string("G1I1K1L1M1N", 8, Some(8)).unwrap();
+        assert_eq!(chunks[0].text, "G1I1K1L1");
+        assert_eq!(chunks[1].start, 8);
+    }
+
+    #[test]
+    fn test_chunk_too_long() {
+        assert_eq!(
+            chunk_string("G@G=G", 4, None),
+            Err(ChunkError::TickTooLong { tick: 0, len: 5, max_len: 4 })
+        );
+    }
+}
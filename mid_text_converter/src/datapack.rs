@@ -0,0 +1,178 @@
+use crate::instruments::InstrumentKind;
+use crate::note::Note;
+use crate::song::Song;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum DatapackError {
+    #[error("Invalid resource name: {0}")]
+    InvalidName(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DatapackOptions {
+    pub namespace: String,
+    /// Name of the folder holding the song's functions.
+    pub name: String,
+    /// Players who hear the song.
+    pub selector: String,
+    pub pack_format: u32,
+}
+
+impl Default for DatapackOptions {
+    fn default() -> Self {
+        Self {
+            namespace: "mid2text".to_string(),
+            name: "song".to_string(),
+            selector: "@a".to_string(),
+            pack_format: 48,
+        }
+    }
+}
+
+/// Sound event name of each instrument.
+pub fn sound(kind: InstrumentKind) -> &'static str {
+    match kind {
+        InstrumentKind::Pling => "block.note_block.pling",
+        InstrumentKind::Hat => "block.note_block.hat",
+        InstrumentKind::Snare => "block.note_block.snare",
+        InstrumentKind::BassDrum => "block.note_block.basedrum",
+        InstrumentKind::Bass => "block.note_block.bass",
+        InstrumentKind::Bell => "block.note_block.bell",
+        InstrumentKind::Chime => "block.note_block.chime",
+        InstrumentKind::Flute => "block.note_block.flute",
+        InstrumentKind::Guitar => "block.note_block.guitar",
+        InstrumentKind::Harp => "block.note_block.harp",
+        InstrumentKind::Xylophone => "block.note_block.xylophone",
+    }
+}
+
+/// `playsound` pitch of a key. Keys outside the note block range are moved by octaves into it,
+/// since the game only plays pitches between 0.5 and 2.0.
+pub fn pitch(key: u8) -> f64 {
+    2f64.powf((Note::fold_key(key) as i32 - 66) as f64 / 12.0)
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "_-.".contains(c))
+}
+
+/// Builds the functions of the song as `(path inside the datapack, contents)`.
+///
+/// `start` plays the first tick; every tick function plays its notes and schedules the next one,
+/// so only one function is pending at a time. `stop` clears whichever one that is.
+pub fn song_to_functions(song: &Song, options: &DatapackOptions) -> Result<Vec<(String, String)>, DatapackError> {
+    for name in [&options.namespace, &options.name] {
+        if !valid_name(name) {
+            return Err(DatapackError::InvalidName(name.clone()));
+        }
+    }
+    let folder = if options.pack_format >= 45 { "function" } else { "functions" };
+    let dir = format!("data/{}/{}/{}", options.namespace, folder, options.name);
+    let id = |tick: u32| format!("{}:{}/t{}", options.namespace, options.name, tick);
+
+    let mut ticks: BTreeMap<u32, Vec<(InstrumentKind, u8)>> = BTreeMap::new();
+    for instrument in &song.tracks {
+        for note in instrument.track().iter() {
+            ticks.entry(note.start_timing).or_default().push((instrument.kind(), note.key.as_int()));
+        }
+    }
+    let order: Vec<u32> = ticks.keys().copied().collect();
+
+    let mut files = Vec::new();
+    let mut start = String::new();
+    let mut stop = String::new();
+    if let Some(first) = order.first() {
+        if *first == 0 {
+            writeln!(start, "function {}", id(0)).unwrap();
+        } else {
+            writeln!(start, "schedule function {} {}t", id(*first), first).unwrap();
+        }
+    }
+
+    for (i, (tick, notes)) in ticks.iter().enumerate() {
+        let mut body = String::new();
+        for (kind, key) in notes {
+            writeln!(
+                body,
+                "execute as {} at @s run playsound minecraft:{} record @s ~ ~ ~ 1 {:.6}",
+                options.selector, sound(*kind), pitch(*key)
+            ).unwrap();
+        }
+        if let Some(next) = order.get(i + 1) {
+            writeln!(body, "schedule function {} {}t", id(*next), next - tick).unwrap();
+        }
+        writeln!(stop, "schedule clear {}", id(*tick)).unwrap();
+        files.push((format!("{}/t{}.mcfunction", dir, tick), body));
+    }
+
+    files.push((format!("{}/start.mcfunction", dir), start));
+    files.push((format!("{}/stop.mcfunction", dir), stop));
+    Ok(files)
+}
+
+/// Writes the song as a datapack into `dir`, including `pack.mcmeta`.
+pub fn write_datapack(song: &Song, dir: &Path, options: &DatapackOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let mcmeta = format!(
+        "{{\n  \"pack\": {{\n    \"pack_format\": {},\n    \"description\": \"{}\"\n  }}\n}}\n",
+        options.pack_format, options.name
+    );
+    std::fs::create_dir_all(dir)?;
+    std::fs::write(dir.join("pack.mcmeta"), mcmeta)?;
+
+    for (path, contents) in song_to_functions(song, options)? {
+        let path = dir.join(path);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, contents)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pitch() {
+        assert!((pitch(66) - 1.0).abs() < 1e-9);
+        assert!((pitch(54) - 0.5).abs() < 1e-9);
+        assert!((pitch(78) - 2.0).abs() < 1e-9);
+        assert!((pitch(90) - 2.0).abs() < 1e-9);
+        assert!((pitch(30) - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_song_to_functions() {
+        let song = Song::from_text("1;M=G3G").unwrap();
+        let files = song_to_functions(&song, &DatapackOptions::default()).unwrap();
+        let names: Vec<&str> = files.iter().map(|(p, _)| p.as_str()).collect();
+        assert_eq!(names, vec![
+            "data/mid2text/function/song/t2.mcfunction",
+            "data/mid2text/function/song/t8.mcfunction",
+            "data/mid2text/function/song/start.mcfunction",
+            "data/mid2text/function/song/stop.mcfunction",
+        ]);
+        assert_eq!(
+            files[0].1,
+            "execute as @a at @s run playsound minecraft:block.note_block.harp record @s ~ ~ ~ 1 1.000000\n\
+             execute as @a at @s run playsound minecraft:block.note_block.basedrum record @s ~ ~ ~ 1 0.707107\n\
+             schedule function mid2text:song/t8 6t\n"
+        );
+        assert_eq!(files[2].1, "schedule function mid2text:song/t2 2t\n");
+        assert_eq!(files[3].1, "schedule clear mid2text:song/t2\nschedule clear mid2text:song/t8\n");
+    }
+
+    #[test]
+    fn test_invalid_name() {
+        let options = DatapackOptions { name: "My Song".to_string(), ..Default::default() };
+        assert_eq!(
+            song_to_functions(&Song::new(), &options),
+            Err(DatapackError::InvalidName("My Song".to_string()))
+        );
+    }
+}
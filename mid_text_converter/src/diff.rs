@@ -0,0 +1,195 @@
+use crate::instruments::InstrumentKind;
+use crate::note::Note;
+use crate::song::Song;
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+
+/// Musical differences of one instrument between two songs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstrumentDiff {
+    pub kind: InstrumentKind,
+    /// Ticks every note of the new song was moved by, if the whole part was shifted.
+    pub shift: i64,
+    /// Semitones every note of the new song was moved by, if the whole part was transposed.
+    pub transpose: i32,
+    /// Notes only in the new song, `(tick, key)`.
+    pub added: Vec<(u32, u8)>,
+    /// Notes only in the old song, `(tick, key)`.
+    pub removed: Vec<(u32, u8)>,
+}
+
+impl InstrumentDiff {
+    pub fn is_empty(&self) -> bool {
+        self.shift == 0 && self.transpose == 0 && self.added.is_empty() && self.removed.is_empty()
+    }
+
+    fn summary_line(&self) -> String {
+        let mut line = format!("{:?}: +{} -{}", self.kind, self.added.len(), self.removed.len());
+        if self.shift != 0 {
+            write!(line, ", shifted {:+} ticks", self.shift).unwrap();
+        }
+        if self.transpose != 0 {
+            write!(line, ", transposed {:+} semitones", self.transpose).unwrap();
+        }
+        line
+    }
+}
+
+/// Result of comparing two songs note by note.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SongDiff {
+    pub instruments: Vec<InstrumentDiff>,
+}
+
+impl SongDiff {
+    pub fn is_empty(&self) -> bool {
+        self.instruments.iter().all(|d| d.is_empty())
+    }
+
+    /// Lists every added and removed note below the summary line of its instrument.
+    pub fn detailed(&self) -> String {
+        let mut result = String::new();
+        for diff in self.instruments.iter().filter(|d| !d.is_empty()) {
+            writeln!(result, "{}", diff.summary_line()).unwrap();
+
+            let mut lines: Vec<(u32, char, u8)> = diff.removed.iter().map(|&(t, k)| (t, '-', k))
+                .chain(diff.added.iter().map(|&(t, k)| (t, '+', k)))
+                .collect();
+            lines.sort();
+            for (tick, sign, key) in lines {
+                writeln!(result, "  {} tick {:>6}  {} ({})", sign, tick, Note::key_name(key), key).unwrap();
+            }
+        }
+        result
+    }
+}
+
+impl fmt::Display for SongDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for diff in self.instruments.iter().filter(|d| !d.is_empty()) {
+            writeln!(f, "{}", diff.summary_line())?;
+        }
+        Ok(())
+    }
+}
+
+fn notes_of(song: &Song, kind: InstrumentKind) -> Vec<(u32, u8)> {
+    let mut notes: Vec<(u32, u8)> = song.tracks.iter()
+        .filter(|t| t.kind() == kind)
+        .flat_map(|t| t.track().iter().map(|n| (n.start_timing, n.key.as_int())))
+        .collect();
+    notes.sort();
+    notes
+}
+
+/// Finds the `(tick, key)` offset that makes the most notes of `old` line up with `new`.
+/// Falls back to no offset unless another one matches more than half of the notes.
+fn best_offset(old: &[(u32, u8)], new: &[(u32, u8)]) -> (i64, i32) {
+    let mut counts: HashMap<(i64, i32), usize> = HashMap::new();
+    for &(ot, ok) in old {
+        for &(nt, nk) in new {
+            *counts.entry((nt as i64 - ot as i64, nk as i32 - ok as i32)).or_insert(0) += 1;
+        }
+    }
+
+    let unchanged = counts.get(&(0, 0)).copied().unwrap_or(0);
+    let threshold = old.len().min(new.len()) / 2;
+    counts.into_iter()
+        .filter(|&(_, count)| count > unchanged && count > threshold)
+        .max_by_key(|&((dt, dk), count)| (count, -dt.abs(), -dk.abs()))
+        .map(|(offset, _)| offset)
+        .unwrap_or((0, 0))
+}
+
+fn diff_instrument(kind: InstrumentKind, old: &[(u32, u8)], new: &[(u32, u8)]) -> InstrumentDiff {
+    let (shift, transpose) = best_offset(old, new);
+
+    let mut remaining: HashMap<(i64, i32), usize> = HashMap::new();
+    for &(t, k) in new {
+        *remaining.entry((t as i64, k as i32)).or_insert(0) += 1;
+    }
+
+    let mut removed = Vec::new();
+    for &(t, k) in old {
+        let moved = (t as i64 + shift, k as i32 + transpose);
+        match remaining.get_mut(&moved) {
+            Some(count) if *count > 0 => *count -= 1,
+            _ => removed.push((t, k)),
+        }
+    }
+
+    let mut added = Vec::new();
+    for &(t, k) in new {
+        if let Some(count) = remaining.get_mut(&(t as i64, k as i32)).filter(|c| **c > 0) {
+            *count -= 1;
+            added.push((t, k));
+        }
+    }
+
+    InstrumentDiff { kind, shift, transpose, added, removed }
+}
+
+/// Compares two songs per instrument.
+///
+/// A constant timing shift or transposition of a whole part is reported once
+/// instead of as every note being removed and added again.
+pub fn diff(old: &Song, new: &Song) -> SongDiff {
+    let instruments = InstrumentKind::ALL.into_iter()
+        .filter_map(|kind| {
+            let old = notes_of(old, kind);
+            let new = notes_of(new, kind);
+            if old.is_empty() && new.is_empty() {
+                None
+            } else {
+                Some(diff_instrument(kind, &old, &new))
+            }
+        })
+        .collect();
+    SongDiff { instruments }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_identical() {
+        let song = Song::from_text("G2I2K@G").unwrap();
+        let result = diff(&song, &song);
+        assert!(result.is_empty());
+        assert_eq!(result.to_string(), "");
+    }
+
+    #[test]
+    fn test_diff_added_removed() {
+        let old = Song::from_text("G2I2K2L").unwrap();
+        let new = Song::from_text("G2I2M2L@G").unwrap();
+        let result = diff(&old, &new);
+
+        assert_eq!(result.instruments.len(), 2);
+        let pling = &result.instruments[0];
+        assert_eq!(pling.kind, InstrumentKind::Pling);
+        assert_eq!(pling.removed, vec![(8, 64)]);
+        assert_eq!(pling.added, vec![(8, 66)]);
+        assert_eq!(result.instruments[1].added, vec![(12, 60)]);
+        assert_eq!(result.to_string(), "Pling: +1 -1\nFlute: +1 -0\n");
+        assert_eq!(
+            result.detailed(),
+            "Pling: +1 -1\n  + tick      8  F#4 (66)\n  - tick      8  E4 (64)\nFlute: +1 -0\n  + tick     12  C4 (60)\n"
+        );
+    }
+
+    #[test]
+    fn test_diff_shift_and_transpose() {
+        let old = Song::from_text("G2I2K2L").unwrap();
+        let new = Song::from_text("1I2K2M2N").unwrap();
+        let result = diff(&old, &new);
+
+        let pling = &result.instruments[0];
+        assert_eq!(pling.shift, 2);
+        assert_eq!(pling.transpose, 2);
+        assert!(pling.added.is_empty());
+        assert!(pling.removed.is_empty());
+        assert_eq!(result.to_string(), "Pling: +0 -0, shifted +2 ticks, transposed +2 semitones\n");
+    }
+}
@@ -27,11 +27,25 @@ impl Track {
         Track(Vec::new())
     }
 
+    #[allow(clippy::unnecessary_sort_by)]
     pub fn merge(&mut self, mut track: Self) {
         self.append(&mut track);
         self.sort_by(|a, b| a.start_timing.cmp(&b.start_timing));
     }
 
+    /// Moves every note later by `ticks`.
+    pub fn shift(&mut self, ticks: u32) {
+        for note in self.iter_mut() {
+            note.start_timing += ticks;
+        }
+    }
+
+    /// Returns the timing of the last note, or 0 for an empty track.
+    pub fn last_timing(&self) -> u32 {
+        self.iter().map(|n| n.start_timing).max().unwrap_or(0)
+    }
+
+    #[allow(clippy::collapsible_if)]
     pub fn midi_to_track(midi: &midly::Smf) -> Self {
         let tracks: Vec<Vec<&midly::TrackEvent>> = midi.tracks
             .iter()
@@ -76,7 +90,7 @@ impl Track {
                             false
                         }
                     }) {
-                        let note = Note::new(*key, current_time / 12);
+                        let note = Note::new(*key, current_time / utils::MIDI_TICKS_PER_TICK);
                         t.push(note);
                         buff_tracks.remove(index);
                     };
@@ -98,6 +112,7 @@ impl Default for Track {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum InstrumentKind {
     Pling,
     Hat,
@@ -112,12 +127,75 @@ pub enum InstrumentKind {
     Xylophone,
 }
 
+impl InstrumentKind {
+    pub const ALL: [InstrumentKind; 11] = [
+        InstrumentKind::Pling,
+        InstrumentKind::Hat,
+        InstrumentKind::Snare,
+        InstrumentKind::BassDrum,
+        InstrumentKind::Bass,
+        InstrumentKind::Bell,
+        InstrumentKind::Chime,
+        InstrumentKind::Flute,
+        InstrumentKind::Guitar,
+        InstrumentKind::Harp,
+        InstrumentKind::Xylophone,
+    ];
+
+    /// The character written before every note of this instrument. Pling has none.
+    pub fn prefix(&self) -> Option<char> {
+        match self {
+            InstrumentKind::Pling => None,
+            InstrumentKind::Hat => Some('!'),
+            InstrumentKind::Snare => Some('?'),
+            InstrumentKind::BassDrum => Some('='),
+            InstrumentKind::Bass => Some('\\'),
+            InstrumentKind::Bell => Some('/'),
+            InstrumentKind::Chime => Some('_'),
+            InstrumentKind::Flute => Some('@'),
+            InstrumentKind::Guitar => Some(':'),
+            InstrumentKind::Harp => Some(';'),
+            InstrumentKind::Xylophone => Some(','),
+        }
+    }
+
+    pub fn from_prefix(prefix: char) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.prefix() == Some(prefix))
+    }
+
+    /// Lowercase name, as used by the command line options.
+    pub fn name(&self) -> &'static str {
+        match self {
+            InstrumentKind::Pling => "pling",
+            InstrumentKind::Hat => "hat",
+            InstrumentKind::Snare => "snare",
+            InstrumentKind::BassDrum => "bassdrum",
+            InstrumentKind::Bass => "bass",
+            InstrumentKind::Bell => "bell",
+            InstrumentKind::Chime => "chime",
+            InstrumentKind::Flute => "flute",
+            InstrumentKind::Guitar => "guitar",
+            InstrumentKind::Harp => "harp",
+            InstrumentKind::Xylophone => "xylophone",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+
+    pub fn is_drum(&self) -> bool {
+        matches!(self, InstrumentKind::Hat | InstrumentKind::Snare | InstrumentKind::BassDrum)
+    }
+}
+
 #[derive(Debug, Error, Clone, PartialEq)]
 pub enum InstrumentError {
     #[error("Merge Different Instrument Types")]
     MergeDifferentInstrumentTypes,
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum Instruments {
     Pling(Track),
     Hat(Track),
@@ -149,7 +227,56 @@ impl Instruments {
         }
     }
 
+    pub fn kind(&self) -> InstrumentKind {
+        match self {
+            Instruments::Pling(_) => InstrumentKind::Pling,
+            Instruments::Hat(_) => InstrumentKind::Hat,
+            Instruments::Snare(_) => InstrumentKind::Snare,
+            Instruments::BassDrum(_) => InstrumentKind::BassDrum,
+            Instruments::Bass(_) => InstrumentKind::Bass,
+            Instruments::Bell(_) => InstrumentKind::Bell,
+            Instruments::Chime(_) => InstrumentKind::Chime,
+            Instruments::Flute(_) => InstrumentKind::Flute,
+            Instruments::Guitar(_) => InstrumentKind::Guitar,
+            Instruments::Harp(_) => InstrumentKind::Harp,
+            Instruments::Xylophone(_) => InstrumentKind::Xylophone,
+        }
+    }
+
+    pub fn track(&self) -> &Track {
+        match self {
+            Instruments::Pling(track)
+            | Instruments::Hat(track)
+            | Instruments::Snare(track)
+            | Instruments::BassDrum(track)
+            | Instruments::Bass(track)
+            | Instruments::Bell(track)
+            | Instruments::Chime(track)
+            | Instruments::Flute(track)
+            | Instruments::Guitar(track)
+            | Instruments::Harp(track)
+            | Instruments::Xylophone(track) => track,
+        }
+    }
+
+    pub fn track_mut(&mut self) -> &mut Track {
+        match self {
+            Instruments::Pling(track)
+            | Instruments::Hat(track)
+            | Instruments::Snare(track)
+            | Instruments::BassDrum(track)
+            | Instruments::Bass(track)
+            | Instruments::Bell(track)
+            | Instruments::Chime(track)
+            | Instruments::Flute(track)
+            | Instruments::Guitar(track)
+            | Instruments::Harp(track)
+            | Instruments::Xylophone(track) => track,
+        }
+    }
+
     /// Merges two tracks of the same instrument type.
+    #[allow(clippy::unnecessary_sort_by)]
     pub fn merge(&mut self, track: Self) -> Result<(), InstrumentError> {
         if mem::discriminant(self) == mem::discriminant(&track) {
             match (self, track) {
@@ -2,3 +2,24 @@ pub mod instruments;
 pub mod note;
 pub mod utils;
 pub mod song;
+pub mod diff;
+pub mod chunk;
+pub mod budget;
+pub mod optimize;
+pub mod smf;
+pub mod render;
+pub mod samples;
+pub mod nbs;
+pub mod datapack;
+pub mod structure;
+pub mod lua;
+pub mod mml;
+pub mod abc;
+pub mod musicxml;
+pub mod notelist;
+pub mod tracker;
+pub mod tab;
+pub mod transcribe;
+pub mod midi2;
+pub mod pianoroll;
+pub mod player;
@@ -0,0 +1,128 @@
+use crate::instruments::InstrumentKind;
+use crate::note::Note;
+use crate::song::Song;
+use crate::utils;
+use std::fmt::Write;
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum LuaError {
+    #[error("Volume must be from 0.0 to 3.0: {0}")]
+    InvalidVolume(f32),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LuaOptions {
+    /// Volume passed to `speaker.playNote`, from 0.0 to 3.0.
+    pub volume: f32,
+    /// Most notes in one data table. Each table lives in its own function,
+    /// which keeps large songs under Lua's per-function constant limits.
+    pub part_size: usize,
+}
+
+impl Default for LuaOptions {
+    fn default() -> Self {
+        Self { volume: 1.0, part_size: 2000 }
+    }
+}
+
+/// Instrument name `speaker.playNote` expects for each instrument.
+pub fn speaker_instrument(kind: InstrumentKind) -> &'static str {
+    match kind {
+        InstrumentKind::BassDrum => "basedrum",
+        _ => kind.name(),
+    }
+}
+
+/// Generates a self-contained CC:Tweaked program that plays the song on an attached speaker.
+///
+/// Notes are stored as `delay, instrument, pitch` triples, where the delay is the number of
+/// ticks since the previous note. Keys outside the note block range are moved by octaves into it.
+pub fn song_to_lua(song: &Song, options: &LuaOptions) -> Result<String, LuaError> {
+    if !(0.0..=3.0).contains(&options.volume) {
+        return Err(LuaError::InvalidVolume(options.volume));
+    }
+    let mut notes: Vec<(u32, InstrumentKind, u8)> = song.tracks.iter()
+        .flat_map(|t| t.track().iter().map(move |n| (n.start_timing, t.kind(), n.key.as_int())))
+        .collect();
+    notes.sort_by_key(|(tick, _, _)| *tick);
+
+    let mut kinds: Vec<InstrumentKind> = Vec::new();
+    for (_, kind, _) in &notes {
+        if !kinds.contains(kind) {
+            kinds.push(*kind);
+        }
+    }
+
+    let mut lua = String::new();
+    writeln!(lua, "-- Generated by mid2text").unwrap();
+    writeln!(lua, "local speaker = peripheral.find(\"speaker\")").unwrap();
+    writeln!(lua, "if not speaker then error(\"No speaker attached\", 0) end").unwrap();
+    writeln!(lua).unwrap();
+    let names: Vec<String> = kinds.iter().map(|k| format!("\"{}\"", speaker_instrument(*k))).collect();
+    writeln!(lua, "local instruments = {{ {} }}", names.join(", ")).unwrap();
+    writeln!(lua, "local volume = {}", options.volume).unwrap();
+    writeln!(lua, "local parts = {{}}").unwrap();
+
+    let mut last = 0;
+    for (i, part) in notes.chunks(options.part_size.max(1)).enumerate() {
+        writeln!(lua).unwrap();
+        writeln!(lua, "parts[{}] = function() return {{", i + 1).unwrap();
+        for (tick, kind, key) in part {
+            let instrument = kinds.iter().position(|k| k == kind).unwrap() + 1;
+            writeln!(lua, "{},{},{},", tick - last, instrument, Note::fold_key(*key) - 54).unwrap();
+            last = *tick;
+        }
+        writeln!(lua, "}} end").unwrap();
+    }
+
+    writeln!(lua).unwrap();
+    writeln!(lua, "for _, part in ipairs(parts) do").unwrap();
+    writeln!(lua, "  local data = part()").unwrap();
+    writeln!(lua, "  for i = 1, #data, 3 do").unwrap();
+    writeln!(lua, "    if data[i] > 0 then sleep(data[i] / {}) end", utils::TICKS_PER_SECOND).unwrap();
+    writeln!(lua, "    speaker.playNote(instruments[data[i + 1]], volume, data[i + 2])").unwrap();
+    writeln!(lua, "  end").unwrap();
+    writeln!(lua, "end").unwrap();
+    Ok(lua)
+}
+
+pub fn write_lua(song: &Song, path: &Path, options: &LuaOptions) -> Result<(), Box<dyn std::error::Error>> {
+    std::fs::write(path, song_to_lua(song, options)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_song_to_lua() {
+        let song = Song::from_text("G=G2.I").unwrap();
+        let lua = song_to_lua(&song, &LuaOptions::default()).unwrap();
+        assert!(lua.contains("local instruments = { \"pling\", \"basedrum\" }\n"));
+        assert!(lua.contains("parts[1] = function() return {\n0,1,6,\n0,2,6,\n5,1,8,\n} end\n"));
+        assert!(lua.contains("sleep(data[i] / 20)"));
+        assert!(!lua.contains("parts[2]"));
+    }
+
+    #[test]
+    fn test_song_to_lua_parts() {
+        let song = Song::from_text("G1I1K").unwrap();
+        let options = LuaOptions { part_size: 2, ..Default::default() };
+        let lua = song_to_lua(&song, &options).unwrap();
+        assert!(lua.contains("parts[1] = function() return {\n0,1,6,\n2,1,8,\n} end\n"));
+        assert!(lua.contains("parts[2] = function() return {\n2,1,10,\n} end\n"));
+    }
+
+    #[test]
+    fn test_invalid_volume() {
+        let song = Song::from_text("G").unwrap();
+        for volume in [-0.5, 3.5, f32::NAN, f32::INFINITY] {
+            let options = LuaOptions { volume, ..Default::default() };
+            assert!(matches!(song_to_lua(&song, &options), Err(LuaError::InvalidVolume(_))));
+        }
+        assert!(song_to_lua(&song, &LuaOptions { volume: 3.0, ..Default::default() }).is_ok());
+    }
+}
@@ -0,0 +1,185 @@
+use crate::instruments::Track;
+use crate::note::Note;
+use crate::utils;
+use midly::num::u7;
+use thiserror::Error;
+
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum ClipError {
+    #[error("Not a MIDI 2.0 clip file")]
+    NotClip,
+    #[error("Truncated packet at byte {0}")]
+    Truncated(usize),
+}
+
+const HEADER: &[u8; 8] = b"SMF2CLIP";
+
+/// Per-note attribute type whose data is the note's pitch in 7.9 fixed point.
+const ATTRIBUTE_PITCH: u8 = 3;
+
+/// Words in a Universal MIDI Packet of each message type.
+fn packet_words(message_type: u8) -> usize {
+    match message_type {
+        0x0..=0x2 | 0x6 | 0x7 => 1,
+        0x3 | 0x4 | 0x8..=0xA => 2,
+        0xB | 0xC => 3,
+        _ => 4,
+    }
+}
+
+/// A note on or off read from the clip.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClipNote {
+    /// Clip ticks from the start of the clip.
+    pub time: u32,
+    /// Group and channel, as `group * 16 + channel`.
+    pub channel: u8,
+    /// Key written in the packet, which pairs note ons with note offs.
+    pub key: u8,
+    /// Key the note plays, from the pitch attribute if there is one.
+    pub pitch: u8,
+    pub on: bool,
+    /// Velocity scaled to 16 bits, as MIDI 2.0 sends it.
+    pub velocity: u16,
+}
+
+/// A parsed clip: its resolution and note events in order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Clip {
+    /// Delta clockstamp ticks per quarter note, 0 if the clip does not say.
+    pub ticks_per_quarter: u16,
+    pub notes: Vec<ClipNote>,
+}
+
+/// Scales a 7-bit MIDI 1.0 velocity to 16 bits the way the MIDI 2.0 translation does.
+fn velocity_16(velocity: u8) -> u16 {
+    let v = velocity as u16 & 0x7F;
+    if v <= 0x40 { v << 9 } else { (v << 9) | ((v & 0x3F) << 3) | ((v & 0x3F) >> 3) }
+}
+
+/// Reads the Universal MIDI Packets of an SMF2CLIP file.
+///
+/// Delta clockstamps move the time forward. MIDI 2.0 notes take their key from the pitch
+/// attribute when they have one, and MIDI 1.0 notes in packets are read too, with velocity
+/// 0 as note off. Other messages are skipped.
+pub fn parse_clip(data: &[u8]) -> Result<Clip, ClipError> {
+    let body = data.strip_prefix(HEADER).ok_or(ClipError::NotClip)?;
+    let mut clip = Clip { ticks_per_quarter: 0, notes: Vec::new() };
+    let mut time = 0u32;
+    let mut pos = 0;
+    while pos < body.len() {
+        let word = |i: usize| -> Result<u32, ClipError> {
+            let at = pos + i * 4;
+            let bytes = body.get(at..at + 4).ok_or(ClipError::Truncated(HEADER.len() + at))?;
+            Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+        };
+        let first = word(0)?;
+        let message_type = (first >> 28) as u8;
+        let words = packet_words(message_type);
+        let second = if words > 1 { word(1)? } else { 0 };
+        let channel = ((first >> 24) & 0x0F) as u8 * 16 + ((first >> 16) & 0x0F) as u8;
+        let status = ((first >> 20) & 0x0F) as u8;
+        let key = ((first >> 8) & 0x7F) as u8;
+
+        match (message_type, status) {
+            // delta clockstamp ticks per quarter note
+            (0x0, 0x3) => clip.ticks_per_quarter = (first & 0xFFFF) as u16,
+            // delta clockstamp
+            (0x0, 0x4) => time += first & 0x000F_FFFF,
+            (0x2, 0x8 | 0x9) => {
+                let velocity = (first & 0x7F) as u8;
+                clip.notes.push(ClipNote { time, channel, key, pitch: key, on: status == 0x9 && velocity > 0, velocity: velocity_16(velocity) });
+            }
+            (0x4, 0x8 | 0x9) => {
+                let attribute = (first & 0xFF) as u8;
+                let pitch = if attribute == ATTRIBUTE_PITCH {
+                    ((second & 0xFFFF) as f32 / 512.0).round().min(127.0) as u8
+                } else {
+                    key
+                };
+                clip.notes.push(ClipNote { time, channel, key, pitch, on: status == 0x9, velocity: (second >> 16) as u16 });
+            }
+            _ => {}
+        }
+        pos += words * 4;
+    }
+    Ok(clip)
+}
+
+impl Clip {
+    /// Converts the clip to the note model of `Track::midi_to_track`: a note on that has a
+    /// matching note off becomes a note at its clip tick divided by `MIDI_TICKS_PER_TICK`.
+    ///
+    /// A note off matches the note on of the same channel and key, even when the pitch
+    /// attribute moved the played key.
+    pub fn to_track(&self) -> Track {
+        let mut open: Vec<&ClipNote> = Vec::new();
+        let mut track = Track::new();
+        for note in &self.notes {
+            if note.on {
+                open.push(note);
+            } else if let Some(index) = open.iter().position(|n| n.channel == note.channel && n.key == note.key) {
+                let on = open.remove(index);
+                track.push(Note::new(u7::from(on.pitch), on.time / utils::MIDI_TICKS_PER_TICK));
+            }
+        }
+        track.sort_by_key(|n| n.start_timing);
+        track
+    }
+}
+
+pub fn clip_to_track(path: &str) -> Result<Track, Box<dyn std::error::Error>> {
+    let data = std::fs::read(path)?;
+    Ok(parse_clip(&data)?.to_track())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clip(words: &[u32]) -> Vec<u8> {
+        let mut data = HEADER.to_vec();
+        for word in words {
+            data.extend(word.to_be_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn test_parse_clip() {
+        let data = clip(&[
+            0x0030_0060, // 96 ticks per quarter
+            0x0040_0000,
+            0xF020_0000, 0, 0, 0, // start of clip
+            0x0040_0000,
+            0x4090_3C00, 0xFFFF_0000, // note on C4, full velocity
+            0x0040_0060,
+            0x4080_3C00, 0x8000_0000, // note off C4
+            0x4090_4003, 0x4000_8200, // note on E4 played as key 65 by its pitch attribute
+            0x0040_0030,
+            0x2093_4364, // MIDI 1.0 note on G4 on channel 3
+            0x0040_0018,
+            0x4080_4000, 0x0000_0000, // note off E4
+            0x2093_4300, // velocity 0 releases G4
+            0x2093_4340, // G4 again, never released
+            0xF021_0000, 0, 0, 0, // end of clip
+        ]);
+        let clip = parse_clip(&data).unwrap();
+        assert_eq!(clip.ticks_per_quarter, 96);
+        assert_eq!(clip.notes[0], ClipNote { time: 0, channel: 0, key: 60, pitch: 60, on: true, velocity: 0xFFFF });
+        assert_eq!(clip.notes[2].pitch, 65);
+        assert_eq!(clip.notes[4].velocity, 0);
+        assert!(!clip.notes[5].on);
+        assert_eq!(clip.notes[6].velocity, 0x8000);
+
+        let track = clip.to_track();
+        let notes: Vec<(u32, u8)> = track.iter().map(|n| (n.start_timing, n.key.as_int())).collect();
+        assert_eq!(notes, vec![(0, 60), (8, 65), (12, 67)]);
+    }
+
+    #[test]
+    fn test_not_clip() {
+        assert_eq!(parse_clip(b"MThd"), Err(ClipError::NotClip));
+        assert_eq!(parse_clip(&clip(&[0x4090_3C00])), Err(ClipError::Truncated(12)));
+    }
+}
@@ -0,0 +1,413 @@
+use crate::instruments::{InstrumentKind, Track};
+use crate::note::Note;
+use crate::song::Song;
+use crate::utils::{self, TempoMap};
+use midly::num::u7;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Write};
+use thiserror::Error;
+
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum MmlError {
+    #[error("Invalid tempo: {0}")]
+    InvalidTempo(u32),
+    #[error("Shortest note length must be a power of two: {0}")]
+    InvalidShortest(u32),
+    #[error("Unexpected character {0:?} at {1}")]
+    UnexpectedCharacter(char, usize),
+    #[error("Invalid note length at {0}")]
+    InvalidLength(usize),
+    #[error("No channel {0}, the song has {1}")]
+    UnknownChannel(String, usize),
+}
+
+/// Tempo of MML songs until a `t` command, in quarter notes per minute.
+pub const DEFAULT_TEMPO: f64 = 120.0;
+
+/// Octaves `o`, `<` and `>` can reach. `o-1c` is key 0 and `o9g` is key 127.
+const MIN_OCTAVE: i32 = -1;
+const MAX_OCTAVE: i32 = 9;
+
+pub const NOTE_NAMES: [&str; 12] = ["c", "c+", "d", "d+", "e", "f", "f+", "g", "g+", "a", "a+", "b"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MmlOptions {
+    /// Quarter notes per minute.
+    pub tempo: u32,
+    /// Shortest note length written, e.g. 16 for sixteenth notes. Sets the quantization grid.
+    pub shortest: u32,
+}
+
+impl Default for MmlOptions {
+    fn default() -> Self {
+        Self { tempo: 150, shortest: 16 }
+    }
+}
+
+/// How far the notes had to move to land on the MML grid.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quantization {
+    /// Length of one grid step in ticks.
+    pub grid: f64,
+    /// Notes whose tick was not on the grid.
+    pub moved: usize,
+    /// Largest distance a note was moved, in ticks.
+    pub max_shift: f64,
+    /// Notes dropped because another note of the same key and instrument landed on the same step.
+    pub merged: usize,
+}
+
+impl Display for Quantization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.moved == 0 {
+            return write!(f, "no quantization (grid {} ticks)", self.grid);
+        }
+        write!(f, "quantized {} notes to a {} tick grid, by up to {} ticks", self.moved, self.grid, self.max_shift)?;
+        if self.merged > 0 {
+            write!(f, ", merged {} notes", self.merged)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mml {
+    /// One monophonic channel per voice; chords take several channels of the same instrument.
+    pub channels: Vec<(InstrumentKind, String)>,
+    pub quantization: Quantization,
+}
+
+impl Display for Mml {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (_, text) in &self.channels {
+            writeln!(f, "{}", text)?;
+        }
+        Ok(())
+    }
+}
+
+/// Length tokens adding up to `steps` grid steps, where one step is a `shortest` note.
+/// Ties between the tokens are written by the caller.
+pub fn lengths(steps: u32, shortest: u32) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut rest = steps;
+    while rest > 0 {
+        let mut size = shortest.min(rest.next_power_of_two());
+        if size > rest {
+            size /= 2;
+        }
+        let denominator = shortest / size;
+        if size >= 2 && size + size / 2 <= rest {
+            tokens.push(format!("{}.", denominator));
+            rest -= size + size / 2;
+        } else {
+            tokens.push(denominator.to_string());
+            rest -= size;
+        }
+    }
+    tokens
+}
+
+/// Octave and note name of a key, with `o4c` being middle C.
+pub fn key_to_mml(key: u8) -> (i32, &'static str) {
+    (key as i32 / 12 - 1, NOTE_NAMES[key as usize % 12])
+}
+
+/// Writes the song as MML, one channel per instrument voice.
+///
+/// Each note lasts until the next note of its instrument; the rest of the gap to the voice's next
+/// note is a rest. Ticks are rounded to the nearest `shortest` note at `tempo`.
+pub fn song_to_mml(song: &Song, options: &MmlOptions) -> Result<Mml, MmlError> {
+    if options.tempo == 0 {
+        return Err(MmlError::InvalidTempo(options.tempo));
+    }
+    if !options.shortest.is_power_of_two() {
+        return Err(MmlError::InvalidShortest(options.shortest));
+    }
+    let whole = 4.0 * 60.0 * utils::TICKS_PER_SECOND as f64 / options.tempo as f64;
+    let grid = whole / options.shortest as f64;
+    let quantize = |tick: u32| (tick as f64 / grid).round() as u32;
+
+    let mut quantization = Quantization { grid, moved: 0, max_shift: 0.0, merged: 0 };
+    let mut groups: Vec<(InstrumentKind, BTreeMap<u32, Vec<u8>>)> = Vec::new();
+    for track in &song.tracks {
+        let index = match groups.iter().position(|(k, _)| *k == track.kind()) {
+            Some(index) => index,
+            None => {
+                groups.push((track.kind(), BTreeMap::new()));
+                groups.len() - 1
+            }
+        };
+        for note in track.track().iter() {
+            let step = quantize(note.start_timing);
+            let shift = (step as f64 * grid - note.start_timing as f64).abs();
+            if shift > 1e-9 {
+                quantization.moved += 1;
+                quantization.max_shift = quantization.max_shift.max(shift);
+            }
+            let keys = groups[index].1.entry(step).or_default();
+            if keys.contains(&note.key.as_int()) {
+                quantization.merged += 1;
+            } else {
+                keys.push(note.key.as_int());
+            }
+        }
+    }
+    let end = quantize(song.length());
+
+    let mut channels = Vec::new();
+    for (kind, steps) in &groups {
+        let onsets: Vec<u32> = steps.keys().copied().collect();
+        let mut voices: Vec<Vec<(u32, u8, u32)>> = Vec::new();
+        for (i, (step, keys)) in steps.iter().enumerate() {
+            let next = onsets.get(i + 1).copied().unwrap_or(end.max(step + 1));
+            let mut keys = keys.clone();
+            keys.sort_by(|a, b| b.cmp(a));
+            for (voice, key) in keys.into_iter().enumerate() {
+                if voices.len() <= voice {
+                    voices.push(Vec::new());
+                }
+                voices[voice].push((*step, key, next - step));
+            }
+        }
+
+        for voice in voices {
+            let mut text = format!("t{}", options.tempo);
+            let mut position = 0;
+            let mut octave = None;
+            for (step, key, length) in voice {
+                if step > position {
+                    for token in lengths(step - position, options.shortest) {
+                        write!(text, "r{}", token).unwrap();
+                    }
+                }
+                let (o, name) = key_to_mml(key);
+                if octave != Some(o) {
+                    write!(text, "o{}", o).unwrap();
+                    octave = Some(o);
+                }
+                let tokens: Vec<String> = lengths(length, options.shortest).iter().map(|t| format!("{}{}", name, t)).collect();
+                text.push_str(&tokens.join("&"));
+                position = step + length;
+            }
+            channels.push((*kind, text));
+        }
+    }
+    Ok(Mml { channels, quantization })
+}
+
+struct Reader {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Reader {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn number(&mut self) -> Option<u32> {
+        let start = self.position;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.position += 1;
+        }
+        self.chars[start..self.position].iter().collect::<String>().parse().ok()
+    }
+
+    /// Reads an optional length with dots, in quarter notes.
+    fn length(&mut self, default: f64) -> Result<f64, MmlError> {
+        let start = self.position;
+        let mut length = match self.number() {
+            Some(0) => return Err(MmlError::InvalidLength(start)),
+            Some(n) => 4.0 / n as f64,
+            None => default,
+        };
+        let mut dot = length / 2.0;
+        while self.peek() == Some('.') {
+            self.position += 1;
+            length += dot;
+            dot /= 2.0;
+        }
+        Ok(length)
+    }
+}
+
+/// Reads MML into one track per channel, with channels separated by `;` or `,`.
+///
+/// Supports `t` tempo, `o`, `<` and `>` octaves, `l` default length, dotted lengths, rests,
+/// and ties with `&` or `^`. A tie to the same key continues the note instead of playing it again.
+/// Tempo changes apply to every channel. Volume, instrument and other commands are skipped.
+pub fn parse_mml(text: &str) -> Result<Vec<Track>, MmlError> {
+    let text = text.trim();
+    let text = text.strip_prefix("MML@").or_else(|| text.strip_prefix("mml@")).unwrap_or(text);
+    let mut reader = Reader { chars: text.chars().map(|c| c.to_ascii_lowercase()).collect(), position: 0 };
+
+    let mut channels: Vec<Vec<(f64, u8)>> = vec![Vec::new()];
+    let mut tempos = Vec::new();
+    let (mut octave, mut default, mut position) = (4i32, 1.0, 0.0);
+    let mut tied: Option<u8> = None;
+    let mut last_key: Option<u8> = None;
+
+    while let Some(c) = reader.peek() {
+        let start = reader.position;
+        reader.position += 1;
+        match c {
+            ';' | ',' => {
+                channels.push(Vec::new());
+                (octave, default, position) = (4, 1.0, 0.0);
+                tied = None;
+                last_key = None;
+            }
+            'c' | 'd' | 'e' | 'f' | 'g' | 'a' | 'b' => {
+                let mut key = (octave + 1) * 12 + NOTE_NAMES.iter().position(|n| n.starts_with(c)).unwrap() as i32;
+                while let Some(accidental) = reader.peek() {
+                    match accidental {
+                        '+' | '#' => key += 1,
+                        '-' => key -= 1,
+                        _ => break,
+                    }
+                    reader.position += 1;
+                }
+                let key = key.clamp(0, 127) as u8;
+                let mut length = reader.length(default)?;
+                while reader.peek() == Some('^') {
+                    reader.position += 1;
+                    length += reader.length(default)?;
+                }
+                if tied != Some(key) {
+                    channels.last_mut().unwrap().push((position, key));
+                }
+                tied = None;
+                last_key = Some(key);
+                position += length;
+            }
+            '&' => {
+                if reader.peek().is_some_and(|c| c.is_ascii_digit()) {
+                    position += reader.length(default)?;
+                } else {
+                    tied = last_key;
+                }
+            }
+            'r' => {
+                position += reader.length(default)?;
+                while reader.peek() == Some('^') {
+                    reader.position += 1;
+                    position += reader.length(default)?;
+                }
+                last_key = None;
+            }
+            'l' => default = reader.length(default)?,
+            'o' => octave = reader.number().map(|n| n.min(MAX_OCTAVE as u32) as i32).unwrap_or(octave),
+            '<' => octave = (octave - 1).max(MIN_OCTAVE),
+            '>' => octave = (octave + 1).min(MAX_OCTAVE),
+            't' => {
+                if let Some(tempo) = reader.number().filter(|t| *t > 0) {
+                    tempos.push((position, tempo as f64));
+                }
+            }
+            c if c.is_whitespace() => {}
+            c if c.is_ascii_alphabetic() || c == '@' => {
+                reader.number();
+            }
+            c => return Err(MmlError::UnexpectedCharacter(c, start)),
+        }
+    }
+
+    let tempo_map = TempoMap::new(DEFAULT_TEMPO, tempos);
+    Ok(channels.into_iter()
+        .map(|notes| {
+            let mut track = Track::new();
+            for (at, key) in notes {
+                track.push(Note::new(u7::from(key), tempo_map.ticks(at)));
+            }
+            track.sort_by_key(|n| n.start_timing);
+            track
+        })
+        .collect())
+}
+
+/// Reads an MML file like `mid_to_track`. `song.mml#2` takes only the second channel;
+/// without it every channel is merged.
+pub fn mml_to_track(path: &str) -> Result<Track, Box<dyn std::error::Error>> {
+    let (file, selector) = path.split_once('#').unwrap_or((path, ""));
+    let channels = parse_mml(&std::fs::read_to_string(file)?)?;
+    if selector.is_empty() {
+        let mut track = Track::new();
+        for channel in channels {
+            track.merge(channel);
+        }
+        return Ok(track);
+    }
+    let count = channels.len();
+    selector.parse::<usize>().ok()
+        .filter(|i| (1..=count).contains(i))
+        .map(|i| channels[i - 1].clone())
+        .ok_or_else(|| MmlError::UnknownChannel(selector.to_string(), count).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lengths() {
+        assert_eq!(lengths(1, 16), vec!["16"]);
+        assert_eq!(lengths(3, 16), vec!["8."]);
+        assert_eq!(lengths(5, 16), vec!["4", "16"]);
+        assert_eq!(lengths(16, 16), vec!["1"]);
+        assert_eq!(lengths(40, 16), vec!["1.", "1"]);
+    }
+
+    #[test]
+    fn test_song_to_mml() {
+        let song = Song::from_text("G2I2.K2").unwrap();
+        let mml = song_to_mml(&song, &MmlOptions::default()).unwrap();
+        assert_eq!(mml.channels, vec![(InstrumentKind::Pling, "t150o4c8d8.e8".to_string())]);
+        assert_eq!(mml.quantization.moved, 1);
+        assert_eq!(mml.quantization.max_shift, 1.0);
+    }
+
+    #[test]
+    fn test_chord_voices() {
+        let song = Song::from_text("GK4I").unwrap();
+        let mml = song_to_mml(&song, &MmlOptions::default()).unwrap();
+        assert_eq!(mml.channels, vec![
+            (InstrumentKind::Pling, "t150o4e4d16".to_string()),
+            (InstrumentKind::Pling, "t150o4c4".to_string()),
+        ]);
+        assert_eq!(mml.quantization.moved, 0);
+    }
+
+    fn track(notes: &[(u8, u32)]) -> Track {
+        Track(notes.iter().map(|(key, tick)| Note::new(u7::from(*key), *tick)).collect())
+    }
+
+    #[test]
+    fn test_parse_mml() {
+        let tracks = parse_mml("MML@t150l8o4c d4. e&e f&8 g^16 >c<b-; o3c2 r c+").unwrap();
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[0], track(&[(60, 0), (62, 4), (64, 16), (65, 24), (67, 32), (72, 38), (70, 42)]));
+        assert_eq!(tracks[1], track(&[(48, 0), (49, 24)]));
+    }
+
+    #[test]
+    fn test_parse_mml_tempo_and_errors() {
+        let tracks = parse_mml("c4 t60 c4 c4").unwrap();
+        assert_eq!(tracks[0], track(&[(60, 0), (60, 10), (60, 30)]));
+        assert_eq!(parse_mml("c4 $"), Err(MmlError::UnexpectedCharacter('$', 3)));
+        assert_eq!(parse_mml("c0"), Err(MmlError::InvalidLength(1)));
+    }
+
+    #[test]
+    fn test_parse_mml_octave_limits() {
+        assert_eq!(parse_mml("o300000000c >>>g").unwrap()[0], track(&[(120, 0), (127, 10)]));
+        let low = format!("o0{}c", "<".repeat(100));
+        assert_eq!(parse_mml(&low).unwrap()[0], track(&[(0, 0)]));
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        let options = MmlOptions { shortest: 12, ..Default::default() };
+        assert_eq!(song_to_mml(&Song::new(), &options), Err(MmlError::InvalidShortest(12)));
+    }
+}
@@ -0,0 +1,456 @@
+use crate::instruments::Track;
+use crate::note::Note;
+use crate::utils::TempoMap;
+use midly::num::u7;
+use roxmltree::{Document, Node};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum MusicXmlError {
+    #[error("Invalid XML: {0}")]
+    Xml(#[from] roxmltree::Error),
+    #[error("Only partwise scores are supported, found <{0}>")]
+    NotPartwise(String),
+    #[error("No part or voice named {0}, available: {1}")]
+    UnknownPart(String, String),
+    #[error("No score found in the .mxl archive")]
+    ScoreNotFound,
+}
+
+/// Tempo used until the score sets one, in quarter notes per minute.
+pub const DEFAULT_TEMPO: f64 = 120.0;
+
+/// One `<part>` of the score, with a track for each voice.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScorePart {
+    pub id: String,
+    pub name: String,
+    pub voices: Vec<(String, Track)>,
+}
+
+impl ScorePart {
+    /// All voices of the part in one track.
+    pub fn track(&self) -> Track {
+        let mut track = Track::new();
+        for (_, voice) in &self.voices {
+            track.merge(voice.clone());
+        }
+        track
+    }
+}
+
+/// Barline marks of a measure, used to expand repeats.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Barlines {
+    pub(crate) forward: bool,
+    /// Number of times the section is played, if the measure ends with a backward repeat.
+    pub(crate) backward: Option<u32>,
+    /// Volta numbers the measure is played on, empty outside of endings.
+    pub(crate) ending: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Measure {
+    /// Onsets as quarter notes from the start of the measure, with the key and voice.
+    pub(crate) notes: Vec<(f64, u8, String)>,
+    pub(crate) tempos: Vec<(f64, f64)>,
+    pub(crate) length: f64,
+    pub(crate) barlines: Barlines,
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|n| n.has_tag_name(name))
+}
+
+fn child_text<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    child(node, name).and_then(|n| n.text()).map(str::trim)
+}
+
+fn child_number(node: Node, name: &str) -> Option<f64> {
+    child_text(node, name).and_then(|t| t.parse().ok())
+}
+
+/// MIDI key of a `<pitch>` or `<unpitched>` element.
+fn pitch_key(pitch: Node) -> Option<u8> {
+    let (step, octave) = if pitch.has_tag_name("unpitched") {
+        (child_text(pitch, "display-step")?, child_number(pitch, "display-octave")?)
+    } else {
+        (child_text(pitch, "step")?, child_number(pitch, "octave")?)
+    };
+    let pc = match step {
+        "C" => 0,
+        "D" => 2,
+        "E" => 4,
+        "F" => 5,
+        "G" => 7,
+        "A" => 9,
+        "B" => 11,
+        _ => return None,
+    };
+    let alter = child_number(pitch, "alter").unwrap_or(0.0).round() as i32;
+    Some(((octave as i32 + 1) * 12 + pc + alter).clamp(0, 127) as u8)
+}
+
+/// Tempo of a `<metronome>` mark in quarter notes per minute.
+fn metronome_tempo(metronome: Node) -> Option<f64> {
+    let unit = match child_text(metronome, "beat-unit")? {
+        "whole" => 4.0,
+        "half" => 2.0,
+        "quarter" => 1.0,
+        "eighth" => 0.5,
+        "16th" => 0.25,
+        _ => return None,
+    };
+    let dot = if child(metronome, "beat-unit-dot").is_some() { 1.5 } else { 1.0 };
+    Some(child_number(metronome, "per-minute")? * unit * dot)
+}
+
+fn direction_tempo(direction: Node) -> Option<f64> {
+    if let Some(tempo) = child(direction, "sound").and_then(|s| s.attribute("tempo")).and_then(|t| t.parse().ok()) {
+        return Some(tempo);
+    }
+    direction.descendants().find(|n| n.has_tag_name("metronome")).and_then(metronome_tempo)
+}
+
+fn parse_barline(barline: Node, barlines: &mut Barlines) {
+    if let Some(repeat) = child(barline, "repeat") {
+        match repeat.attribute("direction") {
+            Some("forward") => barlines.forward = true,
+            Some("backward") => {
+                barlines.backward = Some(repeat.attribute("times").and_then(|t| t.parse().ok()).unwrap_or(2));
+            }
+            _ => {}
+        }
+    }
+    if let Some(ending) = child(barline, "ending") {
+        let numbers = ending.attribute("number").unwrap_or("1");
+        for number in numbers.split([',', ' ']).filter_map(|n| n.trim().parse().ok()) {
+            if !barlines.ending.contains(&number) {
+                barlines.ending.push(number);
+            }
+        }
+    }
+}
+
+/// Reads the measures of one part. `divisions` carries over between measures.
+fn parse_part(part: Node) -> Vec<Measure> {
+    let mut divisions = 1.0;
+    let mut measures = Vec::new();
+    for measure in part.children().filter(|n| n.has_tag_name("measure")) {
+        let mut result = Measure::default();
+        let mut position = 0.0;
+        let mut last_start = 0.0;
+        for element in measure.children().filter(|n| n.is_element()) {
+            match element.tag_name().name() {
+                "attributes" => {
+                    if let Some(d) = child_number(element, "divisions") {
+                        divisions = d;
+                    }
+                }
+                "backup" => position -= child_number(element, "duration").unwrap_or(0.0) / divisions,
+                "forward" => position += child_number(element, "duration").unwrap_or(0.0) / divisions,
+                "direction" => {
+                    if let Some(tempo) = direction_tempo(element) {
+                        result.tempos.push((position, tempo));
+                    }
+                }
+                "sound" => {
+                    if let Some(tempo) = element.attribute("tempo").and_then(|t| t.parse().ok()) {
+                        result.tempos.push((position, tempo));
+                    }
+                }
+                "barline" => parse_barline(element, &mut result.barlines),
+                "note" => {
+                    if child(element, "grace").is_some() {
+                        continue;
+                    }
+                    let start = if child(element, "chord").is_some() { last_start } else { position };
+                    let tied = element.children().any(|n| n.has_tag_name("tie") && n.attribute("type") == Some("stop"));
+                    let key = element.children()
+                        .find(|n| n.has_tag_name("pitch") || n.has_tag_name("unpitched"))
+                        .and_then(pitch_key);
+                    if let Some(key) = key
+                        && !tied
+                        && child(element, "cue").is_none()
+                    {
+                        let voice = child_text(element, "voice").unwrap_or("1").to_string();
+                        result.notes.push((start, key, voice));
+                    }
+                    if child(element, "chord").is_none() {
+                        last_start = position;
+                        position += child_number(element, "duration").unwrap_or(0.0) / divisions;
+                    }
+                }
+                _ => {}
+            }
+            result.length = result.length.max(position);
+        }
+        measures.push(result);
+    }
+    measures
+}
+
+/// Order the measures are played in, with repeats and voltas expanded.
+pub(crate) fn play_order(barlines: &[Barlines]) -> Vec<usize> {
+    let mut order = Vec::new();
+    let mut i = 0;
+    let mut section_start = 0;
+    let mut pass = 1;
+    let mut jumped = false;
+    let mut in_endings = false;
+    while i < barlines.len() {
+        let measure = &barlines[i];
+        if measure.forward && !jumped {
+            section_start = i;
+            pass = 1;
+        }
+        if measure.ending.is_empty() {
+            if in_endings {
+                section_start = i;
+                pass = 1;
+                in_endings = false;
+            }
+        } else {
+            in_endings = true;
+            if !measure.ending.contains(&pass) {
+                i += 1;
+                continue;
+            }
+        }
+        jumped = false;
+        order.push(i);
+        match measure.backward {
+            Some(times) if pass < times => {
+                pass += 1;
+                i = section_start;
+                jumped = true;
+                in_endings = false;
+                continue;
+            }
+            Some(_) if !in_endings => {
+                section_start = i + 1;
+                pass = 1;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    order
+}
+
+/// Reads a partwise MusicXML score into one `ScorePart` per part.
+///
+/// Repeats and voltas are played out, tied notes only sound once, grace and cue notes are skipped.
+pub fn parse_musicxml(xml: &str) -> Result<Vec<ScorePart>, MusicXmlError> {
+    let document = Document::parse(xml)?;
+    let root = document.root_element();
+    if !root.has_tag_name("score-partwise") {
+        return Err(MusicXmlError::NotPartwise(root.tag_name().name().to_string()));
+    }
+
+    let mut names = BTreeMap::new();
+    if let Some(list) = child(root, "part-list") {
+        for score_part in list.children().filter(|n| n.has_tag_name("score-part")) {
+            let name = child_text(score_part, "part-name").unwrap_or("").to_string();
+            names.insert(score_part.attribute("id").unwrap_or("").to_string(), name);
+        }
+    }
+
+    let parts: Vec<(String, Vec<Measure>)> = root.children()
+        .filter(|n| n.has_tag_name("part"))
+        .map(|p| (p.attribute("id").unwrap_or("").to_string(), parse_part(p)))
+        .collect();
+
+    // Parts normally share their barlines and measure lengths, so they are combined.
+    let count = parts.iter().map(|(_, m)| m.len()).max().unwrap_or(0);
+    let mut barlines = vec![Barlines::default(); count];
+    let mut lengths = vec![0.0f64; count];
+    for (_, measures) in &parts {
+        for (i, measure) in measures.iter().enumerate() {
+            let b = &mut barlines[i];
+            b.forward |= measure.barlines.forward;
+            b.backward = b.backward.or(measure.barlines.backward);
+            if b.ending.is_empty() {
+                b.ending = measure.barlines.ending.clone();
+            }
+            lengths[i] = lengths[i].max(measure.length);
+        }
+    }
+
+    let order = play_order(&barlines);
+    let mut starts = Vec::with_capacity(order.len());
+    let mut position = 0.0;
+    for i in &order {
+        starts.push(position);
+        position += lengths[*i];
+    }
+
+    let mut tempos = Vec::new();
+    for (_, measures) in &parts {
+        for (i, start) in order.iter().zip(&starts) {
+            if let Some(measure) = measures.get(*i) {
+                tempos.extend(measure.tempos.iter().map(|(at, tempo)| (start + at, *tempo)));
+            }
+        }
+    }
+    let tempo_map = TempoMap::new(DEFAULT_TEMPO, tempos);
+
+    let mut result = Vec::new();
+    for (id, measures) in &parts {
+        let mut voices: Vec<(String, Track)> = Vec::new();
+        for (i, start) in order.iter().zip(&starts) {
+            let Some(measure) = measures.get(*i) else { continue };
+            for (at, key, voice) in &measure.notes {
+                let note = Note::new(u7::from(*key), tempo_map.ticks(start + at));
+                match voices.iter_mut().find(|(v, _)| v == voice) {
+                    Some((_, track)) => track.push(note),
+                    None => {
+                        let mut track = Track::new();
+                        track.push(note);
+                        voices.push((voice.clone(), track));
+                    }
+                }
+            }
+        }
+        for (_, track) in &mut voices {
+            track.sort_by_key(|n| n.start_timing);
+        }
+        result.push(ScorePart { id: id.clone(), name: names.get(id).cloned().unwrap_or_default(), voices });
+    }
+    Ok(result)
+}
+
+/// Finds the score inside a compressed `.mxl` archive.
+fn read_mxl(data: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(data))?;
+    let mut path = None;
+    if let Ok(mut container) = archive.by_name("META-INF/container.xml") {
+        let mut xml = String::new();
+        container.read_to_string(&mut xml)?;
+        let document = Document::parse(&xml)?;
+        path = document.descendants()
+            .find(|n| n.has_tag_name("rootfile"))
+            .and_then(|n| n.attribute("full-path"))
+            .map(str::to_string);
+    }
+    let path = match path {
+        Some(path) => path,
+        None => archive.file_names()
+            .find(|n| !n.starts_with("META-INF") && (n.ends_with(".xml") || n.ends_with(".musicxml")))
+            .map(str::to_string)
+            .ok_or(MusicXmlError::ScoreNotFound)?,
+    };
+    let mut xml = String::new();
+    archive.by_name(&path)?.read_to_string(&mut xml)?;
+    Ok(xml)
+}
+
+/// Reads a `.musicxml`, `.xml` or compressed `.mxl` file.
+pub fn read_musicxml(path: &Path) -> Result<Vec<ScorePart>, Box<dyn std::error::Error>> {
+    let data = std::fs::read(path)?;
+    let xml = if data.starts_with(b"PK") { read_mxl(&data)? } else { String::from_utf8(data)? };
+    Ok(parse_musicxml(&xml)?)
+}
+
+/// Picks the notes of `selector` from the parts: a part id, part name or 1-based part number,
+/// optionally followed by `.<voice>`. An empty selector takes every part.
+pub fn select_track(parts: &[ScorePart], selector: &str) -> Result<Track, MusicXmlError> {
+    if selector.is_empty() {
+        let mut track = Track::new();
+        for part in parts {
+            track.merge(part.track());
+        }
+        return Ok(track);
+    }
+    let (part_name, voice) = match selector.rsplit_once('.') {
+        Some((part, voice)) => (part, Some(voice)),
+        None => (selector, None),
+    };
+    let part = parts.iter().enumerate()
+        .find(|(i, p)| p.id == part_name || p.name == part_name || (i + 1).to_string() == part_name)
+        .map(|(_, p)| p);
+    let track = match (part, voice) {
+        (Some(part), None) => Some(part.track()),
+        (Some(part), Some(voice)) => part.voices.iter().find(|(v, _)| v == voice).map(|(_, t)| t.clone()),
+        _ => None,
+    };
+    track.ok_or_else(|| {
+        let available: Vec<String> = parts.iter()
+            .flat_map(|p| p.voices.iter().map(move |(v, _)| format!("{}.{} ({})", p.id, v, p.name)))
+            .collect();
+        MusicXmlError::UnknownPart(selector.to_string(), available.join(", "))
+    })
+}
+
+/// Reads `path` like `mid_to_track`. `score.musicxml#P2` or `score.mxl#Piano.1` selects
+/// a part or voice; without a selector every part is merged.
+pub fn musicxml_to_track(path: &str) -> Result<Track, Box<dyn std::error::Error>> {
+    let (file, selector) = path.split_once('#').unwrap_or((path, ""));
+    let parts = read_musicxml(Path::new(file))?;
+    Ok(select_track(&parts, selector)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(key: u8, tick: u32) -> Note {
+        Note::new(u7::from(key), tick)
+    }
+
+    const SCORE: &str = r#"<?xml version="1.0"?>
+<score-partwise version="4.0">
+  <part-list>
+    <score-part id="P1"><part-name>Piano</part-name></score-part>
+  </part-list>
+  <part id="P1">
+    <measure number="1">
+      <attributes><divisions>2</divisions></attributes>
+      <direction><sound tempo="150"/></direction>
+      <note><pitch><step>C</step><octave>4</octave></pitch><duration>2</duration><voice>1</voice></note>
+      <note><chord/><pitch><step>E</step><octave>4</octave></pitch><duration>2</duration><voice>1</voice></note>
+      <note><pitch><step>F</step><alter>1</alter><octave>4</octave></pitch><duration>2</duration><voice>1</voice><tie type="start"/></note>
+      <note><pitch><step>F</step><alter>1</alter><octave>4</octave></pitch><duration>2</duration><voice>1</voice><tie type="stop"/></note>
+      <note><rest/><duration>2</duration><voice>1</voice></note>
+      <backup><duration>8</duration></backup>
+      <note><pitch><step>C</step><octave>3</octave></pitch><duration>8</duration><voice>2</voice></note>
+    </measure>
+  </part>
+</score-partwise>"#;
+
+    #[test]
+    fn test_parse_musicxml() {
+        let parts = parse_musicxml(SCORE).unwrap();
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].name, "Piano");
+        let voices: Vec<&str> = parts[0].voices.iter().map(|(v, _)| v.as_str()).collect();
+        assert_eq!(voices, vec!["1", "2"]);
+        assert_eq!(parts[0].voices[0].1, Track(vec![note(60, 0), note(64, 0), note(66, 8)]));
+        assert_eq!(parts[0].voices[1].1, Track(vec![note(48, 0)]));
+        assert_eq!(select_track(&parts, "P1.2").unwrap(), Track(vec![note(48, 0)]));
+        assert_eq!(select_track(&parts, "").unwrap().len(), 4);
+        assert!(select_track(&parts, "P9").is_err());
+    }
+
+    fn barlines(marks: &[(bool, Option<u32>, &[u32])]) -> Vec<Barlines> {
+        marks.iter()
+            .map(|(forward, backward, ending)| Barlines { forward: *forward, backward: *backward, ending: ending.to_vec() })
+            .collect()
+    }
+
+    #[test]
+    fn test_play_order() {
+        // A |: B C :| D
+        let simple = barlines(&[(false, None, &[]), (true, None, &[]), (false, Some(2), &[]), (false, None, &[])]);
+        assert_eq!(play_order(&simple), vec![0, 1, 2, 1, 2, 3]);
+
+        // A B [1 C :| [2 D | E, repeating from the start
+        let voltas = barlines(&[
+            (false, None, &[]), (false, None, &[]), (false, Some(2), &[1]), (false, None, &[2]), (false, None, &[]),
+        ]);
+        assert_eq!(play_order(&voltas), vec![0, 1, 2, 0, 1, 3, 4]);
+    }
+}
@@ -0,0 +1,414 @@
+use crate::instruments::{InstrumentKind, Instruments, Track};
+use crate::note::Note;
+use crate::song::Song;
+use crate::utils;
+use midly::num::u7;
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum NbsError {
+    #[error("Unexpected end of file at byte {0}")]
+    UnexpectedEof(usize),
+    #[error("Invalid tempo: {0}")]
+    InvalidTempo(i16),
+    #[error("Song is too long for NBS at tick {0}, the most is {max}", max = i16::MAX)]
+    TooLong(u32),
+}
+
+/// Version of the Note Block Studio format that is written.
+pub const NBS_VERSION: u8 = 5;
+
+/// Number of vanilla instruments known to `NBS_VERSION`.
+const VANILLA_INSTRUMENTS: u8 = 16;
+
+/// Difference between a MIDI key and an NBS key, which starts at A0.
+const KEY_OFFSET: u8 = 21;
+
+/// Song information written to the header.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NbsMeta {
+    pub name: String,
+    pub author: String,
+    pub original_author: String,
+    pub description: String,
+}
+
+/// NBS instrument id of each instrument.
+pub fn nbs_instrument(kind: InstrumentKind) -> u8 {
+    match kind {
+        InstrumentKind::Harp => 0,
+        InstrumentKind::Bass => 1,
+        InstrumentKind::BassDrum => 2,
+        InstrumentKind::Snare => 3,
+        InstrumentKind::Hat => 4,
+        InstrumentKind::Guitar => 5,
+        InstrumentKind::Flute => 6,
+        InstrumentKind::Bell => 7,
+        InstrumentKind::Chime => 8,
+        InstrumentKind::Xylophone => 9,
+        InstrumentKind::Pling => 15,
+    }
+}
+
+/// Instrument for an NBS instrument id. Vanilla instruments the game lacks are mapped
+/// to the closest one, custom instruments to Harp.
+pub fn instrument_kind(id: u8) -> InstrumentKind {
+    match id {
+        1 | 12 => InstrumentKind::Bass,
+        2 => InstrumentKind::BassDrum,
+        3 => InstrumentKind::Snare,
+        4 => InstrumentKind::Hat,
+        5 | 14 => InstrumentKind::Guitar,
+        6 => InstrumentKind::Flute,
+        7 | 11 => InstrumentKind::Bell,
+        8 => InstrumentKind::Chime,
+        9 | 10 => InstrumentKind::Xylophone,
+        13 | 15 => InstrumentKind::Pling,
+        _ => InstrumentKind::Harp,
+    }
+}
+
+/// NBS key of a MIDI key, clamped to the 88 keys NBS knows.
+pub fn nbs_key(key: u8) -> u8 {
+    key.clamp(KEY_OFFSET, KEY_OFFSET + 87) - KEY_OFFSET
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn byte(&mut self, v: u8) {
+        self.0.push(v);
+    }
+
+    fn short(&mut self, v: i16) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn int(&mut self, v: i32) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn string(&mut self, v: &str) {
+        self.int(v.len() as i32);
+        self.0.extend_from_slice(v.as_bytes());
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8], NbsError> {
+        let bytes = self.data.get(self.pos..self.pos + n).ok_or(NbsError::UnexpectedEof(self.pos))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, NbsError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn short(&mut self) -> Result<i16, NbsError> {
+        Ok(i16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn int(&mut self) -> Result<i32, NbsError> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, NbsError> {
+        let len = self.int()?.max(0) as usize;
+        Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+}
+
+struct Layer {
+    name: String,
+    instrument: u8,
+    /// `(tick, MIDI key)`
+    notes: Vec<(u32, u8)>,
+}
+
+/// A layer holds at most one note per tick, so each `Instruments` entry gets
+/// as many layers as its largest chord.
+fn layers(song: &Song) -> Vec<Layer> {
+    let mut layers = Vec::new();
+    for instrument in &song.tracks {
+        let kind = instrument.kind();
+        let mut notes: Vec<(u32, u8)> = instrument.track().iter().map(|n| (n.start_timing, n.key.as_int())).collect();
+        notes.sort();
+
+        let mut own: Vec<Vec<(u32, u8)>> = Vec::new();
+        let mut i = 0;
+        while i < notes.len() {
+            let chord = notes[i..].iter().take_while(|n| n.0 == notes[i].0).count();
+            for (voice, &note) in notes[i..i + chord].iter().enumerate() {
+                if own.len() <= voice {
+                    own.push(Vec::new());
+                }
+                own[voice].push(note);
+            }
+            i += chord;
+        }
+
+        for (voice, notes) in own.into_iter().enumerate() {
+            let name = if voice == 0 { kind.name().to_string() } else { format!("{} {}", kind.name(), voice + 1) };
+            layers.push(Layer { name, instrument: nbs_instrument(kind), notes });
+        }
+    }
+    layers
+}
+
+/// Encodes a song as a Note Block Studio file, one output tick per NBS tick.
+///
+/// NBS stores the length and tick jumps in 16 bits, so songs past 32767 ticks are an error.
+pub fn song_to_nbs(song: &Song, meta: &NbsMeta) -> Result<Vec<u8>, NbsError> {
+    let layers = layers(song);
+    let mut w = Writer(Vec::new());
+
+    w.short(0);
+    w.byte(NBS_VERSION);
+    w.byte(VANILLA_INSTRUMENTS);
+    w.short(i16::try_from(song.length()).map_err(|_| NbsError::TooLong(song.length()))?);
+    w.short(layers.len() as i16);
+    w.string(&meta.name);
+    w.string(&meta.author);
+    w.string(&meta.original_author);
+    w.string(&meta.description);
+    w.short((utils::TICKS_PER_SECOND * 100) as i16);
+    w.byte(0); // auto-saving
+    w.byte(10); // auto-saving duration
+    w.byte(4); // time signature
+    for _ in 0..5 {
+        w.int(0); // minutes spent, left clicks, right clicks, blocks added, blocks removed
+    }
+    w.string(""); // imported file name
+    w.byte(0); // loop
+    w.byte(0); // max loop count
+    w.short(0); // loop start tick
+
+    let mut notes: Vec<(u32, usize, u8, u8)> = layers.iter().enumerate()
+        .flat_map(|(l, layer)| layer.notes.iter().map(move |&(tick, key)| (tick, l, layer.instrument, key)))
+        .collect();
+    notes.sort();
+
+    let mut tick = -1i64;
+    let mut i = 0;
+    while i < notes.len() {
+        let current = notes[i].0;
+        w.short(i16::try_from(current as i64 - tick).map_err(|_| NbsError::TooLong(current))?);
+        tick = current as i64;
+
+        let mut layer = -1i64;
+        while i < notes.len() && notes[i].0 == current {
+            let (_, l, instrument, key) = notes[i];
+            w.short((l as i64 - layer) as i16);
+            layer = l as i64;
+            w.byte(instrument);
+            w.byte(nbs_key(key));
+            w.byte(100); // velocity
+            w.byte(100); // panning, centered
+            w.short(0); // fine pitch
+            i += 1;
+        }
+        w.short(0);
+    }
+    w.short(0);
+
+    for layer in &layers {
+        w.string(&layer.name);
+        w.byte(0); // lock
+        w.byte(100); // volume
+        w.byte(100); // stereo, centered
+    }
+    w.byte(0); // custom instruments
+
+    Ok(w.0)
+}
+
+pub fn write_nbs(song: &Song, path: &Path, meta: &NbsMeta) -> Result<(), Box<dyn std::error::Error>> {
+    std::fs::write(path, song_to_nbs(song, meta)?)?;
+    Ok(())
+}
+
+/// Parses a Note Block Studio file, both the classic format and the versioned one.
+///
+/// Notes are grouped into one `Instruments` per layer and instrument, and NBS ticks
+/// at the song's tempo are converted to output ticks. Fine pitch is rounded to the nearest key.
+pub fn parse_nbs(data: &[u8]) -> Result<(Song, NbsMeta), NbsError> {
+    let mut r = Reader { data, pos: 0 };
+
+    let first = r.short()?;
+    let (version, length) = if first == 0 {
+        let version = r.byte()?;
+        r.byte()?; // vanilla instrument count
+        let length = if version >= 3 { r.short()? } else { 0 };
+        (version, length)
+    } else {
+        (0, first)
+    };
+    r.short()?; // layer count
+    let meta = NbsMeta {
+        name: r.string()?,
+        author: r.string()?,
+        original_author: r.string()?,
+        description: r.string()?,
+    };
+    let tempo = r.short()?;
+    if tempo <= 0 {
+        return Err(NbsError::InvalidTempo(tempo));
+    }
+    r.take(3)?; // auto-saving, auto-saving duration, time signature
+    r.take(20)?; // statistics
+    r.string()?; // imported file name
+    if version >= 4 {
+        r.take(4)?; // loop, max loop count, loop start tick
+    }
+
+    let to_tick = |nbs_tick: i64| ((nbs_tick * (utils::TICKS_PER_SECOND * 100) as i64) as f64 / tempo as f64).round() as u32;
+
+    // (layer, instrument id) in order of first appearance
+    let mut groups: Vec<((i64, u8), Track)> = Vec::new();
+    let mut tick = -1i64;
+    loop {
+        let jump = r.short()?;
+        if jump == 0 {
+            break;
+        }
+        tick += jump as i64;
+
+        let mut layer = -1i64;
+        loop {
+            let jump = r.short()?;
+            if jump == 0 {
+                break;
+            }
+            layer += jump as i64;
+
+            let instrument = r.byte()?;
+            let mut key = r.byte()? as i32 + KEY_OFFSET as i32;
+            if version >= 4 {
+                r.take(2)?; // velocity, panning
+                key += (r.short()? as f64 / 100.0).round() as i32;
+            }
+
+            let note = Note::new(u7::from(key.clamp(0, 127) as u8), to_tick(tick));
+            match groups.iter_mut().find(|(g, _)| *g == (layer, instrument)) {
+                Some((_, track)) => track.push(note),
+                None => groups.push(((layer, instrument), Track(vec![note]))),
+            }
+        }
+    }
+
+    groups.sort_by_key(|((layer, _), _)| *layer);
+    let mut song = Song::new();
+    for ((_, instrument), track) in groups {
+        song.add_track(Instruments::new(instrument_kind(instrument), track));
+    }
+    song.end = to_tick(length as i64);
+
+    Ok((song, meta))
+}
+
+pub fn read_nbs(path: &str) -> Result<(Song, NbsMeta), Box<dyn std::error::Error>> {
+    let data = std::fs::read(path)?;
+    Ok(parse_nbs(&data)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nbs_key() {
+        assert_eq!(nbs_key(54), 33);
+        assert_eq!(nbs_key(78), 57);
+        assert_eq!(nbs_key(0), 0);
+        assert_eq!(nbs_key(127), 87);
+    }
+
+    #[test]
+    fn test_layers_split_chords() {
+        let song = Song::from_text("GK@G2G").unwrap();
+        let layers = layers(&song);
+        let names: Vec<&str> = layers.iter().map(|l| l.name.as_str()).collect();
+        assert_eq!(names, vec!["pling", "pling 2", "flute"]);
+        assert_eq!(layers[0].notes, vec![(0, 60), (4, 60)]);
+        assert_eq!(layers[1].notes, vec![(0, 64)]);
+        assert_eq!(layers[2].instrument, 6);
+    }
+
+    #[test]
+    fn test_song_to_nbs_header() {
+        let song = Song::from_text("G2=G").unwrap();
+        let meta = NbsMeta { name: "test".to_string(), author: "me".to_string(), ..Default::default() };
+        let data = song_to_nbs(&song, &meta).unwrap();
+
+        assert_eq!(&data[0..4], &[0, 0, NBS_VERSION, VANILLA_INSTRUMENTS]);
+        assert_eq!(&data[4..6], &4i16.to_le_bytes());
+        assert_eq!(&data[6..8], &2i16.to_le_bytes());
+        assert_eq!(&data[8..16], &[4, 0, 0, 0, b't', b'e', b's', b't']);
+        assert_eq!(*data.last().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let song = Song::from_text("GK@G2G1.=G3").unwrap();
+        let meta = NbsMeta { name: "test".to_string(), ..Default::default() };
+        let (parsed, parsed_meta) = parse_nbs(&song_to_nbs(&song, &meta).unwrap()).unwrap();
+
+        assert_eq!(parsed_meta, meta);
+        assert_eq!(parsed.tracks.len(), 4);
+        assert_eq!(parsed.end, 13);
+        assert_eq!(parsed.to_text(false).unwrap(), "GK@G2G1.=G3");
+    }
+
+    #[test]
+    fn test_song_too_long() {
+        let song = |tick: u32| {
+            let mut track = Track::new();
+            track.push(Note::new(u7::from(60), tick));
+            let mut song = Song::new();
+            song.add_track(Instruments::new(InstrumentKind::Harp, track));
+            song
+        };
+        assert!(song_to_nbs(&song(32766), &NbsMeta::default()).is_ok());
+        // the first jump is one more than the tick
+        assert_eq!(song_to_nbs(&song(32767), &NbsMeta::default()), Err(NbsError::TooLong(32767)));
+        assert_eq!(song_to_nbs(&song(40000), &NbsMeta::default()), Err(NbsError::TooLong(40000)));
+    }
+
+    #[test]
+    fn test_parse_classic() {
+        let mut w = Writer(Vec::new());
+        w.short(8); // length
+        w.short(1); // layers
+        for s in ["old", "", "", ""] {
+            w.string(s);
+        }
+        w.short(1000); // 10 ticks per second
+        w.0.extend_from_slice(&[0; 23]);
+        w.string("");
+        w.short(1); // tick 0
+        w.short(1); // layer 0
+        w.byte(6);
+        w.byte(39);
+        w.short(0);
+        w.short(4); // tick 4
+        w.short(1);
+        w.byte(0);
+        w.byte(45);
+        w.short(0);
+        w.short(0);
+
+        let (song, meta) = parse_nbs(&w.0).unwrap();
+        assert_eq!(meta.name, "old");
+        assert_eq!(song.end, 16);
+        assert_eq!(song.tracks[0].kind(), InstrumentKind::Flute);
+        assert_eq!(song.to_text(false).unwrap(), "@G4;M4");
+        assert_eq!(parse_nbs(&w.0[..10]).unwrap_err(), NbsError::UnexpectedEof(8));
+    }
+}
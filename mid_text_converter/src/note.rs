@@ -18,12 +18,17 @@ impl Note {
         Self { key, start_timing }
     }
 
+    /// Moves a key by octaves into the playable range 54..=78.
+    pub fn fold_key(key: u8) -> u8 {
+        let mut k = key;
+        while k < 54 { k += 12; }
+        while k > 78 { k -= 12; }
+        k
+    }
+
     pub fn key_to_char(key: u8, relative_move: bool) -> Result<char, NoteError> {
         let k = if relative_move {
-            let mut k = key;
-            while k < 54 { k += 12; }
-            while k > 78 { k -= 12; }
-            k
+            Self::fold_key(key)
         } else if !(54..=78).contains(&key) {
             return Err(NoteError::InvalidKey(key));
         } else {
@@ -37,6 +42,12 @@ impl Note {
     pub fn to_char(&self, relative_move: bool) -> Result<char, NoteError> {
         Self::key_to_char(self.key.as_int(), relative_move)
     }
+
+    /// Returns the scientific pitch name of a MIDI key, e.g. `C4` for 60.
+    pub fn key_name(key: u8) -> String {
+        const NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+        format!("{}{}", NAMES[(key % 12) as usize], key as i32 / 12 - 1)
+    }
 }
 
 #[cfg(test)]
@@ -58,6 +69,14 @@ mod tests {
         assert_eq!(Note::key_to_char(1, false), Err(NoteError::InvalidKey(1)));
     }
     
+    #[test]
+    fn test_key_name() {
+        assert_eq!(Note::key_name(60), "C4");
+        assert_eq!(Note::key_name(61), "C#4");
+        assert_eq!(Note::key_name(0), "C-1");
+        assert_eq!(Note::key_name(127), "G9");
+    }
+
     #[test]
     fn test_to_char() {
         let note = Note::new(u7::from(60), 0);
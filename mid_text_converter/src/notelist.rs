@@ -0,0 +1,387 @@
+use crate::instruments::{InstrumentKind, Instruments, Track};
+use crate::note::Note;
+use crate::smf;
+use crate::song::Song;
+use crate::utils;
+use midly::num::u7;
+use midly::{MetaMessage, MidiMessage, TrackEventKind};
+use serde_json::{json, Value};
+use std::fmt::Write;
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum NoteListError {
+    #[error("Missing column: {0}")]
+    MissingColumn(&'static str),
+    #[error("Invalid value on line {0}: {1}")]
+    InvalidLine(usize, String),
+    #[error("Unknown instrument: {0}")]
+    UnknownInstrument(String),
+    #[error("Invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// Velocity written for every note, since songs do not store one.
+pub const VELOCITY: u8 = 100;
+
+const FLAT_COLUMNS: [&str; 5] = ["tick", "instrument", "key", "duration", "velocity"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoteListFormat {
+    /// The event list of `midicsv`, one line per MIDI event.
+    MidiCsv,
+    /// One `tick,instrument,key,duration,velocity` line per note.
+    Csv,
+    /// An array of objects with the same fields as `Csv`.
+    Json,
+}
+
+impl NoteListFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "midicsv" => Some(Self::MidiCsv),
+            "csv" => Some(Self::Csv),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+
+    /// Format matching the extension of `path`; `.csv` files are the flat format.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_lowercase().as_str() {
+            "csv" => Some(Self::Csv),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+/// One note of the flat formats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoteEvent {
+    pub tick: u32,
+    pub kind: InstrumentKind,
+    pub key: u8,
+    pub duration: u32,
+    pub velocity: u8,
+}
+
+/// Lists the notes of the song in tick order.
+///
+/// Without `note_length`, a note lasts until the next note of its track,
+/// and the last one until the end of the song, which is 0 ticks if the song ends on it.
+pub fn song_to_events(song: &Song, note_length: Option<u32>) -> Vec<NoteEvent> {
+    let mut events = Vec::new();
+    for instrument in &song.tracks {
+        let mut ticks: Vec<u32> = instrument.track().iter().map(|n| n.start_timing).collect();
+        ticks.sort();
+        ticks.dedup();
+        for note in instrument.track().iter() {
+            let duration = note_length.map(|l| l.max(1)).unwrap_or_else(|| {
+                let next = ticks.iter().find(|t| **t > note.start_timing).copied().unwrap_or(song.length());
+                next.saturating_sub(note.start_timing)
+            });
+            events.push(NoteEvent {
+                tick: note.start_timing,
+                kind: instrument.kind(),
+                key: note.key.as_int(),
+                duration,
+                velocity: VELOCITY,
+            });
+        }
+    }
+    events.sort_by_key(|e| e.tick);
+    events
+}
+
+/// Builds a song with one track per instrument. The song ends when the last note does.
+pub fn events_to_song(events: &[NoteEvent]) -> Song {
+    let mut song = Song::new();
+    for kind in InstrumentKind::ALL {
+        let mut track = Track::new();
+        for event in events.iter().filter(|e| e.kind == kind && e.velocity > 0) {
+            track.push(Note::new(u7::from(event.key.min(127)), event.tick));
+        }
+        if !track.is_empty() {
+            track.sort_by_key(|n| n.start_timing);
+            song.add_track(Instruments::new(kind, track));
+        }
+    }
+    song.end = events.iter().map(|e| e.tick + e.duration).max().unwrap_or(0);
+    song
+}
+
+pub fn song_to_csv(song: &Song, note_length: Option<u32>) -> String {
+    let mut csv = FLAT_COLUMNS.join(",") + "\n";
+    for e in song_to_events(song, note_length) {
+        writeln!(csv, "{},{},{},{},{}", e.tick, e.kind.name(), e.key, e.duration, e.velocity).unwrap();
+    }
+    csv
+}
+
+pub fn song_to_json(song: &Song, note_length: Option<u32>) -> String {
+    let notes: Vec<Value> = song_to_events(song, note_length).iter()
+        .map(|e| json!({
+            "tick": e.tick,
+            "instrument": e.kind.name(),
+            "key": e.key,
+            "duration": e.duration,
+            "velocity": e.velocity,
+        }))
+        .collect();
+    serde_json::to_string_pretty(&notes).unwrap() + "\n"
+}
+
+/// Writes the song in the `midicsv` format, with the same events as `smf::song_to_smf`
+/// except that drums keep their key, so `parse_midicsv` reads back the same song.
+pub fn song_to_midicsv(song: &Song, note_length: Option<u32>) -> String {
+    let smf = smf::song_to_smf_with(song, note_length, false);
+    let mut csv = format!("0, 0, Header, 1, {}, {}\n", smf.tracks.len(), smf::PPQ);
+    for (i, track) in smf.tracks.iter().enumerate() {
+        let number = i + 1;
+        let mut time = 0;
+        writeln!(csv, "{}, 0, Start_track", number).unwrap();
+        for event in track {
+            time += event.delta.as_int();
+            let line = match event.kind {
+                TrackEventKind::Meta(MetaMessage::TrackName(name)) => {
+                    format!("Title_t, \"{}\"", String::from_utf8_lossy(name).replace('"', "\\\""))
+                }
+                TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => format!("Tempo, {}", tempo.as_int()),
+                TrackEventKind::Meta(MetaMessage::EndOfTrack) => "End_track".to_string(),
+                TrackEventKind::Midi { channel, message } => match message {
+                    MidiMessage::NoteOn { key, vel } => format!("Note_on_c, {}, {}, {}", channel, key, vel),
+                    MidiMessage::NoteOff { key, vel } => format!("Note_off_c, {}, {}, {}", channel, key, vel),
+                    MidiMessage::ProgramChange { program } => format!("Program_c, {}, {}", channel, program),
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            writeln!(csv, "{}, {}, {}", number, time, line).unwrap();
+        }
+    }
+    csv.push_str("0, 0, End_of_file\n");
+    csv
+}
+
+/// Splits a CSV line at commas outside of double quotes, trimming each field.
+fn fields(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if quoted => field.extend(chars.next()),
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field).trim().to_string()),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field.trim().to_string());
+    fields
+}
+
+fn parse_kind(name: &str) -> Result<InstrumentKind, NoteListError> {
+    InstrumentKind::from_name(&name.to_lowercase()).ok_or_else(|| NoteListError::UnknownInstrument(name.to_string()))
+}
+
+/// Reads the flat CSV format. The header line is optional; with it the columns may come in any
+/// order and only `tick`, `instrument` and `key` are required.
+pub fn parse_csv(text: &str) -> Result<Vec<NoteEvent>, NoteListError> {
+    let mut lines = text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()).peekable();
+    let mut columns: Vec<String> = FLAT_COLUMNS.iter().map(|c| c.to_string()).collect();
+    if let Some((_, first)) = lines.peek()
+        && fields(first).first().is_some_and(|f| f.parse::<u32>().is_err())
+    {
+        columns = fields(first).iter().map(|f| f.to_lowercase()).collect();
+        lines.next();
+    }
+    let column = |name: &'static str| columns.iter().position(|c| c == name);
+    let tick = column("tick").ok_or(NoteListError::MissingColumn("tick"))?;
+    let instrument = column("instrument").ok_or(NoteListError::MissingColumn("instrument"))?;
+    let key = column("key").ok_or(NoteListError::MissingColumn("key"))?;
+    let (duration, velocity) = (column("duration"), column("velocity"));
+
+    let mut events = Vec::new();
+    for (i, line) in lines {
+        let values = fields(line);
+        let invalid = || NoteListError::InvalidLine(i + 1, line.to_string());
+        let number = |index: usize| values.get(index).and_then(|v| v.parse::<u32>().ok());
+        events.push(NoteEvent {
+            tick: number(tick).ok_or_else(invalid)?,
+            kind: parse_kind(values.get(instrument).ok_or_else(invalid)?)?,
+            key: number(key).filter(|k| *k < 128).ok_or_else(invalid)? as u8,
+            duration: duration.and_then(number).unwrap_or(0),
+            velocity: velocity.and_then(number).unwrap_or(VELOCITY as u32).min(127) as u8,
+        });
+    }
+    Ok(events)
+}
+
+/// Reads the flat JSON format: an array of notes, or an object with a `notes` array.
+pub fn parse_json(text: &str) -> Result<Vec<NoteEvent>, NoteListError> {
+    let value: Value = serde_json::from_str(text)?;
+    let notes = value.get("notes").unwrap_or(&value).as_array().cloned().unwrap_or_default();
+    let mut events = Vec::new();
+    for (i, note) in notes.iter().enumerate() {
+        let invalid = || NoteListError::InvalidLine(i + 1, note.to_string());
+        let number = |name: &str| note.get(name).and_then(Value::as_u64).map(|n| n as u32);
+        events.push(NoteEvent {
+            tick: number("tick").ok_or_else(invalid)?,
+            kind: parse_kind(note.get("instrument").and_then(Value::as_str).ok_or_else(invalid)?)?,
+            key: number("key").filter(|k| *k < 128).ok_or_else(invalid)? as u8,
+            duration: number("duration").unwrap_or(0),
+            velocity: number("velocity").unwrap_or(VELOCITY as u32).min(127) as u8,
+        });
+    }
+    Ok(events)
+}
+
+/// Instrument of a `midicsv` track: its title if it names an instrument, the General MIDI
+/// drum sound on channel 10, and pling otherwise.
+fn midicsv_kind(title: Option<&str>, channel: u8, key: u8) -> InstrumentKind {
+    if let Some(kind) = title.and_then(|t| InstrumentKind::from_name(&t.to_lowercase())) {
+        return kind;
+    }
+    if channel == 9 {
+        return match key {
+            35 | 36 => InstrumentKind::BassDrum,
+            37..=40 => InstrumentKind::Snare,
+            _ => InstrumentKind::Hat,
+        };
+    }
+    InstrumentKind::Pling
+}
+
+/// Reads `midicsv` output. Times are converted like `mid_to_track`, and note ons with
+/// velocity 0 count as note offs. Drums on channel 10 keep their General MIDI key moved into range.
+pub fn parse_midicsv(text: &str) -> Result<Vec<NoteEvent>, NoteListError> {
+    struct Pending {
+        title: Option<String>,
+        notes: Vec<(u32, u8, u8, u8)>,
+    }
+    let mut tracks: Vec<(u32, Pending)> = Vec::new();
+    for (i, line) in text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty() && !l.starts_with(';')) {
+        let values = fields(line);
+        let invalid = || NoteListError::InvalidLine(i + 1, line.to_string());
+        let number = |index: usize| values.get(index).and_then(|v| v.parse::<u32>().ok()).ok_or_else(invalid);
+        let (track, time) = (number(0)?, number(1)?);
+        let index = match tracks.iter().position(|(t, _)| *t == track) {
+            Some(index) => index,
+            None => {
+                tracks.push((track, Pending { title: None, notes: Vec::new() }));
+                tracks.len() - 1
+            }
+        };
+        let pending = &mut tracks[index].1;
+        match values.get(2).map(|v| v.as_str()) {
+            Some("Title_t") => pending.title = values.get(3).cloned(),
+            Some("Note_on_c") => {
+                let (channel, key, velocity) = (number(3)?, number(4)?, number(5)?);
+                if velocity > 0 {
+                    pending.notes.push((time / utils::MIDI_TICKS_PER_TICK, channel as u8, key.min(127) as u8, velocity.min(127) as u8));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut events = Vec::new();
+    for (_, pending) in tracks {
+        for (tick, channel, key, velocity) in pending.notes {
+            let kind = midicsv_kind(pending.title.as_deref(), channel, key);
+            let key = if kind.is_drum() && channel == 9 { Note::fold_key(key) } else { key };
+            events.push(NoteEvent { tick, kind, key, duration: 0, velocity });
+        }
+    }
+    events.sort_by_key(|e| e.tick);
+    Ok(events)
+}
+
+/// Reads any of the formats, telling them apart by their contents.
+pub fn parse_note_list(text: &str) -> Result<Vec<NoteEvent>, NoteListError> {
+    let trimmed = text.trim_start();
+    if trimmed.starts_with('[') || trimmed.starts_with('{') {
+        return parse_json(text);
+    }
+    let first = trimmed.lines().next().unwrap_or("");
+    if fields(first).get(2).is_some_and(|f| f == "Header") {
+        parse_midicsv(text)
+    } else {
+        parse_csv(text)
+    }
+}
+
+pub fn read_note_list(path: &Path) -> Result<Song, Box<dyn std::error::Error>> {
+    Ok(events_to_song(&parse_note_list(&std::fs::read_to_string(path)?)?))
+}
+
+/// Reads a note list like `mid_to_track`, with the notes of every instrument in one track.
+pub fn note_list_to_track(path: &str) -> Result<Track, Box<dyn std::error::Error>> {
+    let song = read_note_list(Path::new(path))?;
+    let mut track = Track::new();
+    for instrument in song.tracks {
+        track.merge(instrument.track().clone());
+    }
+    Ok(track)
+}
+
+pub fn write_note_list(song: &Song, path: &Path, format: NoteListFormat, note_length: Option<u32>) -> Result<(), Box<dyn std::error::Error>> {
+    let text = match format {
+        NoteListFormat::MidiCsv => song_to_midicsv(song, note_length),
+        NoteListFormat::Csv => song_to_csv(song, note_length),
+        NoteListFormat::Json => song_to_json(song, note_length),
+    };
+    std::fs::write(path, text)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csv_round_trip() {
+        let song = Song::from_text("GK2=I2").unwrap();
+        let csv = song_to_csv(&song, None);
+        assert_eq!(csv, "tick,instrument,key,duration,velocity\n0,pling,60,8,100\n0,pling,64,8,100\n4,bassdrum,62,4,100\n");
+        let parsed = events_to_song(&parse_note_list(&csv).unwrap());
+        assert_eq!(parsed.to_text(false).unwrap(), "GK2=I2");
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let song = Song::from_text("G.;I").unwrap();
+        let json = song_to_json(&song, Some(2));
+        let events = parse_note_list(&json).unwrap();
+        assert_eq!(events, song_to_events(&song, Some(2)));
+        assert_eq!(events[1], NoteEvent { tick: 1, kind: InstrumentKind::Harp, key: 62, duration: 2, velocity: 100 });
+    }
+
+    #[test]
+    fn test_parse_csv_columns() {
+        let events = parse_csv("key,tick,instrument\n60,4,Flute\n").unwrap();
+        assert_eq!(events, vec![NoteEvent { tick: 4, kind: InstrumentKind::Flute, key: 60, duration: 0, velocity: 100 }]);
+        assert!(matches!(parse_csv("0,kazoo,60"), Err(NoteListError::UnknownInstrument(_))));
+        assert!(matches!(parse_csv("tick,key\n0,60"), Err(NoteListError::MissingColumn("instrument"))));
+    }
+
+    #[test]
+    fn test_midicsv_round_trip() {
+        let song = Song::from_text("G2?K2=I").unwrap();
+        let csv = song_to_midicsv(&song, None);
+        assert!(csv.starts_with("0, 0, Header, 1, 4, 96\n1, 0, Start_track\n1, 0, Tempo, 400000\n"));
+        assert!(csv.contains("2, 0, Title_t, \"pling\"\n2, 0, Program_c, 0, 4\n2, 0, Note_on_c, 0, 60, 100\n"));
+        assert!(csv.ends_with("0, 0, End_of_file\n"));
+
+        assert!(csv.contains("3, 48, Note_on_c, 9, 64, 100\n"));
+        assert_eq!(events_to_song(&parse_midicsv(&csv).unwrap()), song);
+
+        // drums named by General MIDI key on channel 10 are moved into range
+        let events = parse_midicsv("1, 0, Note_on_c, 9, 36, 100\n1, 0, Note_on_c, 9, 42, 100\n").unwrap();
+        let kinds: Vec<(InstrumentKind, u8)> = events.iter().map(|e| (e.kind, e.key)).collect();
+        assert_eq!(kinds, vec![(InstrumentKind::BassDrum, 60), (InstrumentKind::Hat, 54)]);
+    }
+}
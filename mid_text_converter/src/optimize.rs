@@ -0,0 +1,125 @@
+use crate::utils;
+
+/// A string shortened by moving notes a few ticks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Nudged {
+    pub text: String,
+    /// Characters saved compared to the input.
+    pub saved: usize,
+    /// Largest number of ticks any note was moved.
+    pub max_shift: u32,
+    /// Number of ticks whose notes were moved.
+    pub moved: usize,
+}
+
+fn gap_cost(gap: u32) -> usize {
+    utils::tick_to_string(gap).len()
+}
+
+/// Shortens a string by moving each tick's notes by up to `max_nudge` ticks.
+///
+/// All notes on a tick move together and keep their order relative to the other ticks,
+/// and the silence after the last tick keeps its length. The offsets are chosen by dynamic
+/// programming over the cost of the rests between ticks, so the result is the shortest
+/// possible string under these rules.
+pub fn nudge(text: &str, max_nudge: u32) -> Nudged {
+    let (groups, end) = utils::split_ticks(text);
+    if groups.is_empty() {
+        return Nudged { text: text.to_string(), saved: 0, max_shift: 0, moved: 0 };
+    }
+
+    let k = max_nudge as i64;
+    let width = (2 * k + 1) as usize;
+    let tick_at = |i: usize, d: usize| groups[i].0 as i64 + d as i64 - k;
+
+    // cost[i][d]: shortest rests up to group i when it is moved by d - k ticks
+    let mut cost = vec![vec![usize::MAX; width]; groups.len()];
+    let mut from = vec![vec![0usize; width]; groups.len()];
+
+    for (d, c) in cost[0].iter_mut().enumerate() {
+        let tick = tick_at(0, d);
+        if tick >= 0 {
+            *c = gap_cost(tick as u32);
+        }
+    }
+    for i in 1..groups.len() {
+        for d in 0..width {
+            let tick = tick_at(i, d);
+            for p in 0..width {
+                let prev = tick_at(i - 1, p);
+                if cost[i - 1][p] == usize::MAX || prev >= tick {
+                    continue;
+                }
+                let c = cost[i - 1][p] + gap_cost((tick - prev) as u32);
+                if c < cost[i][d] || (c == cost[i][d] && p.abs_diff(width / 2) < from[i][d].abs_diff(width / 2)) {
+                    cost[i][d] = c;
+                    from[i][d] = p;
+                }
+            }
+        }
+    }
+
+    let last = groups.len() - 1;
+    let best = (0..width)
+        .filter(|&d| cost[last][d] != usize::MAX)
+        .min_by_key(|&d| (cost[last][d], d.abs_diff(width / 2)))
+        .unwrap_or(width / 2);
+
+    let mut shifts = vec![0usize; groups.len()];
+    shifts[last] = best;
+    for i in (1..groups.len()).rev() {
+        shifts[i - 1] = from[i][shifts[i]];
+    }
+
+    let mut result = String::with_capacity(text.len());
+    let mut prev = 0u32;
+    for (i, (_, chars)) in groups.iter().enumerate() {
+        let tick = tick_at(i, shifts[i]) as u32;
+        result.push_str(&utils::tick_to_string(tick - prev));
+        result.push_str(chars);
+        prev = tick;
+    }
+    result.push_str(&utils::tick_to_string(end - groups[last].0));
+
+    if result.len() >= text.len() {
+        return Nudged { text: text.to_string(), saved: 0, max_shift: 0, moved: 0 };
+    }
+    Nudged {
+        saved: text.len() - result.len(),
+        max_shift: shifts.iter().map(|&d| d.abs_diff(width / 2) as u32).max().unwrap_or(0),
+        moved: shifts.iter().filter(|&&d| d != width / 2).count(),
+        text: result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nudge_zero_keeps_text() {
+        let result = nudge("G3I.K", 0);
+        assert_eq!(result.text, "G3I.K");
+        assert_eq!(result.saved, 0);
+    }
+
+    #[test]
+    fn test_nudge_removes_single_ticks() {
+        let result = nudge("G1.I1.K", 1);
+        assert_eq!(result.text, "G1I2K");
+        assert_eq!(result.saved, 2);
+        assert_eq!(result.max_shift, 1);
+        assert_eq!(result.moved, 1);
+
+        let result = nudge("G3.I", 2);
+        assert_eq!(result.text, "G3I");
+        assert_eq!(result.saved, 1);
+    }
+
+    #[test]
+    fn test_nudge_keeps_chords_together() {
+        let result = nudge("G@G1.I@I2", 1);
+        assert_eq!(result.text, "G@G1I@I2");
+        assert_eq!(result.saved, 1);
+    }
+}
@@ -0,0 +1,226 @@
+use crate::abc;
+use crate::instruments::{InstrumentKind, Instruments};
+use crate::note::Note;
+use crate::render;
+use crate::song::Song;
+use std::fmt::Write;
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+/// Space on the left for key names.
+const MARGIN: f32 = 32.0;
+/// Space on the top for bar numbers and the legend.
+const HEADER: f32 = 28.0;
+/// Longest a note is drawn, in ticks, when the next note of its track is further away.
+const MAX_NOTE_TICKS: u32 = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PianoRollOptions {
+    /// Ticks in one bar. Estimated from the notes if `None`.
+    pub bar: Option<u32>,
+    /// Width of one tick in pixels.
+    pub tick_width: f32,
+    /// Height of one key in pixels.
+    pub key_height: f32,
+}
+
+impl Default for PianoRollOptions {
+    fn default() -> Self {
+        Self { bar: None, tick_width: 6.0, key_height: 6.0 }
+    }
+}
+
+/// Colour an instrument is drawn in.
+pub fn colour(kind: InstrumentKind) -> &'static str {
+    match kind {
+        InstrumentKind::Pling => "#4e79a7",
+        InstrumentKind::Hat => "#bab0ac",
+        InstrumentKind::Snare => "#9c755f",
+        InstrumentKind::BassDrum => "#59595e",
+        InstrumentKind::Bass => "#e15759",
+        InstrumentKind::Bell => "#edc948",
+        InstrumentKind::Chime => "#76b7b2",
+        InstrumentKind::Flute => "#59a14f",
+        InstrumentKind::Guitar => "#f28e2b",
+        InstrumentKind::Harp => "#b07aa1",
+        InstrumentKind::Xylophone => "#ff9da7",
+    }
+}
+
+/// Keys an instrument plays without moving them. Pling reaches two octaves further each way with `+` and `-`.
+pub fn playable_range(kind: InstrumentKind) -> RangeInclusive<u8> {
+    match kind {
+        InstrumentKind::Pling => 30..=102,
+        _ => 54..=78,
+    }
+}
+
+/// Key a note plays at once `to_text` with `relative_move` has moved it into range.
+pub fn played_key(kind: InstrumentKind, key: u8) -> u8 {
+    match kind {
+        InstrumentKind::Pling if key >= 78 => Note::fold_key(key - 24) + 24,
+        InstrumentKind::Pling if key <= 54 => Note::fold_key(key + 24) - 24,
+        _ => Note::fold_key(key),
+    }
+}
+
+/// Pitch the game sounds a key at, in MIDI keys.
+pub fn sounding(kind: InstrumentKind, key: u8) -> i32 {
+    key as i32 + render::octave_offset(kind) * 12
+}
+
+/// Ticks in one bar: `bar` if given, otherwise the bar `abc::estimate_meter` finds.
+pub fn bar_ticks(song: &Song, bar: Option<u32>) -> u32 {
+    bar.filter(|b| *b > 0).unwrap_or_else(|| {
+        let meter = abc::estimate_meter(song, None);
+        meter.unit * meter.bar
+    })
+}
+
+/// Ticks to the next note of each note in `instrument`, at most `MAX_NOTE_TICKS`.
+fn note_lengths(instrument: &Instruments, end: u32) -> Vec<u32> {
+    let track = instrument.track();
+    (0..track.len())
+        .map(|i| {
+            let next = track.get(i + 1).map_or(end.max(track[i].start_timing + 1), |n| n.start_timing);
+            next.saturating_sub(track[i].start_timing).clamp(1, MAX_NOTE_TICKS)
+        })
+        .collect()
+}
+
+/// Draws the song as a piano roll.
+///
+/// Pitched instruments are placed by the pitch the game plays them at, over a shaded band of
+/// the pitches each can reach, and drums get one row each below them. Notes out of range are
+/// drawn where they will play with a red outline, joined by a dashed line to where they were.
+pub fn song_to_svg(song: &Song, options: &PianoRollOptions) -> String {
+    let length = song.length().max(1);
+    let tw = options.tick_width;
+    let kh = options.key_height;
+    let kinds: Vec<InstrumentKind> = InstrumentKind::ALL.into_iter()
+        .filter(|k| song.tracks.iter().any(|t| t.kind() == *k && !t.track().is_empty()))
+        .collect();
+    let drums: Vec<InstrumentKind> = kinds.iter().copied().filter(|k| k.is_drum()).collect();
+
+    let mut low = i32::MAX;
+    let mut high = i32::MIN;
+    for kind in kinds.iter().filter(|k| !k.is_drum()) {
+        let range = playable_range(*kind);
+        low = low.min(sounding(*kind, *range.start()));
+        high = high.max(sounding(*kind, *range.end()));
+    }
+    for instrument in song.tracks.iter().filter(|t| !t.kind().is_drum()) {
+        for note in instrument.track().iter() {
+            let key = sounding(instrument.kind(), note.key.as_int());
+            low = low.min(key);
+            high = high.max(key);
+        }
+    }
+    if low > high {
+        (low, high) = (60, 60);
+    }
+    let row = |key: i32| HEADER + (high - key) as f32 * kh;
+    let drum_top = row(low - 1) + kh;
+    let width = MARGIN + length as f32 * tw;
+    let height = drum_top + drums.len() as f32 * kh * 2.0;
+
+    let mut svg = String::new();
+    let _ = writeln!(svg, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}" font-family="sans-serif" font-size="9">"#);
+    let _ = writeln!(svg, r#"<rect width="{width}" height="{height}" fill="white"/>"#);
+
+    for kind in kinds.iter().copied().filter(|k| !k.is_drum()) {
+        let range = playable_range(kind);
+        let (top, bottom) = (row(sounding(kind, *range.end())), row(sounding(kind, *range.start())) + kh);
+        let _ = writeln!(svg, r#"<rect class="range" x="{MARGIN}" y="{top}" width="{}" height="{}" fill="{}" fill-opacity="0.08"/>"#, width - MARGIN, bottom - top, colour(kind));
+    }
+    for key in low..=high {
+        if key.rem_euclid(12) == 0 {
+            let y = row(key) + kh;
+            let _ = writeln!(svg, r##"<line x1="{MARGIN}" y1="{y}" x2="{width}" y2="{y}" stroke="#ddd"/><text x="2" y="{y}">{}</text>"##, Note::key_name(key as u8));
+        }
+    }
+    for (i, kind) in drums.iter().enumerate() {
+        let y = drum_top + i as f32 * kh * 2.0;
+        let _ = writeln!(svg, r##"<line x1="{MARGIN}" y1="{y}" x2="{width}" y2="{y}" stroke="#ddd"/><text x="2" y="{}">{}</text>"##, y + kh * 1.5, kind.name());
+    }
+
+    let bar = bar_ticks(song, options.bar);
+    if bar > 0 {
+        for (number, tick) in (0..=length).step_by(bar as usize).enumerate() {
+            let x = MARGIN + tick as f32 * tw;
+            let _ = writeln!(svg, r##"<line class="bar" x1="{x}" y1="{}" x2="{x}" y2="{height}" stroke="#999"/><text x="{}" y="{}">{}</text>"##, HEADER - 6.0, x + 2.0, HEADER - 8.0, number + 1);
+        }
+    }
+
+    let mut legend_x = width;
+    for kind in kinds.iter().copied().rev() {
+        legend_x -= kind.name().len() as f32 * 5.5 + 16.0;
+        let _ = writeln!(svg, r#"<rect x="{legend_x}" y="2" width="8" height="8" fill="{}"/><text x="{}" y="10">{}</text>"#, colour(kind), legend_x + 10.0, kind.name());
+    }
+
+    for instrument in &song.tracks {
+        let kind = instrument.kind();
+        let drum_row = drums.iter().position(|k| *k == kind);
+        for (note, ticks) in instrument.track().iter().zip(note_lengths(instrument, song.length())) {
+            let x = MARGIN + note.start_timing as f32 * tw;
+            let w = ticks as f32 * tw - 1.0;
+            let key = note.key.as_int();
+            if let Some(i) = drum_row {
+                let y = drum_top + i as f32 * kh * 2.0 + kh * 0.5;
+                let _ = writeln!(svg, r#"<rect class="note" x="{x}" y="{y}" width="{w}" height="{kh}" fill="{}"><title>{} tick {}</title></rect>"#, colour(kind), kind.name(), note.start_timing);
+                continue;
+            }
+            let played = played_key(kind, key);
+            let y = row(sounding(kind, played));
+            let title = format!("{} {} tick {}", kind.name(), Note::key_name(played), note.start_timing);
+            if played == key {
+                let _ = writeln!(svg, r#"<rect class="note" x="{x}" y="{y}" width="{w}" height="{kh}" fill="{}"><title>{title}</title></rect>"#, colour(kind));
+            } else {
+                let from = row(sounding(kind, key));
+                let _ = writeln!(svg, r##"<line x1="{}" y1="{}" x2="{}" y2="{}" stroke="#d00" stroke-dasharray="2,2"/>"##, x + 1.0, from + kh / 2.0, x + 1.0, y + kh / 2.0);
+                let _ = writeln!(svg, r##"<rect x="{x}" y="{from}" width="{w}" height="{kh}" fill="none" stroke="#d00" stroke-opacity="0.5"/>"##);
+                let _ = writeln!(svg, r##"<rect class="note folded" x="{x}" y="{y}" width="{w}" height="{kh}" fill="{}" stroke="#d00" stroke-width="1.5"><title>{title} (moved from {})</title></rect>"##, colour(kind), Note::key_name(key));
+            }
+        }
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+pub fn write_svg(song: &Song, path: &Path, options: &PianoRollOptions) -> Result<(), Box<dyn std::error::Error>> {
+    std::fs::write(path, song_to_svg(song, options))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruments::Track;
+    use midly::num::u7;
+
+    #[test]
+    fn test_played_key() {
+        assert_eq!(played_key(InstrumentKind::Harp, 60), 60);
+        assert_eq!(played_key(InstrumentKind::Harp, 90), 78);
+        assert_eq!(played_key(InstrumentKind::Pling, 90), 90);
+        assert_eq!(played_key(InstrumentKind::Pling, 110), 98);
+        assert_eq!(played_key(InstrumentKind::Pling, 20), 32);
+    }
+
+    #[test]
+    fn test_song_to_svg() {
+        let mut song = Song::from_text("G2!G2@I2M").unwrap();
+        let mut track = Track::new();
+        track.push(Note::new(u7::from(40), 4));
+        song.add_track(Instruments::new(InstrumentKind::Guitar, track));
+        let svg = song_to_svg(&song, &PianoRollOptions { bar: Some(4), ..PianoRollOptions::default() });
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.ends_with("</svg>\n"));
+        assert_eq!(svg.matches(r#"class="note""#).count(), 4);
+        assert_eq!(svg.matches(r#"class="note folded""#).count(), 1);
+        assert!(svg.contains("guitar E4 tick 4 (moved from E2)"));
+        assert_eq!(svg.matches(r#"class="range""#).count(), 3);
+        assert_eq!(svg.matches(r#"class="bar""#).count(), 4);
+        assert!(svg.contains(">hat</text>"));
+    }
+}
@@ -0,0 +1,363 @@
+use crate::chunk::Chunk;
+use crate::instruments::InstrumentKind;
+use crate::pianoroll;
+use crate::song::Song;
+use serde_json::json;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PlayerOptions {
+    pub title: String,
+    /// Splits the string into chunks of at most this many characters, like `Song::to_chunks`.
+    pub max_len: Option<usize>,
+    /// Ticks in one bar, for the bar lines and chunk cuts. Estimated from the notes if `None`.
+    pub bar: Option<u32>,
+}
+
+/// WebAudio oscillator type and decay in seconds of each instrument.
+/// `noise` plays filtered noise and `kick` a falling sine.
+fn voice(kind: InstrumentKind) -> (&'static str, f32) {
+    match kind {
+        InstrumentKind::Pling => ("triangle", 1.2),
+        InstrumentKind::Harp => ("triangle", 1.5),
+        InstrumentKind::Guitar => ("sawtooth", 0.9),
+        InstrumentKind::Bass => ("sine", 0.8),
+        InstrumentKind::Bell => ("sine", 2.0),
+        InstrumentKind::Chime => ("sine", 2.5),
+        InstrumentKind::Flute => ("sine", 0.6),
+        InstrumentKind::Xylophone => ("square", 0.4),
+        InstrumentKind::Hat => ("noise", 0.06),
+        InstrumentKind::Snare => ("noise", 0.2),
+        InstrumentKind::BassDrum => ("kick", 0.3),
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Note data the player reads: instruments, notes as `[tick, instrument, key]` sorted by tick,
+/// the key range of the roll and the chunked strings.
+///
+/// Keys of pitched instruments are the pitch the game sounds, after moving them into range.
+/// Drum notes carry their lane below the pitched keys instead.
+fn player_data(song: &Song, options: &PlayerOptions) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    let kinds: Vec<InstrumentKind> = InstrumentKind::ALL.into_iter()
+        .filter(|k| song.tracks.iter().any(|t| t.kind() == *k && !t.track().is_empty()))
+        .collect();
+    let drums: Vec<InstrumentKind> = kinds.iter().copied().filter(|k| k.is_drum()).collect();
+
+    let mut notes = Vec::new();
+    for instrument in &song.tracks {
+        let kind = instrument.kind();
+        let index = kinds.iter().position(|k| *k == kind).unwrap_or(0);
+        for note in instrument.track().iter() {
+            let key = match drums.iter().position(|k| *k == kind) {
+                Some(lane) => lane as i32,
+                None => pianoroll::sounding(kind, pianoroll::played_key(kind, note.key.as_int())),
+            };
+            notes.push((note.start_timing, index, key));
+        }
+    }
+    notes.sort();
+    let pitched = notes.iter().filter(|(_, i, _)| !kinds[*i].is_drum()).map(|(_, _, k)| *k);
+    let (low, high) = (pitched.clone().min().unwrap_or(60) - 2, pitched.max().unwrap_or(72) + 2);
+
+    let chunks = match options.max_len {
+        Some(max_len) => song.to_chunks(true, max_len, options.bar)?,
+        None => vec![Chunk { start: 0, text: song.to_text(true)? }],
+    };
+    let instruments: Vec<serde_json::Value> = kinds.iter()
+        .map(|kind| {
+            let (wave, decay) = voice(*kind);
+            json!({ "name": kind.name(), "colour": pianoroll::colour(*kind), "wave": wave, "decay": decay, "drum": kind.is_drum() })
+        })
+        .collect();
+    Ok(json!({
+        "title": options.title,
+        "length": song.length(),
+        "bar": pianoroll::bar_ticks(song, options.bar),
+        "low": low,
+        "high": high,
+        "drums": drums.len(),
+        "instruments": instruments,
+        "notes": notes.iter().map(|(tick, index, key)| json!([tick, index, key])).collect::<Vec<_>>(),
+        "chunks": chunks.iter().map(|c| json!({ "start": c.start, "text": c.text })).collect::<Vec<_>>(),
+    }))
+}
+
+/// Writes a single HTML page that plays the song with WebAudio, draws it as a scrolling
+/// piano roll and offers the strings to copy. Everything is embedded, so it works offline.
+pub fn song_to_html(song: &Song, options: &PlayerOptions) -> Result<String, Box<dyn std::error::Error>> {
+    // `<` only appears inside JSON strings, so escaping it there keeps `</script>` out of the page
+    let data = player_data(song, options)?.to_string().replace('<', "\\u003c");
+    let title = if options.title.is_empty() { "mid2text" } else { &options.title };
+    Ok(TEMPLATE.replace("{{TITLE}}", &escape_html(title)).replace("{{DATA}}", &data))
+}
+
+pub fn write_html(song: &Song, path: &Path, options: &PlayerOptions) -> Result<(), Box<dyn std::error::Error>> {
+    std::fs::write(path, song_to_html(song, options)?)?;
+    Ok(())
+}
+
+const TEMPLATE: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{{TITLE}}</title>
+<style>
+body { font-family: sans-serif; margin: 16px; background: #fafafa; color: #222; }
+#controls { display: flex; gap: 8px; align-items: center; margin-bottom: 8px; }
+#seek { flex: 1; }
+#roll { width: 100%; background: white; border: 1px solid #ccc; display: block; }
+#legend span { display: inline-block; margin-right: 12px; }
+#legend i { display: inline-block; width: 10px; height: 10px; margin-right: 4px; }
+#chunks { list-style: none; padding: 0; }
+#chunks li { margin: 4px 0; display: flex; gap: 8px; align-items: baseline; }
+#chunks code { word-break: break-all; background: white; border: 1px solid #ddd; padding: 2px 4px; flex: 1; }
+</style>
+</head>
+<body>
+<h1>{{TITLE}}</h1>
+<div id="controls">
+<button id="play">Play</button>
+<input id="seek" type="range" min="0" step="1" value="0">
+<span id="time">0.0 s</span>
+</div>
+<canvas id="roll" height="300"></canvas>
+<p id="legend"></p>
+<h2>Strings</h2>
+<ul id="chunks"></ul>
+<script>
+const data = {{DATA}};
+const TICKS_PER_SECOND = 20;
+const TICK_WIDTH = 6;
+const KEY_HEIGHT = 5;
+const HEAD = 120;
+
+let ctx = null, master = null, noise = null;
+let playing = false, position = 0, startAt = 0, next = 0, sources = [];
+
+function audio() {
+  if (!ctx) {
+    ctx = new (window.AudioContext || window.webkitAudioContext)();
+    master = ctx.createGain();
+    master.gain.value = 0.4;
+    master.connect(ctx.destination);
+    noise = ctx.createBuffer(1, ctx.sampleRate / 2, ctx.sampleRate);
+    const samples = noise.getChannelData(0);
+    for (let i = 0; i < samples.length; i++) samples[i] = Math.random() * 2 - 1;
+  }
+  return ctx;
+}
+
+function playNote(note, when) {
+  const inst = data.instruments[note[1]];
+  const gain = ctx.createGain();
+  gain.connect(master);
+  let source;
+  if (inst.wave === "noise") {
+    source = ctx.createBufferSource();
+    source.buffer = noise;
+    const filter = ctx.createBiquadFilter();
+    filter.type = inst.name === "hat" ? "highpass" : "bandpass";
+    filter.frequency.value = inst.name === "hat" ? 7000 : 1800;
+    source.connect(filter);
+    filter.connect(gain);
+  } else {
+    source = ctx.createOscillator();
+    if (inst.wave === "kick") {
+      source.type = "sine";
+      source.frequency.setValueAtTime(150, when);
+      source.frequency.exponentialRampToValueAtTime(50, when + 0.1);
+    } else {
+      source.type = inst.wave;
+      source.frequency.value = 440 * Math.pow(2, (note[2] - 69) / 12);
+    }
+    source.connect(gain);
+  }
+  gain.gain.setValueAtTime(0.0001, when);
+  gain.gain.exponentialRampToValueAtTime(0.3, when + 0.005);
+  gain.gain.exponentialRampToValueAtTime(0.0001, when + inst.decay);
+  source.start(when);
+  source.stop(when + inst.decay + 0.05);
+  sources.push(source);
+  source.onended = () => {
+    const i = sources.indexOf(source);
+    if (i >= 0) sources.splice(i, 1);
+  };
+}
+
+function now() {
+  return playing ? (ctx.currentTime - startAt) * TICKS_PER_SECOND : position;
+}
+
+const playButton = document.getElementById("play");
+const seekBar = document.getElementById("seek");
+const time = document.getElementById("time");
+seekBar.max = data.length;
+
+function play() {
+  audio().resume();
+  if (position >= data.length) position = 0;
+  startAt = ctx.currentTime - position / TICKS_PER_SECOND;
+  next = data.notes.findIndex(n => n[0] >= position);
+  if (next < 0) next = data.notes.length;
+  playing = true;
+  playButton.textContent = "Pause";
+}
+
+function pause() {
+  position = Math.min(now(), data.length);
+  playing = false;
+  for (const source of sources) {
+    try { source.stop(); } catch (e) {}
+  }
+  sources = [];
+  playButton.textContent = "Play";
+}
+
+function seek(tick) {
+  const was = playing;
+  if (was) pause();
+  position = tick;
+  if (was) play();
+}
+
+playButton.onclick = () => (playing ? pause() : play());
+seekBar.oninput = () => seek(Number(seekBar.value));
+
+const canvas = document.getElementById("roll");
+const roll = canvas.getContext("2d");
+const keys = data.high - data.low + 1;
+canvas.height = (keys + data.drums * 2) * KEY_HEIGHT;
+
+function keyY(note) {
+  const inst = data.instruments[note[1]];
+  return inst.drum ? (keys + note[2] * 2) * KEY_HEIGHT + KEY_HEIGHT / 2 : (data.high - note[2]) * KEY_HEIGHT;
+}
+
+function draw(tick) {
+  canvas.width = canvas.clientWidth;
+  const width = canvas.width;
+  const first = tick - HEAD / TICK_WIDTH;
+  const last = tick + (width - HEAD) / TICK_WIDTH;
+  const x = t => HEAD + (t - tick) * TICK_WIDTH;
+  roll.fillStyle = "#f4f4f4";
+  for (let key = data.low; key <= data.high; key++) {
+    if ([1, 3, 6, 8, 10].includes(((key % 12) + 12) % 12)) roll.fillRect(0, (data.high - key) * KEY_HEIGHT, width, KEY_HEIGHT);
+  }
+  roll.strokeStyle = "#ccc";
+  if (data.bar > 0) {
+    for (let t = Math.max(0, Math.floor(first / data.bar) * data.bar); t <= last; t += data.bar) {
+      roll.beginPath();
+      roll.moveTo(x(t) + 0.5, 0);
+      roll.lineTo(x(t) + 0.5, canvas.height);
+      roll.stroke();
+    }
+  }
+  for (const note of data.notes) {
+    if (note[0] < first - 8) continue;
+    if (note[0] > last) break;
+    roll.fillStyle = data.instruments[note[1]].colour;
+    roll.globalAlpha = note[0] <= tick && tick < note[0] + 4 ? 1 : 0.7;
+    roll.fillRect(x(note[0]), keyY(note), TICK_WIDTH * 2 - 1, KEY_HEIGHT);
+  }
+  roll.globalAlpha = 1;
+  roll.fillStyle = "#d00";
+  roll.fillRect(HEAD, 0, 1, canvas.height);
+}
+
+function frame() {
+  if (playing) {
+    const horizon = ctx.currentTime + 0.15;
+    while (next < data.notes.length && startAt + data.notes[next][0] / TICKS_PER_SECOND < horizon) {
+      playNote(data.notes[next], Math.max(ctx.currentTime, startAt + data.notes[next][0] / TICKS_PER_SECOND));
+      next++;
+    }
+    if (now() >= data.length && next >= data.notes.length) pause();
+  }
+  const tick = now();
+  seekBar.value = tick;
+  time.textContent = (tick / TICKS_PER_SECOND).toFixed(1) + " s";
+  draw(tick);
+  requestAnimationFrame(frame);
+}
+
+const legend = document.getElementById("legend");
+for (const inst of data.instruments) {
+  const item = document.createElement("span");
+  const swatch = document.createElement("i");
+  swatch.style.background = inst.colour;
+  item.append(swatch, inst.name);
+  legend.append(item);
+}
+
+function copy(text, button) {
+  const done = () => {
+    button.textContent = "Copied";
+    setTimeout(() => (button.textContent = "Copy"), 1500);
+  };
+  const fallback = () => {
+    const area = document.createElement("textarea");
+    area.value = text;
+    document.body.append(area);
+    area.select();
+    document.execCommand("copy");
+    area.remove();
+    done();
+  };
+  if (navigator.clipboard) navigator.clipboard.writeText(text).then(done, fallback);
+  else fallback();
+}
+
+const list = document.getElementById("chunks");
+data.chunks.forEach((chunk, i) => {
+  const item = document.createElement("li");
+  const button = document.createElement("button");
+  button.textContent = "Copy";
+  button.onclick = () => copy(chunk.text, button);
+  const label = document.createElement("span");
+  label.textContent = "[" + (i + 1) + "/" + data.chunks.length + "] tick " + chunk.start;
+  const text = document.createElement("code");
+  text.textContent = chunk.text;
+  item.append(button, label, text);
+  list.append(item);
+});
+
+requestAnimationFrame(frame);
+</script>
+</body>
+</html>
+"##;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_player_data() {
+        let song = Song::from_text("G2!G2@I2+M4").unwrap();
+        let data = player_data(&song, &PlayerOptions::default()).unwrap();
+        assert_eq!(data["length"], 20);
+        assert_eq!(data["drums"], 1);
+        let names: Vec<&str> = data["instruments"].as_array().unwrap().iter().map(|i| i["name"].as_str().unwrap()).collect();
+        assert_eq!(names, vec!["pling", "hat", "flute"]);
+        // flute plays an octave up, and the hat is in the first drum lane
+        assert_eq!(data["notes"], json!([[0, 0, 60], [4, 1, 0], [8, 2, 74], [12, 0, 90]]));
+        assert_eq!(data["chunks"][0]["text"], "G2!G2@I2+M4");
+    }
+
+    #[test]
+    fn test_song_to_html() {
+        let song = Song::from_text("G2I2K2L2M2N2O2P2").unwrap();
+        let options = PlayerOptions { title: "</script><b>".to_string(), max_len: Some(8), bar: None };
+        let html = song_to_html(&song, &options).unwrap();
+        assert!(html.contains("<title>&lt;/script&gt;&lt;b&gt;</title>"));
+        assert!(html.contains(r#""title":"\u003c/script>\u003cb>""#));
+        assert_eq!(html.matches("</script>").count(), 1);
+        assert!(!html.contains("{{DATA}}"));
+        let data = player_data(&song, &options).unwrap();
+        assert!(data["chunks"].as_array().unwrap().len() > 1);
+    }
+}
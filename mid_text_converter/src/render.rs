@@ -0,0 +1,258 @@
+use crate::instruments::InstrumentKind;
+use crate::song::Song;
+use crate::utils;
+use std::f32::consts::PI;
+use std::path::Path;
+
+pub const SAMPLE_RATE: u32 = 44100;
+
+/// Peak level the mix is normalized to.
+const PEAK: f32 = 0.9;
+
+/// Octaves the game plays each instrument above or below its key.
+pub fn octave_offset(kind: InstrumentKind) -> i32 {
+    match kind {
+        InstrumentKind::Bass => -2,
+        InstrumentKind::Guitar => -1,
+        InstrumentKind::Flute => 1,
+        InstrumentKind::Bell | InstrumentKind::Chime | InstrumentKind::Xylophone => 2,
+        _ => 0,
+    }
+}
+
+/// Frequency in Hz the game plays `key` at on `kind`.
+pub fn frequency(kind: InstrumentKind, key: u8) -> f32 {
+    let key = key as i32 + octave_offset(kind) * 12;
+    440.0 * 2f32.powf((key - 69) as f32 / 12.0)
+}
+
+/// Small deterministic noise source, so renders are reproducible.
+struct Noise(u32);
+
+impl Noise {
+    fn next(&mut self) -> f32 {
+        self.0 = self.0.wrapping_mul(1664525).wrapping_add(1013904223);
+        (self.0 >> 8) as f32 / (1 << 23) as f32 - 1.0
+    }
+}
+
+fn seconds(s: f32) -> usize {
+    (s * SAMPLE_RATE as f32) as usize
+}
+
+/// Karplus-Strong plucked string.
+fn pluck(freq: f32, length: f32, damping: f32) -> Vec<f32> {
+    let period = ((SAMPLE_RATE as f32 / freq) as usize).max(2);
+    let mut noise = Noise(period as u32);
+    let mut buffer: Vec<f32> = (0..period).map(|_| noise.next()).collect();
+    (0..seconds(length))
+        .map(|i| {
+            let j = i % period;
+            let out = buffer[j];
+            buffer[j] = damping * 0.5 * (buffer[j] + buffer[(j + 1) % period]);
+            out
+        })
+        .collect()
+}
+
+/// Sum of decaying sine partials given as `(frequency ratio, amplitude, decay per second)`.
+fn partials(freq: f32, length: f32, attack: f32, partials: &[(f32, f32, f32)]) -> Vec<f32> {
+    (0..seconds(length))
+        .map(|i| {
+            let t = i as f32 / SAMPLE_RATE as f32;
+            let env = if attack > 0.0 { (t / attack).min(1.0) } else { 1.0 };
+            env * partials.iter()
+                .map(|&(ratio, amp, decay)| amp * (-decay * t).exp() * (2.0 * PI * freq * ratio * t).sin())
+                .sum::<f32>()
+        })
+        .collect()
+}
+
+fn noise_burst(length: f32, decay: f32, brightness: f32, seed: u32) -> Vec<f32> {
+    let mut noise = Noise(seed);
+    let mut last = 0.0;
+    (0..seconds(length))
+        .map(|i| {
+            let t = i as f32 / SAMPLE_RATE as f32;
+            let n = noise.next();
+            // mixing in the previous sample darkens the noise when brightness is low
+            let out = brightness * n + (1.0 - brightness) * last;
+            last = n;
+            out * (-decay * t).exp()
+        })
+        .collect()
+}
+
+/// Synthesizes one note of `kind`.
+pub fn synth_note(kind: InstrumentKind, key: u8) -> Vec<f32> {
+    let freq = frequency(kind, key);
+    match kind {
+        InstrumentKind::Harp => pluck(freq, 1.5, 0.996),
+        InstrumentKind::Guitar => pluck(freq, 1.0, 0.99),
+        InstrumentKind::Bell => partials(freq, 2.0, 0.0, &[(1.0, 0.6, 3.0), (2.76, 0.3, 5.0), (5.4, 0.15, 8.0)]),
+        InstrumentKind::Chime => partials(freq, 2.5, 0.0, &[(1.0, 0.5, 2.0), (3.0, 0.25, 4.0), (4.2, 0.2, 6.0)]),
+        InstrumentKind::Xylophone => partials(freq, 0.4, 0.0, &[(1.0, 0.7, 12.0), (3.93, 0.2, 25.0)]),
+        InstrumentKind::Pling => partials(freq, 1.2, 0.0, &[(1.0, 0.6, 3.0), (2.0, 0.25, 5.0), (3.0, 0.1, 8.0)]),
+        InstrumentKind::Bass => partials(freq, 0.8, 0.005, &[(1.0, 0.8, 4.0), (2.0, 0.2, 6.0)]),
+        InstrumentKind::Flute => partials(freq, 0.6, 0.05, &[(1.0, 0.7, 2.0), (2.0, 0.1, 2.0)]),
+        InstrumentKind::Hat => noise_burst(0.06, 60.0, 1.0, key as u32),
+        InstrumentKind::Snare => {
+            let mut out = noise_burst(0.2, 18.0, 0.7, key as u32);
+            let tone = partials(freq / 2.0, 0.2, 0.0, &[(1.0, 0.3, 25.0)]);
+            for (o, t) in out.iter_mut().zip(tone) {
+                *o += t;
+            }
+            out
+        }
+        InstrumentKind::BassDrum => (0..seconds(0.3))
+            .map(|i| {
+                let t = i as f32 / SAMPLE_RATE as f32;
+                // the pitch falls quickly from about 150 Hz to 50 Hz
+                let phase = 2.0 * PI * (50.0 * t + 100.0 * (1.0 - (-30.0 * t).exp()) / 30.0);
+                phase.sin() * (-12.0 * t).exp()
+            })
+            .collect(),
+    }
+}
+
+/// Sample index at which a note on `tick` starts.
+pub fn tick_to_sample(tick: u32) -> usize {
+    tick as usize * SAMPLE_RATE as usize / utils::TICKS_PER_SECOND as usize
+}
+
+/// How notes are combined into the final mix.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MixOptions {
+    /// Most notes sounding at once. When exceeded, the oldest note is cut off.
+    pub polyphony: Option<usize>,
+    /// Peak level to normalize to, or `None` to only clip.
+    pub normalize: Option<f32>,
+}
+
+impl Default for MixOptions {
+    fn default() -> Self {
+        Self { polyphony: None, normalize: Some(PEAK) }
+    }
+}
+
+/// Length of the fade applied when a note is cut off by the polyphony limit.
+const CUT_FADE: f32 = 0.005;
+
+/// Mixes the notes returned by `voice` for every note of the song.
+pub fn mix<F>(song: &Song, mut voice: F, options: &MixOptions) -> Vec<f32>
+where
+    F: FnMut(InstrumentKind, u8) -> Vec<f32>,
+{
+    let mut notes: Vec<(usize, Vec<f32>)> = song.tracks.iter()
+        .flat_map(|instrument| instrument.track().iter().map(move |n| (instrument.kind(), n)))
+        .map(|(kind, note)| (tick_to_sample(note.start_timing), voice(kind, note.key.as_int())))
+        .collect();
+    notes.sort_by_key(|(start, _)| *start);
+
+    if let Some(polyphony) = options.polyphony.filter(|p| *p > 0) {
+        // indices of the notes still sounding, oldest first
+        let mut sounding: Vec<usize> = Vec::new();
+        for i in 0..notes.len() {
+            let start = notes[i].0;
+            sounding.retain(|&j| notes[j].0 + notes[j].1.len() > start);
+            if sounding.len() >= polyphony {
+                let oldest = sounding.remove(0);
+                let (old_start, samples) = &mut notes[oldest];
+                samples.truncate(start - *old_start);
+                let fade = seconds(CUT_FADE).min(samples.len());
+                let len = samples.len();
+                for (k, s) in samples[len - fade..].iter_mut().enumerate() {
+                    *s *= 1.0 - (k + 1) as f32 / fade as f32;
+                }
+            }
+            sounding.push(i);
+        }
+    }
+
+    let mut out = vec![0.0f32; tick_to_sample(song.length())];
+    for (start, samples) in notes {
+        if out.len() < start + samples.len() {
+            out.resize(start + samples.len(), 0.0);
+        }
+        for (o, s) in out[start..].iter_mut().zip(samples) {
+            *o += s;
+        }
+    }
+    if let Some(peak) = options.normalize {
+        normalize(&mut out, peak);
+    }
+    out
+}
+
+pub fn normalize(samples: &mut [f32], peak: f32) {
+    let max = samples.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+    if max > 0.0 {
+        for s in samples.iter_mut() {
+            *s *= peak / max;
+        }
+    }
+}
+
+/// Renders a song with the built-in synthesizer.
+pub fn render_song(song: &Song) -> Vec<f32> {
+    mix(song, synth_note, &MixOptions::default())
+}
+
+/// Writes mono samples as a 16-bit WAV file.
+pub fn write_wav(samples: &[f32], path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: SAMPLE_RATE,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(path, spec)?;
+    for s in samples {
+        writer.write_sample((s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)?;
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frequency() {
+        assert!((frequency(InstrumentKind::Harp, 69) - 440.0).abs() < 0.01);
+        assert!((frequency(InstrumentKind::Bass, 69) - 110.0).abs() < 0.01);
+        assert!((frequency(InstrumentKind::Bell, 57) - 880.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_tick_to_sample() {
+        assert_eq!(tick_to_sample(0), 0);
+        assert_eq!(tick_to_sample(20), 44100);
+    }
+
+    #[test]
+    fn test_render_song() {
+        let song = Song::from_text("1G=G!G?G;G:G/G_G@G\\G,G").unwrap();
+        let samples = render_song(&song);
+        let start = tick_to_sample(2);
+
+        assert!(samples[..start].iter().all(|s| *s == 0.0));
+        assert!(samples[start..].iter().any(|s| *s != 0.0));
+        let peak = samples.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+        assert!((peak - PEAK).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_mix_polyphony() {
+        let song = Song::from_text("G2G2G").unwrap();
+        let voice = |_, _| vec![0.5; tick_to_sample(10)];
+        let options = MixOptions { polyphony: Some(1), normalize: None };
+        let samples = mix(&song, voice, &options);
+
+        assert_eq!(samples.len(), tick_to_sample(18));
+        assert!(samples.iter().all(|s| *s <= 0.5));
+        let options = MixOptions { polyphony: None, normalize: None };
+        assert_eq!(mix(&song, voice, &options)[tick_to_sample(9)], 1.5);
+    }
+}
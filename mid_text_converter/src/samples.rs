@@ -0,0 +1,154 @@
+use crate::instruments::InstrumentKind;
+use crate::render::{self, MixOptions, SAMPLE_RATE};
+use crate::song::Song;
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+
+/// Key the game plays a sample file at its original speed.
+pub const BASE_KEY: u8 = 66;
+
+/// One recorded sound per instrument, resampled to `SAMPLE_RATE`.
+#[derive(Debug, Clone, Default)]
+pub struct SamplePack {
+    samples: HashMap<InstrumentKind, Vec<f32>>,
+}
+
+impl SamplePack {
+    pub fn new() -> Self {
+        Self { samples: HashMap::new() }
+    }
+
+    pub fn insert(&mut self, kind: InstrumentKind, samples: Vec<f32>) {
+        self.samples.insert(kind, samples);
+    }
+
+    /// Loads `<name>.wav` or `<name>.ogg` for every instrument found in `dir`, e.g. `harp.ogg`.
+    /// Instruments without a file are left out.
+    pub fn load(dir: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut pack = Self::new();
+        for kind in InstrumentKind::ALL {
+            let wav = dir.join(format!("{}.wav", kind.name()));
+            let ogg = dir.join(format!("{}.ogg", kind.name()));
+            let (samples, rate) = if wav.exists() {
+                read_wav(&wav)?
+            } else if ogg.exists() {
+                read_ogg(&ogg)?
+            } else {
+                continue;
+            };
+            pack.insert(kind, resample(&samples, rate as f32 / SAMPLE_RATE as f32));
+        }
+        Ok(pack)
+    }
+
+    /// Instruments that have no sample.
+    pub fn missing(&self) -> Vec<InstrumentKind> {
+        InstrumentKind::ALL.into_iter().filter(|k| !self.samples.contains_key(k)).collect()
+    }
+
+    /// The sample of `kind` pitched to `key`, or the built-in synthesizer if there is none.
+    pub fn note(&self, kind: InstrumentKind, key: u8) -> Vec<f32> {
+        match self.samples.get(&kind) {
+            Some(samples) => resample(samples, 2f32.powf((key as f32 - BASE_KEY as f32) / 12.0)),
+            None => render::synth_note(kind, key),
+        }
+    }
+
+    pub fn render(&self, song: &Song, options: &MixOptions) -> Vec<f32> {
+        render::mix(song, |kind, key| self.note(kind, key), options)
+    }
+}
+
+/// Plays `samples` `ratio` times faster using linear interpolation.
+pub fn resample(samples: &[f32], ratio: f32) -> Vec<f32> {
+    if samples.is_empty() || ratio <= 0.0 {
+        return Vec::new();
+    }
+    let len = ((samples.len() - 1) as f32 / ratio) as usize + 1;
+    (0..len)
+        .map(|i| {
+            let pos = i as f32 * ratio;
+            let j = pos as usize;
+            let frac = pos - j as f32;
+            let next = samples.get(j + 1).copied().unwrap_or(samples[j]);
+            samples[j] * (1.0 - frac) + next * frac
+        })
+        .collect()
+}
+
+/// Averages interleaved channels into one.
+fn to_mono(interleaved: &[f32], channels: usize) -> Vec<f32> {
+    interleaved.chunks(channels.max(1))
+        .map(|frame| frame.iter().sum::<f32>() / frame.len() as f32)
+        .collect()
+}
+
+/// Reads a WAV file as mono samples and its sample rate.
+pub fn read_wav(path: &Path) -> Result<(Vec<f32>, u32), Box<dyn std::error::Error>> {
+    let mut reader = hound::WavReader::open(path)?;
+    let spec = reader.spec();
+    let interleaved: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+        hound::SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader.samples::<i32>()
+                .map(|s| s.map(|s| s as f32 / scale))
+                .collect::<Result<_, _>>()?
+        }
+    };
+    Ok((to_mono(&interleaved, spec.channels as usize), spec.sample_rate))
+}
+
+/// Reads an Ogg Vorbis file as mono samples and its sample rate.
+pub fn read_ogg(path: &Path) -> Result<(Vec<f32>, u32), Box<dyn std::error::Error>> {
+    let mut reader = lewton::inside_ogg::OggStreamReader::new(File::open(path)?)?;
+    let channels = reader.ident_hdr.audio_channels as usize;
+    let mut interleaved = Vec::new();
+    while let Some(packet) = reader.read_dec_packet_itl()? {
+        interleaved.extend(packet.iter().map(|s| *s as f32 / 32768.0));
+    }
+    Ok((to_mono(&interleaved, channels), reader.ident_hdr.audio_sample_rate))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resample() {
+        assert_eq!(resample(&[0.0, 1.0, 2.0, 3.0, 4.0], 2.0), vec![0.0, 2.0, 4.0]);
+        assert_eq!(resample(&[0.0, 1.0], 0.5), vec![0.0, 0.5, 1.0]);
+        assert!(resample(&[], 1.0).is_empty());
+    }
+
+    #[test]
+    fn test_note_pitch() {
+        let mut pack = SamplePack::new();
+        pack.insert(InstrumentKind::Harp, vec![0.5; 1200]);
+        assert_eq!(pack.note(InstrumentKind::Harp, BASE_KEY).len(), 1200);
+        assert_eq!(pack.note(InstrumentKind::Harp, BASE_KEY + 12).len(), 600);
+        assert_eq!(pack.note(InstrumentKind::Harp, BASE_KEY - 12).len(), 2399);
+        assert_eq!(pack.missing().len(), 10);
+    }
+
+    #[test]
+    fn test_load_wav() {
+        let dir = std::env::temp_dir().join(format!("mid_text_converter_samples_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let spec = hound::WavSpec { channels: 2, sample_rate: 22050, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+        let mut writer = hound::WavWriter::create(dir.join("flute.wav"), spec).unwrap();
+        for _ in 0..100 {
+            writer.write_sample(16384i16).unwrap();
+            writer.write_sample(0i16).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let pack = SamplePack::load(&dir).unwrap();
+        assert!(!pack.missing().contains(&InstrumentKind::Flute));
+        let note = pack.note(InstrumentKind::Flute, BASE_KEY);
+        assert_eq!(note.len(), 199);
+        assert!((note[0] - 0.25).abs() < 1e-6);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
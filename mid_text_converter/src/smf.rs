@@ -0,0 +1,194 @@
+use crate::instruments::{InstrumentKind, Instruments};
+use crate::song::Song;
+use crate::utils;
+use midly::num::{u4, u7, u15, u24, u28};
+use midly::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+use std::path::{Path, PathBuf};
+
+/// Pulses per quarter note of exported files.
+pub const PPQ: u16 = 96;
+
+/// MIDI channel used for the drum instruments.
+const DRUM_CHANNEL: u8 = 9;
+
+const VELOCITY: u8 = 100;
+
+/// General MIDI program closest to the sound of each melodic instrument.
+pub fn gm_program(kind: InstrumentKind) -> u8 {
+    match kind {
+        InstrumentKind::Pling => 4,
+        InstrumentKind::Bass => 32,
+        InstrumentKind::Bell => 9,
+        InstrumentKind::Chime => 14,
+        InstrumentKind::Flute => 73,
+        InstrumentKind::Guitar => 24,
+        InstrumentKind::Harp => 46,
+        InstrumentKind::Xylophone => 13,
+        InstrumentKind::Hat | InstrumentKind::Snare | InstrumentKind::BassDrum => 0,
+    }
+}
+
+/// General MIDI percussion key played for a drum instrument.
+pub fn gm_drum_key(kind: InstrumentKind) -> Option<u8> {
+    match kind {
+        InstrumentKind::Hat => Some(42),
+        InstrumentKind::Snare => Some(38),
+        InstrumentKind::BassDrum => Some(36),
+        _ => None,
+    }
+}
+
+/// Tempo in microseconds per quarter note that plays one output tick per game tick.
+fn tempo() -> u32 {
+    let ticks_per_quarter = PPQ as u32 / utils::MIDI_TICKS_PER_TICK;
+    1_000_000 * ticks_per_quarter / utils::TICKS_PER_SECOND
+}
+
+fn tempo_track() -> Vec<TrackEvent<'static>> {
+    vec![
+        TrackEvent { delta: u28::from(0), kind: TrackEventKind::Meta(MetaMessage::Tempo(u24::from(tempo()))) },
+        TrackEvent { delta: u28::from(0), kind: TrackEventKind::Meta(MetaMessage::EndOfTrack) },
+    ]
+}
+
+/// Builds the events of one instrument on `channel`.
+///
+/// Without `note_length`, every note lasts until the next note of the track starts.
+/// With `gm_drums`, drums use their General MIDI percussion key, so their pitch is lost.
+fn instrument_track(instrument: &Instruments, channel: u8, note_length: Option<u32>, gm_drums: bool) -> Vec<TrackEvent<'static>> {
+    let kind = instrument.kind();
+    let channel = u4::from(channel);
+    let mut notes: Vec<(u32, u8)> = instrument.track().iter()
+        .map(|n| (n.start_timing, gm_drum_key(kind).filter(|_| gm_drums).unwrap_or(n.key.as_int())))
+        .collect();
+    notes.sort();
+
+    // (tick, is note on, key); note offs sort before note ons on the same tick
+    let mut events: Vec<(u32, bool, u8)> = Vec::new();
+    for (i, &(start, key)) in notes.iter().enumerate() {
+        let length = note_length.unwrap_or_else(|| {
+            notes[i..].iter().find(|n| n.0 > start).map(|n| n.0 - start).unwrap_or(1)
+        }).max(1);
+        events.push((start, true, key));
+        events.push((start + length, false, key));
+    }
+    events.sort();
+
+    let mut track = vec![TrackEvent {
+        delta: u28::from(0),
+        kind: TrackEventKind::Meta(MetaMessage::TrackName(kind.name().as_bytes())),
+    }];
+    if !kind.is_drum() {
+        track.push(TrackEvent {
+            delta: u28::from(0),
+            kind: TrackEventKind::Midi { channel, message: MidiMessage::ProgramChange { program: u7::from(gm_program(kind)) } },
+        });
+    }
+
+    let mut last = 0;
+    for (tick, on, key) in events {
+        let message = if on {
+            MidiMessage::NoteOn { key: u7::from(key), vel: u7::from(VELOCITY) }
+        } else {
+            MidiMessage::NoteOff { key: u7::from(key), vel: u7::from(0) }
+        };
+        track.push(TrackEvent {
+            delta: u28::from((tick - last) * utils::MIDI_TICKS_PER_TICK),
+            kind: TrackEventKind::Midi { channel, message },
+        });
+        last = tick;
+    }
+    track.push(TrackEvent { delta: u28::from(0), kind: TrackEventKind::Meta(MetaMessage::EndOfTrack) });
+    track
+}
+
+fn new_smf(tracks: Vec<Vec<TrackEvent<'static>>>) -> Smf<'static> {
+    let mut smf = Smf::new(Header::new(Format::Parallel, Timing::Metrical(u15::from(PPQ))));
+    smf.tracks.push(tempo_track());
+    smf.tracks.extend(tracks);
+    smf
+}
+
+/// Converts a song to a Standard MIDI File with one track per `Instruments` entry.
+pub fn song_to_smf(song: &Song, note_length: Option<u32>) -> Smf<'static> {
+    song_to_smf_with(song, note_length, true)
+}
+
+/// Like `song_to_smf`, but drums keep their own key on the percussion channel unless `gm_drums` is set.
+pub(crate) fn song_to_smf_with(song: &Song, note_length: Option<u32>, gm_drums: bool) -> Smf<'static> {
+    let mut melodic = (0..16u8).filter(|c| *c != DRUM_CHANNEL).cycle();
+    let tracks = song.tracks.iter()
+        .map(|instrument| {
+            let channel = if instrument.kind().is_drum() { DRUM_CHANNEL } else { melodic.next().unwrap() };
+            instrument_track(instrument, channel, note_length, gm_drums)
+        })
+        .collect();
+    new_smf(tracks)
+}
+
+pub fn write_song(song: &Song, path: &Path, note_length: Option<u32>) -> Result<(), Box<dyn std::error::Error>> {
+    song_to_smf(song, note_length).save(path)?;
+    Ok(())
+}
+
+/// Writes one file per instrument next to `path`, named `<stem>_<instrument>.mid`.
+/// Tracks of the same instrument end up in the same file. Returns the written paths.
+pub fn write_stems(song: &Song, path: &Path, note_length: Option<u32>) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("song");
+    let mut written = Vec::new();
+
+    for kind in InstrumentKind::ALL {
+        let mut stem_song = Song::new();
+        for instrument in song.tracks.iter().filter(|t| t.kind() == kind) {
+            stem_song.add_track(instrument.clone());
+        }
+        if stem_song.tracks.is_empty() {
+            continue;
+        }
+
+        let stem_path = path.with_file_name(format!("{}_{}.mid", stem, kind.name()));
+        write_song(&stem_song, &stem_path, note_length)?;
+        written.push(stem_path);
+    }
+
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruments::Track;
+
+    #[test]
+    fn test_tempo() {
+        assert_eq!(tempo(), 400_000);
+    }
+
+    #[test]
+    fn test_song_to_smf_round_trip() {
+        let song = Song::from_text("@G2@I.@K3GN").unwrap();
+        let smf = song_to_smf(&song, None);
+        assert_eq!(smf.tracks.len(), 3);
+
+        let mut data = Vec::new();
+        smf.write_std(&mut data).unwrap();
+        let parsed = Smf::parse(&data).unwrap();
+        let track = Track::midi_to_track(&parsed);
+        let ticks: Vec<(u32, u8)> = track.iter().map(|n| (n.start_timing, n.key.as_int())).collect();
+        assert_eq!(ticks, vec![(0, 60), (4, 62), (5, 64), (11, 60), (11, 67)]);
+    }
+
+    #[test]
+    fn test_drums_use_percussion_channel() {
+        let song = Song::from_text("=G2!I").unwrap();
+        let smf = song_to_smf(&song, Some(2));
+        for track in &smf.tracks[1..] {
+            for event in track {
+                if let TrackEventKind::Midi { channel, message } = event.kind {
+                    assert_eq!(channel.as_int(), DRUM_CHANNEL);
+                    assert!(!matches!(message, MidiMessage::ProgramChange { .. }));
+                }
+            }
+        }
+    }
+}
@@ -1,6 +1,19 @@
-use crate::instruments::Instruments;
+use crate::chunk::{self, Chunk};
+use crate::instruments::{InstrumentKind, Instruments, Track};
+use crate::note::Note;
 use crate::utils;
+use midly::num::u7;
+use thiserror::Error;
 
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum SongError {
+    #[error("Invalid character: {0}")]
+    InvalidCharacter(char),
+    #[error("Missing note after prefix: {0}")]
+    MissingNote(char),
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Song {
     pub tracks: Vec<Instruments>,
     pub end: u32,
@@ -20,8 +33,126 @@ impl Song {
         for track in &self.tracks {
             t.push(track.to_text(relative_move)?);
         }
-        Ok(utils::merge_string(&t))
-        
+        let mut result = utils::merge_string(&t);
+        let last = self.last_timing();
+        if self.end > last {
+            result.push_str(&utils::tick_to_string(self.end - last));
+        }
+        Ok(result)
+    }
+
+    /// Like `to_text`, but split into chunks of at most `max_len` characters. See `chunk::chunk_string`.
+    pub fn to_chunks(&self, relative_move: bool, max_len: usize, bar: Option<u32>) -> Result<Vec<Chunk>, Box<dyn std::error::Error>> {
+        Ok(chunk::chunk_string(&self.to_text(relative_move)?, max_len, bar)?)
+    }
+
+    /// Parses a string produced by `to_text` back into a song.
+    /// Each instrument gets one track and `end` is set to the total length, including trailing silence.
+    pub fn from_text(text: &str) -> Result<Self, SongError> {
+        let mut tracks: Vec<(InstrumentKind, Track)> = Vec::new();
+        let mut current_tick = 0u32;
+        let mut chars = text.chars();
+
+        while let Some(c) = chars.next() {
+            if let Some(digit) = c.to_digit(10) {
+                current_tick += digit * 2;
+                continue;
+            }
+            if c == '.' {
+                current_tick += 1;
+                continue;
+            }
+
+            let (kind, offset, c) = if let Some(kind) = InstrumentKind::from_prefix(c) {
+                (kind, 0, chars.next().ok_or(SongError::MissingNote(c))?)
+            } else if c == '+' {
+                (InstrumentKind::Pling, 24, chars.next().ok_or(SongError::MissingNote(c))?)
+            } else if c == '-' {
+                (InstrumentKind::Pling, -24, chars.next().ok_or(SongError::MissingNote(c))?)
+            } else {
+                (InstrumentKind::Pling, 0, c)
+            };
+
+            if !('A'..='Y').contains(&c) {
+                return Err(SongError::InvalidCharacter(c));
+            }
+            let key = (c as i32 - 11 + offset) as u8;
+            let note = Note::new(u7::from(key), current_tick);
+
+            match tracks.iter_mut().find(|(k, _)| *k == kind) {
+                Some((_, track)) => track.push(note),
+                None => {
+                    let mut track = Track::new();
+                    track.push(note);
+                    tracks.push((kind, track));
+                }
+            }
+        }
+
+        Ok(Self {
+            tracks: tracks.into_iter().map(|(kind, track)| Instruments::new(kind, track)).collect(),
+            end: current_tick,
+        })
+    }
+
+    /// Returns the timing of the last note over all tracks.
+    pub fn last_timing(&self) -> u32 {
+        self.tracks.iter().map(|t| t.track().last_timing()).max().unwrap_or(0)
+    }
+
+    /// Number of notes outside the note block range 54..=78, which note blocks and the
+    /// sounds they play can only reach moved by octaves.
+    pub fn out_of_range_notes(&self) -> usize {
+        self.tracks.iter()
+            .flat_map(|t| t.track().iter())
+            .filter(|n| Note::fold_key(n.key.as_int()) != n.key.as_int())
+            .count()
+    }
+
+    /// Returns the real length of the song: `end` if set, otherwise the last note.
+    pub fn length(&self) -> u32 {
+        self.end.max(self.last_timing())
+    }
+
+    /// Appends `other` after the end of this song, and returns the tick it starts at.
+    ///
+    /// `other` starts after `end` and one tick after the last note, so the songs never
+    /// share a tick. `gap` ticks of silence are inserted between the songs. If `bar` is
+    /// given, the start of `other` is moved forward to the next multiple of `bar`.
+    pub fn concat(&mut self, mut other: Song, gap: u32, bar: Option<u32>) -> u32 {
+        let after_last = if self.tracks.iter().any(|t| !t.track().is_empty()) { self.last_timing() + 1 } else { 0 };
+        let mut start = self.end.max(after_last) + gap;
+        if let Some(bar) = bar.filter(|b| *b > 0) {
+            start = start.div_ceil(bar) * bar;
+        }
+
+        self.end = start + other.length();
+        for track in &mut other.tracks {
+            track.track_mut().shift(start);
+        }
+        self.tracks.append(&mut other.tracks);
+        start
+    }
+
+    /// Joins strings with the timing of `concat`, keeping each string as it was written.
+    ///
+    /// `to_text` would spell Pling notes at the edges of the range with `+` or `-`, so joining
+    /// the strings themselves keeps the result as short as its parts.
+    pub fn concat_texts(texts: &[&str], gap: u32, bar: Option<u32>) -> Result<String, SongError> {
+        let mut song = Song::new();
+        let mut result = String::new();
+        for (i, text) in texts.iter().enumerate() {
+            let next = Song::from_text(text)?;
+            if i == 0 {
+                song = next;
+            } else {
+                let written = song.length();
+                let start = song.concat(next, gap, bar);
+                result.push_str(&utils::tick_to_string(start - written));
+            }
+            result.push_str(text);
+        }
+        Ok(result)
     }
 }
 
@@ -36,7 +167,7 @@ mod tests {
     use crate::note::Note;
     use midly::num::u7;
     use crate::instruments::{InstrumentKind, Instruments, Track};
-    use crate::song::Song;
+    use crate::song::{Song, SongError};
 
     #[test]
     fn test_song() {
@@ -58,6 +189,53 @@ mod tests {
         let result = song.to_text(true).unwrap();
         assert_eq!(result, "@G.@I.+W.+X");
     }
+
+    #[test]
+    fn test_from_text() {
+        let song = Song::from_text("@G.@I.+W.+X2").unwrap();
+        assert_eq!(song.end, 7);
+        assert_eq!(song.tracks.len(), 2);
+        assert_eq!(song.tracks[0].kind(), InstrumentKind::Flute);
+        assert_eq!(song.tracks[1].kind(), InstrumentKind::Pling);
+        assert_eq!(song.to_text(true).unwrap(), "@G.@I.+W.+X2");
+
+        assert_eq!(Song::from_text("G1a"), Err(super::SongError::InvalidCharacter('a')));
+        assert_eq!(Song::from_text("G1@"), Err(super::SongError::MissingNote('@')));
+    }
+
+    #[test]
+    fn test_concat() {
+        let mut song = Song::from_text("G2I3").unwrap();
+        song.concat(Song::from_text("=G1K").unwrap(), 0, None);
+        assert_eq!(song.to_text(true).unwrap(), "G2I3=G1K");
+
+        let mut song = Song::from_text("G2I").unwrap();
+        song.concat(Song::from_text("G1").unwrap(), 1, None);
+        assert_eq!(song.to_text(true).unwrap(), "G2I1G1");
+
+        let mut song = Song::from_text("G2I").unwrap();
+        song.concat(Song::from_text("G").unwrap(), 0, Some(8));
+        assert_eq!(song.to_text(true).unwrap(), "G2I2G");
+
+        // a song that ends on a note is followed one tick later
+        let mut song = Song::from_text("G").unwrap();
+        assert_eq!(song.concat(Song::from_text("K").unwrap(), 0, None), 1);
+        assert_eq!(song.to_text(false).unwrap(), "G.K");
+    }
+
+    #[test]
+    fn test_out_of_range_notes() {
+        assert_eq!(Song::from_text("GY-Y").unwrap().out_of_range_notes(), 0);
+        assert_eq!(Song::from_text("+G1-G=G").unwrap().out_of_range_notes(), 2);
+    }
+
+    #[test]
+    fn test_concat_texts() {
+        assert_eq!(Song::concat_texts(&["G", "K"], 0, None).unwrap(), "G.K");
+        assert_eq!(Song::concat_texts(&["Y2A", "Y"], 0, None).unwrap(), "Y2A.Y");
+        assert_eq!(Song::concat_texts(&["G2I3", "=G1K", "G"], 2, Some(8)).unwrap(), "G2I33=G1K3G");
+        assert_eq!(Song::concat_texts(&["G", "$"], 0, None), Err(SongError::InvalidCharacter('$')));
+    }
 }
 pub mod mid {
     use crate::instruments::Track;
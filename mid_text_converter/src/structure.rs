@@ -0,0 +1,399 @@
+use crate::instruments::InstrumentKind;
+use crate::note::Note;
+use crate::song::Song;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+use thiserror::Error;
+
+/// Blocks between the centers of two parallel lines.
+const LINE_SPACING: i32 = 5;
+
+/// Most lines the feeder can reach: its dust is powered in the middle and carries 14 blocks each way.
+pub const MAX_LINES: usize = 6;
+
+/// Longest delay of one repeater, in redstone ticks.
+const MAX_DELAY: u32 = 4;
+
+const FLOOR: &str = "minecraft:smooth_stone";
+
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum StructureError {
+    #[error("Song needs {needed} parallel lines, only {allowed} fit")]
+    TooManyLines { needed: usize, allowed: usize },
+    #[error("Song needs {length} blocks of length, more than the limit of {max_length}")]
+    TooLong { length: u32, max_length: u32 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StructureOptions {
+    /// Largest size across the lines, in blocks.
+    pub max_width: u32,
+    /// Largest size along the lines, in blocks.
+    pub max_length: Option<u32>,
+    pub data_version: i32,
+}
+
+impl Default for StructureOptions {
+    fn default() -> Self {
+        Self { max_width: 48, max_length: None, data_version: 3953 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StructureReport {
+    pub lines: usize,
+    /// `[x, y, z]` in blocks.
+    pub size: [u32; 3],
+    /// Position of the dust that starts the song when powered.
+    pub start: [i32; 3],
+    pub note_blocks: usize,
+    /// Notes that play later than written: repeaters only step in two ticks, and notes
+    /// closer than that are pushed back.
+    pub late: usize,
+    /// Most ticks any note plays late.
+    pub max_late: u32,
+    /// Notes outside the note block range, tuned by octaves into it.
+    pub moved: usize,
+}
+
+/// `instrument` property of a note block for each instrument.
+pub fn note_block_instrument(kind: InstrumentKind) -> &'static str {
+    match kind {
+        InstrumentKind::BassDrum => "basedrum",
+        _ => kind.name(),
+    }
+}
+
+/// Block that makes a note block on top of it play `kind`.
+pub fn instrument_block(kind: InstrumentKind) -> &'static str {
+    match kind {
+        InstrumentKind::Harp => "minecraft:dirt",
+        InstrumentKind::Bass => "minecraft:oak_planks",
+        InstrumentKind::BassDrum => "minecraft:stone",
+        InstrumentKind::Snare => "minecraft:sand",
+        InstrumentKind::Hat => "minecraft:glass",
+        InstrumentKind::Guitar => "minecraft:white_wool",
+        InstrumentKind::Flute => "minecraft:clay",
+        InstrumentKind::Bell => "minecraft:gold_block",
+        InstrumentKind::Chime => "minecraft:packed_ice",
+        InstrumentKind::Xylophone => "minecraft:bone_block",
+        InstrumentKind::Pling => "minecraft:glowstone",
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Block {
+    pos: [i32; 3],
+    name: &'static str,
+    properties: Vec<(&'static str, String)>,
+}
+
+impl Block {
+    fn new(pos: [i32; 3], name: &'static str) -> Self {
+        Self { pos, name, properties: Vec::new() }
+    }
+
+    fn with(mut self, key: &'static str, value: impl ToString) -> Self {
+        self.properties.push((key, value.to_string()));
+        self
+    }
+}
+
+fn dust(pos: [i32; 3], north: bool, south: bool, east: bool, west: bool) -> Block {
+    let side = |connected: bool| if connected { "side" } else { "none" };
+    Block::new(pos, "minecraft:redstone_wire")
+        .with("north", side(north))
+        .with("south", side(south))
+        .with("east", side(east))
+        .with("west", side(west))
+        .with("power", 0)
+}
+
+/// Lays out the song along +x.
+///
+/// A feeder of dust at x = 0 starts every line at once. Each line is a chain of repeaters
+/// with a dust at every played tick; the dust branches sideways into up to two note blocks,
+/// so chords of more than two notes are spread over parallel lines.
+fn layout(song: &Song, options: &StructureOptions) -> Result<(Vec<Block>, StructureReport), StructureError> {
+    let mut ticks: BTreeMap<u32, Vec<(InstrumentKind, u8)>> = BTreeMap::new();
+    for instrument in &song.tracks {
+        for note in instrument.track().iter() {
+            ticks.entry(note.start_timing).or_default().push((instrument.kind(), note.key.as_int()));
+        }
+    }
+
+    let needed = ticks.values().map(|n| n.len().div_ceil(2)).max().unwrap_or(1);
+    let allowed = ((options.max_width / LINE_SPACING as u32) as usize).min(MAX_LINES);
+    if needed > allowed {
+        return Err(StructureError::TooManyLines { needed, allowed });
+    }
+    let lines = needed as i32;
+
+    let mut blocks = Vec::new();
+    let mut place = |block: Block, floor: &'static str| {
+        blocks.push(Block::new([block.pos[0], 0, block.pos[2]], floor));
+        blocks.push(block);
+    };
+
+    let last_line = (lines - 1) * LINE_SPACING;
+    for z in 0..=last_line {
+        let lone = lines == 1;
+        place(dust([0, 1, z], z > 0, z < last_line, z % LINE_SPACING == 0, lone), FLOOR);
+    }
+
+    let mut x = 1;
+    let mut redstone_time = 0;
+    let mut late = 0;
+    let mut max_late = 0;
+    let mut note_blocks = 0;
+    for (i, (tick, notes)) in ticks.iter().enumerate() {
+        // one extra redstone tick keeps the first branch away from the feeder
+        let time = (tick.div_ceil(2) + 1).max(redstone_time + 1);
+        let played = 2 * (time - 1);
+        if played != *tick {
+            late += notes.len();
+            max_late = max_late.max(played - tick);
+        }
+
+        let mut delay = time - redstone_time;
+        while delay > 0 {
+            let d = delay.min(MAX_DELAY);
+            for line in 0..lines {
+                let repeater = Block::new([x, 1, line * LINE_SPACING], "minecraft:repeater")
+                    .with("delay", d)
+                    .with("facing", "west")
+                    .with("locked", false)
+                    .with("powered", false);
+                place(repeater, FLOOR);
+            }
+            delay -= d;
+            x += 1;
+        }
+        redstone_time = time;
+
+        let mut notes = notes.clone();
+        notes.sort_by_key(|(_, key)| *key);
+        let has_next = i + 1 < ticks.len();
+        for line in 0..lines {
+            let z = line * LINE_SPACING;
+            let own: Vec<&(InstrumentKind, u8)> = notes.iter().skip(line as usize * 2).take(2).collect();
+            place(dust([x, 1, z], own.len() > 1, !own.is_empty(), has_next, true), FLOOR);
+
+            for (side, (kind, key)) in own.into_iter().enumerate() {
+                let dir = if side == 0 { 1 } else { -1 };
+                place(dust([x, 1, z + dir], true, true, false, false), FLOOR);
+                let note = Block::new([x, 1, z + 2 * dir], "minecraft:note_block")
+                    .with("instrument", note_block_instrument(*kind))
+                    .with("note", Note::fold_key(*key) - 54)
+                    .with("powered", false);
+                place(note, instrument_block(*kind));
+                note_blocks += 1;
+            }
+        }
+        x += 1;
+    }
+
+    let length = x as u32;
+    if let Some(max_length) = options.max_length.filter(|m| length > *m) {
+        return Err(StructureError::TooLong { length, max_length });
+    }
+
+    for block in &mut blocks {
+        block.pos[2] += 2;
+    }
+    let report = StructureReport {
+        lines: lines as usize,
+        size: [length, 2, (last_line + 5) as u32],
+        start: [0, 1, last_line / 2 + 2],
+        note_blocks,
+        late,
+        max_late,
+        moved: song.out_of_range_notes(),
+    };
+    Ok((blocks, report))
+}
+
+/// The few NBT tags a structure file needs.
+enum Tag {
+    Int(i32),
+    String(String),
+    List(u8, Vec<Tag>),
+    Compound(Vec<(&'static str, Tag)>),
+}
+
+impl Tag {
+    fn id(&self) -> u8 {
+        match self {
+            Tag::Int(_) => 3,
+            Tag::String(_) => 8,
+            Tag::List(..) => 9,
+            Tag::Compound(_) => 10,
+        }
+    }
+
+    fn write_string(out: &mut Vec<u8>, s: &str) {
+        out.extend_from_slice(&(s.len() as u16).to_be_bytes());
+        out.extend_from_slice(s.as_bytes());
+    }
+
+    fn write_payload(&self, out: &mut Vec<u8>) {
+        match self {
+            Tag::Int(v) => out.extend_from_slice(&v.to_be_bytes()),
+            Tag::String(s) => Self::write_string(out, s),
+            Tag::List(id, items) => {
+                out.push(*id);
+                out.extend_from_slice(&(items.len() as i32).to_be_bytes());
+                for item in items {
+                    item.write_payload(out);
+                }
+            }
+            Tag::Compound(entries) => {
+                for (name, tag) in entries {
+                    out.push(tag.id());
+                    Self::write_string(out, name);
+                    tag.write_payload(out);
+                }
+                out.push(0);
+            }
+        }
+    }
+}
+
+fn ints(values: &[i32]) -> Tag {
+    Tag::List(3, values.iter().map(|v| Tag::Int(*v)).collect())
+}
+
+fn structure_tag(blocks: &[Block], report: &StructureReport, data_version: i32) -> Tag {
+    let mut palette: Vec<(&'static str, &Vec<(&'static str, String)>)> = Vec::new();
+    let mut placed = Vec::new();
+    for block in blocks {
+        let key = (block.name, &block.properties);
+        let state = match palette.iter().position(|p| *p == key) {
+            Some(i) => i,
+            None => {
+                palette.push(key);
+                palette.len() - 1
+            }
+        };
+        placed.push(Tag::Compound(vec![("pos", ints(&block.pos)), ("state", Tag::Int(state as i32))]));
+    }
+
+    let palette = palette.into_iter()
+        .map(|(name, properties)| {
+            let mut entry = vec![("Name", Tag::String(name.to_string()))];
+            if !properties.is_empty() {
+                let properties = properties.iter().map(|(k, v)| (*k, Tag::String(v.clone()))).collect();
+                entry.push(("Properties", Tag::Compound(properties)));
+            }
+            Tag::Compound(entry)
+        })
+        .collect();
+
+    let size: Vec<i32> = report.size.iter().map(|v| *v as i32).collect();
+    Tag::Compound(vec![
+        ("DataVersion", Tag::Int(data_version)),
+        ("size", ints(&size)),
+        ("palette", Tag::List(10, palette)),
+        ("blocks", Tag::List(10, placed)),
+        ("entities", Tag::List(10, Vec::new())),
+    ])
+}
+
+/// Encodes the song as a gzipped vanilla structure file.
+pub fn song_to_structure(song: &Song, options: &StructureOptions) -> Result<(Vec<u8>, StructureReport), Box<dyn std::error::Error>> {
+    let (blocks, report) = layout(song, options)?;
+
+    let mut nbt = vec![10];
+    Tag::write_string(&mut nbt, "");
+    structure_tag(&blocks, &report, options.data_version).write_payload(&mut nbt);
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&nbt)?;
+    Ok((encoder.finish()?, report))
+}
+
+pub fn write_structure(song: &Song, path: &Path, options: &StructureOptions) -> Result<StructureReport, Box<dyn std::error::Error>> {
+    let (data, report) = song_to_structure(song, options)?;
+    std::fs::write(path, data)?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn block_at(blocks: &[Block], pos: [i32; 3]) -> Option<&Block> {
+        blocks.iter().find(|b| b.pos == pos)
+    }
+
+    #[test]
+    fn test_layout_single_line() {
+        let song = Song::from_text("G=G4.I").unwrap();
+        let (blocks, report) = layout(&song, &StructureOptions::default()).unwrap();
+
+        assert_eq!(report.lines, 1);
+        assert_eq!(report.note_blocks, 3);
+        assert_eq!((report.late, report.max_late), (1, 1));
+        assert_eq!(report.size, [6, 2, 5]);
+
+        // tick 0: one repeater, then the branch at x = 2
+        assert_eq!(block_at(&blocks, [1, 1, 2]).unwrap().name, "minecraft:repeater");
+        let note = block_at(&blocks, [2, 1, 0]).unwrap();
+        assert_eq!(note.name, "minecraft:note_block");
+        assert_eq!(note.properties[0], ("instrument", "basedrum".to_string()));
+        assert_eq!(note.properties[1], ("note", "6".to_string()));
+        assert_eq!(block_at(&blocks, [2, 0, 0]).unwrap().name, "minecraft:stone");
+        assert_eq!(block_at(&blocks, [2, 0, 4]).unwrap().name, "minecraft:glowstone");
+
+        // tick 9 is 5 redstone ticks later: repeaters of 4 and 1
+        assert_eq!(block_at(&blocks, [3, 1, 2]).unwrap().properties[0], ("delay", "4".to_string()));
+        assert_eq!(block_at(&blocks, [4, 1, 2]).unwrap().properties[0], ("delay", "1".to_string()));
+        assert_eq!(block_at(&blocks, [5, 1, 4]).unwrap().name, "minecraft:note_block");
+    }
+
+    #[test]
+    fn test_layout_counts_pushed_notes() {
+        // tick 1 is rounded up to 2, so the note on tick 2 moves on to 4
+        let song = Song::from_text(".G.I").unwrap();
+        let (_, report) = layout(&song, &StructureOptions::default()).unwrap();
+        assert_eq!((report.late, report.max_late), (2, 2));
+        assert_eq!(report.moved, 0);
+
+        let (_, report) = layout(&Song::from_text("+G1-G").unwrap(), &StructureOptions::default()).unwrap();
+        assert_eq!(report.moved, 2);
+    }
+
+    #[test]
+    fn test_layout_chords_use_parallel_lines() {
+        let song = Song::from_text("GIKM").unwrap();
+        let (blocks, report) = layout(&song, &StructureOptions::default()).unwrap();
+        assert_eq!(report.lines, 2);
+        assert_eq!(block_at(&blocks, [2, 1, 9]).unwrap().name, "minecraft:note_block");
+
+        let options = StructureOptions { max_width: 5, ..Default::default() };
+        assert_eq!(layout(&song, &options), Err(StructureError::TooManyLines { needed: 2, allowed: 1 }));
+        // two lines are ten blocks wide
+        let options = StructureOptions { max_width: 9, ..Default::default() };
+        assert_eq!(layout(&song, &options), Err(StructureError::TooManyLines { needed: 2, allowed: 1 }));
+        let options = StructureOptions { max_width: 10, ..Default::default() };
+        assert_eq!(layout(&song, &options).unwrap().1.lines, 2);
+        let options = StructureOptions { max_length: Some(2), ..Default::default() };
+        assert_eq!(layout(&song, &options), Err(StructureError::TooLong { length: 3, max_length: 2 }));
+    }
+
+    #[test]
+    fn test_song_to_structure() {
+        let song = Song::from_text("G").unwrap();
+        let (data, _) = song_to_structure(&song, &StructureOptions::default()).unwrap();
+        let mut nbt = Vec::new();
+        GzDecoder::new(&data[..]).read_to_end(&mut nbt).unwrap();
+        assert_eq!(&nbt[..3], &[10, 0, 0]);
+        assert_eq!(&nbt[3..6], &[3, 0, 11]);
+        assert_eq!(*nbt.last().unwrap(), 0);
+    }
+}
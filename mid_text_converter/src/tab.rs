@@ -0,0 +1,228 @@
+use crate::instruments::Track;
+use crate::note::Note;
+use midly::num::u7;
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum TabError {
+    #[error("Invalid tuning: {0}")]
+    InvalidTuning(String),
+    #[error("Tab at line {line} has {found} strings, expected {expected}")]
+    StringCount { line: usize, expected: usize, found: usize },
+    #[error("No tab found")]
+    NoTab,
+}
+
+const NATURALS: [(char, i32); 7] = [('C', 0), ('D', 2), ('E', 4), ('F', 5), ('G', 7), ('A', 9), ('B', 11)];
+
+/// Standard tuning, lowest string first.
+pub const STANDARD_TUNING: [u8; 6] = [40, 45, 50, 55, 59, 64];
+
+/// Characters that can appear on a tab string besides fret numbers.
+const TAB_CHARS: &str = "-|:hpbr/\\~xXsSvt*()<>=.^";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TabOptions {
+    /// Open string keys, lowest string first. The lowest string is the bottom line of the tab.
+    pub tuning: Vec<u8>,
+    /// Ticks each tab column lasts.
+    pub column_ticks: u32,
+}
+
+impl Default for TabOptions {
+    fn default() -> Self {
+        Self { tuning: STANDARD_TUNING.to_vec(), column_ticks: 1 }
+    }
+}
+
+/// Parses a tuning like `EADGBE`, `DADGAD` or `E1 A1 D2 G2`, lowest string first.
+///
+/// A string without an octave is the first key above the previous string, and the lowest
+/// string without an octave is put between B1 and A#2.
+pub fn parse_tuning(text: &str) -> Result<Vec<u8>, TabError> {
+    let invalid = || TabError::InvalidTuning(text.to_string());
+    let mut chars = text.chars().filter(|c| !c.is_whitespace() && *c != ',').peekable();
+    let mut tuning: Vec<u8> = Vec::new();
+    while let Some(c) = chars.next() {
+        let (_, mut pitch) = *NATURALS.iter().find(|(name, _)| *name == c.to_ascii_uppercase()).ok_or_else(invalid)?;
+        match chars.peek() {
+            Some('#') => { pitch += 1; chars.next(); }
+            Some('b') => { pitch -= 1; chars.next(); }
+            _ => {}
+        }
+        let key = match chars.peek().and_then(|c| c.to_digit(10)) {
+            Some(octave) => {
+                chars.next();
+                (octave as i32 + 1) * 12 + pitch
+            }
+            None => {
+                let lowest = tuning.last().map_or(35, |k| *k as i32 + 1);
+                lowest + (pitch - lowest).rem_euclid(12)
+            }
+        };
+        tuning.push(u8::try_from(key).ok().filter(|k| *k < 128).ok_or_else(invalid)?);
+    }
+    if tuning.is_empty() {
+        return Err(invalid());
+    }
+    Ok(tuning)
+}
+
+/// Returns the part of a line after the string name, if the line is a tab string.
+fn tab_body(line: &str) -> Option<&str> {
+    let trimmed = line.trim_start();
+    let mut body = trimmed;
+    if let Some(first) = body.chars().next()
+        && first.is_ascii_alphabetic()
+        && "ABCDEFGabcdefg".contains(first)
+    {
+        let rest = &body[1..];
+        let rest = rest.strip_prefix(['#', 'b']).filter(|r| r.starts_with(['|', ':', '-', ' '])).unwrap_or(rest);
+        body = rest.trim_start();
+    }
+    let body = body.strip_prefix(['|', ':']).unwrap_or(body).trim_end();
+    let dashes = body.chars().filter(|c| *c == '-').count();
+    let valid = body.chars().all(|c| c.is_ascii_digit() || TAB_CHARS.contains(c));
+    (valid && dashes >= 3).then_some(body)
+}
+
+/// Reads every tab system in `text` into one track, and returns it with its length in ticks.
+///
+/// Fret numbers start notes on the column of their first digit, and stacked numbers make
+/// chords. Hammer-ons, pull-offs and slides become plain notes, a number after a bend or
+/// release continues the bent note, and `x` is a muted string. Columns with a bar line and
+/// no fret take no time.
+pub fn parse_tab(text: &str, options: &TabOptions) -> Result<(Track, u32), TabError> {
+    let strings = options.tuning.len();
+    let mut systems: Vec<Vec<Vec<char>>> = Vec::new();
+    let mut current: Vec<Vec<char>> = Vec::new();
+    let mut start = 0;
+    for (i, line) in text.lines().chain(std::iter::once("")).enumerate() {
+        match tab_body(line) {
+            Some(body) => {
+                if current.is_empty() {
+                    start = i + 1;
+                }
+                current.push(body.chars().collect());
+            }
+            None if !current.is_empty() => {
+                if current.len() != strings {
+                    return Err(TabError::StringCount { line: start, expected: strings, found: current.len() });
+                }
+                systems.push(std::mem::take(&mut current));
+            }
+            None => {}
+        }
+    }
+    if systems.is_empty() {
+        return Err(TabError::NoTab);
+    }
+
+    let mut track = Track::new();
+    let mut column_time = 0;
+    for lines in &systems {
+        let width = lines.iter().map(|l| l.len()).max().unwrap_or(0);
+        let at = |line: &Vec<char>, c: usize| line.get(c).copied().unwrap_or('-');
+        for c in 0..width {
+            let has_fret = lines.iter().any(|l| at(l, c).is_ascii_digit());
+            if !has_fret && lines.iter().any(|l| at(l, c) == '|') {
+                continue;
+            }
+            for (i, line) in lines.iter().enumerate() {
+                let starts_number = at(line, c).is_ascii_digit() && (c == 0 || !at(line, c - 1).is_ascii_digit());
+                let bent = c > 0 && matches!(at(line, c - 1), 'b' | 'r');
+                if !starts_number || bent {
+                    continue;
+                }
+                let fret: String = line[c..].iter().take_while(|ch| ch.is_ascii_digit()).take(2).collect();
+                let key = options.tuning[strings - 1 - i] as u32 + fret.parse::<u32>().unwrap_or(0);
+                track.push(Note::new(u7::from(key.min(127) as u8), column_time * options.column_ticks));
+            }
+            column_time += 1;
+        }
+    }
+    track.sort_by_key(|n| (n.start_timing, n.key));
+    Ok((track, column_time * options.column_ticks))
+}
+
+pub fn read_tab(path: &Path, options: &TabOptions) -> Result<(Track, u32), Box<dyn std::error::Error>> {
+    Ok(parse_tab(&std::fs::read_to_string(path)?, options)?)
+}
+
+/// Reads a tab like `mid_to_track`. `riff.tab#DADGAD` replaces the tuning of `options`.
+pub fn tab_to_track(path: &str, options: &TabOptions) -> Result<Track, Box<dyn std::error::Error>> {
+    let (file, tuning) = path.split_once('#').unwrap_or((path, ""));
+    let options = if tuning.is_empty() {
+        options.clone()
+    } else {
+        TabOptions { tuning: parse_tuning(tuning)?, ..options.clone() }
+    };
+    Ok(read_tab(Path::new(file), &options)?.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(track: &Track) -> Vec<(u32, u8)> {
+        track.iter().map(|n| (n.start_timing, n.key.as_int())).collect()
+    }
+
+    #[test]
+    fn test_parse_tuning() {
+        assert_eq!(parse_tuning("EADGBE").unwrap(), STANDARD_TUNING.to_vec());
+        assert_eq!(parse_tuning("DADGAD").unwrap(), vec![38, 45, 50, 55, 57, 62]);
+        assert_eq!(parse_tuning("E1 A1 D2 G2").unwrap(), vec![28, 33, 38, 43]);
+        assert_eq!(parse_tuning("Eb Ab Db Gb Bb Eb").unwrap(), vec![39, 44, 49, 54, 58, 63]);
+        assert!(parse_tuning("EAX").is_err());
+    }
+
+    #[test]
+    fn test_parse_tab() {
+        let text = "\
+Intro riff
+
+e|-----------|--0--|
+B|-----------|--1--|
+G|-----------|--0--|
+D|-----2h4p2-|--2--|
+A|--3/5------|--3--|
+E|-x---------|-----|
+";
+        let (track, end) = parse_tab(text, &TabOptions::default()).unwrap();
+        assert_eq!(keys(&track), vec![(2, 48), (4, 50), (5, 52), (7, 54), (9, 52), (13, 48), (13, 52), (13, 55), (13, 60), (13, 64)]);
+        assert_eq!(end, 16);
+    }
+
+    #[test]
+    fn test_systems_and_bends() {
+        let text = "\
+e|-12b14r12--|
+B|-----------|
+G|-----------|
+D|-----------|
+A|-----------|
+E|-----------|
+
+e|-0--|
+B|----|
+G|----|
+D|----|
+A|----|
+E|-0--|
+";
+        let options = TabOptions { column_ticks: 2, ..TabOptions::default() };
+        let (track, end) = parse_tab(text, &options).unwrap();
+        assert_eq!(keys(&track), vec![(2, 76), (24, 40), (24, 64)]);
+        assert_eq!(end, 30);
+    }
+
+    #[test]
+    fn test_string_count() {
+        let text = "G|--0--|\nD|--2--|\nA|--2--|\nE|--0--|\n";
+        assert_eq!(parse_tab(text, &TabOptions::default()), Err(TabError::StringCount { line: 1, expected: 6, found: 4 }));
+        let options = TabOptions { tuning: parse_tuning("E1A1D2G2").unwrap(), column_ticks: 1 };
+        assert_eq!(keys(&parse_tab(text, &options).unwrap().0), vec![(2, 28), (2, 35), (2, 40), (2, 43)]);
+    }
+}
@@ -0,0 +1,678 @@
+use crate::instruments::{InstrumentKind, Instruments, Track};
+use crate::note::Note;
+use crate::song::Song;
+use crate::utils;
+use midly::num::u7;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum TrackerError {
+    #[error("Not a .mod, .xm or .it module")]
+    UnknownFormat,
+    #[error("Unexpected end of file at byte {0}")]
+    UnexpectedEof(usize),
+    #[error("Invalid instrument mapping: {0}")]
+    InvalidMapping(String),
+}
+
+const DEFAULT_SPEED: u32 = 6;
+const DEFAULT_TEMPO: u32 = 125;
+
+/// Effects that change when rows and notes play.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Effect {
+    None,
+    /// Tracker ticks per row.
+    Speed(u8),
+    /// Beats per minute; one tracker tick lasts 2.5 / BPM seconds.
+    Tempo(u8),
+    /// Continue at this order.
+    Jump(u8),
+    /// Continue at this row of the next pattern.
+    Break(u8),
+    /// Play the note this many tracker ticks into the row.
+    NoteDelay(u8),
+    /// Play the row this many extra times, without retriggering notes.
+    PatternDelay(u8),
+    LoopStart,
+    /// Jump back to the loop start this many times.
+    Loop(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cell {
+    /// MIDI key of a new note.
+    key: Option<u8>,
+    /// Sample or instrument number, 0 if empty.
+    instrument: u16,
+    effect: Effect,
+}
+
+const EMPTY: Cell = Cell { key: None, instrument: 0, effect: Effect::None };
+
+/// A module reduced to what affects note timing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Module {
+    channels: usize,
+    /// Patterns to play in order. `None` is an entry that playback steps over, like `+++` in .it.
+    orders: Vec<Option<usize>>,
+    /// Rows of cells, one per channel.
+    patterns: Vec<Vec<Vec<Cell>>>,
+    speed: u32,
+    tempo: u32,
+    /// Sample or instrument names by number, to help writing the mapping.
+    pub names: Vec<(u16, String)>,
+}
+
+fn byte(data: &[u8], pos: usize) -> Result<u8, TrackerError> {
+    data.get(pos).copied().ok_or(TrackerError::UnexpectedEof(pos))
+}
+
+fn u16_le(data: &[u8], pos: usize) -> Result<u16, TrackerError> {
+    Ok(u16::from_le_bytes([byte(data, pos)?, byte(data, pos + 1)?]))
+}
+
+fn u32_le(data: &[u8], pos: usize) -> Result<u32, TrackerError> {
+    Ok(u32::from_le_bytes([byte(data, pos)?, byte(data, pos + 1)?, byte(data, pos + 2)?, byte(data, pos + 3)?]))
+}
+
+fn text(data: &[u8], pos: usize, len: usize) -> String {
+    let bytes = data.get(pos..pos + len).unwrap_or(&[]);
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).trim().to_string()
+}
+
+/// Effects shared by .mod and .xm, where `D` takes its row as decimal digits.
+fn protracker_effect(effect: u8, param: u8) -> Effect {
+    match (effect, param >> 4) {
+        (0x0B, _) => Effect::Jump(param),
+        (0x0D, _) => Effect::Break((param >> 4) * 10 + (param & 0x0F)),
+        (0x0E, 0x6) if param & 0x0F == 0 => Effect::LoopStart,
+        (0x0E, 0x6) => Effect::Loop(param & 0x0F),
+        (0x0E, 0xD) => Effect::NoteDelay(param & 0x0F),
+        (0x0E, 0xE) => Effect::PatternDelay(param & 0x0F),
+        (0x0F, _) if param == 0 => Effect::None,
+        (0x0F, _) if param < 0x20 => Effect::Speed(param),
+        (0x0F, _) => Effect::Tempo(param),
+        _ => Effect::None,
+    }
+}
+
+/// Key of a ProTracker period, with period 428 (ProTracker C-2) as middle C.
+fn period_key(period: u16) -> Option<u8> {
+    if period == 0 {
+        return None;
+    }
+    let key = 60.0 + 12.0 * (428.0 / period as f64).log2();
+    Some(key.round().clamp(0.0, 127.0) as u8)
+}
+
+fn parse_mod(data: &[u8]) -> Result<Module, TrackerError> {
+    let tag = data.get(1080..1084).ok_or(TrackerError::UnknownFormat)?;
+    let channels = match tag {
+        b"M.K." | b"M!K!" | b"M&K!" | b"FLT4" | b"4CHN" => 4,
+        b"FLT8" => 8,
+        [n, b'C', b'H', b'N'] if n.is_ascii_digit() => (n - b'0') as usize,
+        [a, b, b'C', b'H' | b'N'] if a.is_ascii_digit() && b.is_ascii_digit() => ((a - b'0') * 10 + (b - b'0')) as usize,
+        _ => return Err(TrackerError::UnknownFormat),
+    };
+    let names = (0..31u16)
+        .map(|i| (i + 1, text(data, 20 + i as usize * 30, 22)))
+        .filter(|(_, name)| !name.is_empty())
+        .collect();
+    let length = byte(data, 950)? as usize;
+    let orders: Vec<Option<usize>> = (0..length.min(128)).map(|i| byte(data, 952 + i).map(|p| Some(p as usize))).collect::<Result<_, _>>()?;
+    let pattern_count = (0..128).map(|i| data.get(952 + i).copied().unwrap_or(0) as usize).max().unwrap_or(0) + 1;
+
+    let mut patterns = Vec::with_capacity(pattern_count);
+    for p in 0..pattern_count {
+        let mut rows = Vec::with_capacity(64);
+        for row in 0..64 {
+            let mut cells = Vec::with_capacity(channels);
+            for channel in 0..channels {
+                let pos = 1084 + ((p * 64 + row) * channels + channel) * 4;
+                let (b0, b1, b2, b3) = (byte(data, pos)?, byte(data, pos + 1)?, byte(data, pos + 2)?, byte(data, pos + 3)?);
+                cells.push(Cell {
+                    key: period_key((((b0 & 0x0F) as u16) << 8) | b1 as u16),
+                    instrument: ((b0 & 0xF0) | (b2 >> 4)) as u16,
+                    effect: protracker_effect(b2 & 0x0F, b3),
+                });
+            }
+            rows.push(cells);
+        }
+        patterns.push(rows);
+    }
+    Ok(Module { channels, orders, patterns, speed: DEFAULT_SPEED, tempo: DEFAULT_TEMPO, names })
+}
+
+fn parse_xm(data: &[u8]) -> Result<Module, TrackerError> {
+    let header_size = u32_le(data, 60)? as usize;
+    let length = u16_le(data, 64)? as usize;
+    let channels = u16_le(data, 68)? as usize;
+    let pattern_count = u16_le(data, 70)? as usize;
+    let instrument_count = u16_le(data, 72)? as usize;
+    let speed = u16_le(data, 76)? as u32;
+    let tempo = u16_le(data, 78)? as u32;
+    let orders: Vec<Option<usize>> = (0..length.min(256)).map(|i| byte(data, 80 + i).map(|p| Some(p as usize))).collect::<Result<_, _>>()?;
+
+    let mut pos = 60 + header_size;
+    let mut patterns = Vec::with_capacity(pattern_count);
+    for _ in 0..pattern_count {
+        let length = u32_le(data, pos)? as usize;
+        let row_count = u16_le(data, pos + 5)? as usize;
+        let packed_size = u16_le(data, pos + 7)? as usize;
+        let mut p = pos + length;
+        let end = p + packed_size;
+        let mut rows = vec![vec![EMPTY; channels]; row_count];
+        if packed_size > 0 {
+            for row in rows.iter_mut() {
+                for cell in row.iter_mut() {
+                    let first = byte(data, p)?;
+                    let flags = if first & 0x80 != 0 { p += 1; first } else { 0x1F };
+                    let mut field = |bit: u8| -> Result<u8, TrackerError> {
+                        if flags & bit == 0 {
+                            return Ok(0);
+                        }
+                        p += 1;
+                        byte(data, p - 1)
+                    };
+                    let note = field(0x01)?;
+                    let instrument = field(0x02)?;
+                    field(0x04)?;
+                    let effect = field(0x08)?;
+                    let param = field(0x10)?;
+                    *cell = Cell {
+                        key: (1..=96).contains(&note).then_some(note + 11),
+                        instrument: instrument as u16,
+                        effect: protracker_effect(effect, param),
+                    };
+                }
+            }
+        }
+        patterns.push(rows);
+        pos = end;
+    }
+
+    let mut names = Vec::new();
+    for i in 0..instrument_count {
+        let Ok(size) = u32_le(data, pos) else { break };
+        let name = text(data, pos + 4, 22);
+        if !name.is_empty() {
+            names.push((i as u16 + 1, name));
+        }
+        let samples = u16_le(data, pos + 27).unwrap_or(0) as usize;
+        let sample_header = if samples > 0 { u32_le(data, pos + 29).unwrap_or(40) as usize } else { 0 };
+        pos += size as usize;
+        let mut sample_data = 0;
+        for s in 0..samples {
+            sample_data += u32_le(data, pos + s * sample_header).unwrap_or(0) as usize;
+        }
+        pos += samples * sample_header + sample_data;
+    }
+
+    Ok(Module { channels, orders, patterns, speed, tempo, names })
+}
+
+fn it_effect(command: u8, param: u8) -> Effect {
+    match (command, param >> 4) {
+        (1, _) if param > 0 => Effect::Speed(param),
+        (2, _) => Effect::Jump(param),
+        (3, _) => Effect::Break(param),
+        (19, 0xB) if param & 0x0F == 0 => Effect::LoopStart,
+        (19, 0xB) => Effect::Loop(param & 0x0F),
+        (19, 0xD) => Effect::NoteDelay(param & 0x0F),
+        (19, 0xE) => Effect::PatternDelay(param & 0x0F),
+        (20, _) if param >= 0x20 => Effect::Tempo(param),
+        _ => Effect::None,
+    }
+}
+
+fn parse_it(data: &[u8]) -> Result<Module, TrackerError> {
+    let order_count = u16_le(data, 0x20)? as usize;
+    let instrument_count = u16_le(data, 0x22)? as usize;
+    let sample_count = u16_le(data, 0x24)? as usize;
+    let pattern_count = u16_le(data, 0x26)? as usize;
+    let uses_instruments = u16_le(data, 0x2C)? & 0x04 != 0;
+    let speed = byte(data, 0x32)? as u32;
+    let tempo = byte(data, 0x33)? as u32;
+
+    let mut orders = Vec::new();
+    for i in 0..order_count {
+        match byte(data, 0xC0 + i)? {
+            255 => break,
+            // kept so that position jumps still count it
+            254 => orders.push(None),
+            pattern => orders.push(Some(pattern as usize)),
+        }
+    }
+    let offsets = 0xC0 + order_count;
+    let pattern_offsets = offsets + (instrument_count + sample_count) * 4;
+
+    let (count, table, name_at) = if uses_instruments { (instrument_count, offsets, 0x20) } else { (sample_count, offsets + instrument_count * 4, 0x14) };
+    let mut names = Vec::new();
+    for i in 0..count {
+        let offset = u32_le(data, table + i * 4)? as usize;
+        let name = text(data, offset + name_at, 26);
+        if !name.is_empty() {
+            names.push((i as u16 + 1, name));
+        }
+    }
+
+    let mut channels = 0;
+    let mut patterns = Vec::with_capacity(pattern_count);
+    for i in 0..pattern_count {
+        let offset = u32_le(data, pattern_offsets + i * 4)? as usize;
+        if offset == 0 {
+            patterns.push(vec![vec![EMPTY; 64]; 64]);
+            continue;
+        }
+        let row_count = u16_le(data, offset + 2)? as usize;
+        let mut p = offset + 8;
+        let mut masks = [0u8; 64];
+        let mut last = [EMPTY; 64];
+        let mut last_command = [(0u8, 0u8); 64];
+        let mut rows = vec![vec![EMPTY; 64]; row_count];
+        for row in rows.iter_mut() {
+            loop {
+                let variable = byte(data, p)?;
+                p += 1;
+                if variable == 0 {
+                    break;
+                }
+                let channel = ((variable - 1) & 63) as usize;
+                channels = channels.max(channel + 1);
+                if variable & 0x80 != 0 {
+                    masks[channel] = byte(data, p)?;
+                    p += 1;
+                }
+                let mask = masks[channel];
+                let mut cell = EMPTY;
+                if mask & 0x01 != 0 {
+                    let note = byte(data, p)?;
+                    p += 1;
+                    last[channel].key = (note < 120).then_some(note);
+                    cell.key = last[channel].key;
+                }
+                if mask & 0x02 != 0 {
+                    last[channel].instrument = byte(data, p)? as u16;
+                    p += 1;
+                    cell.instrument = last[channel].instrument;
+                }
+                if mask & 0x04 != 0 {
+                    p += 1;
+                }
+                if mask & 0x08 != 0 {
+                    last_command[channel] = (byte(data, p)?, byte(data, p + 1)?);
+                    p += 2;
+                    cell.effect = it_effect(last_command[channel].0, last_command[channel].1);
+                }
+                if mask & 0x10 != 0 {
+                    cell.key = last[channel].key;
+                }
+                if mask & 0x20 != 0 {
+                    cell.instrument = last[channel].instrument;
+                }
+                if mask & 0x80 != 0 {
+                    cell.effect = it_effect(last_command[channel].0, last_command[channel].1);
+                }
+                row[channel] = cell;
+            }
+        }
+        patterns.push(rows);
+    }
+    for pattern in &mut patterns {
+        for row in pattern.iter_mut() {
+            row.truncate(channels);
+        }
+    }
+    Ok(Module { channels, orders, patterns, speed, tempo, names })
+}
+
+/// Reads a .mod, .xm or .it module, telling them apart by their signatures.
+pub fn parse_module(data: &[u8]) -> Result<Module, TrackerError> {
+    if data.starts_with(b"Extended Module: ") {
+        parse_xm(data)
+    } else if data.starts_with(b"IMPM") {
+        parse_it(data)
+    } else {
+        parse_mod(data)
+    }
+}
+
+/// One played note: `(seconds, instrument number, key)`.
+type Played = (f64, u16, u8);
+
+impl Module {
+    /// Plays the order list and returns every note with its time, and the length of the song in seconds.
+    ///
+    /// Speed and tempo changes, pattern breaks, position jumps, note and pattern delays and
+    /// pattern loops are followed. Playback stops at the end of the order list, or when a jump
+    /// returns to a row that was already played.
+    pub fn play(&self) -> (Vec<Played>, f64) {
+        let (mut speed, mut tempo) = (self.speed.max(1), self.tempo.max(1));
+        let (mut order, mut row) = (0, 0);
+        let mut seconds = 0.0;
+        let mut notes = Vec::new();
+        let mut visited = HashSet::new();
+        let mut last_instrument = vec![0u16; self.channels];
+        let mut loop_start = vec![0usize; self.channels];
+        let mut loop_count = vec![0u8; self.channels];
+
+        while order < self.orders.len() {
+            let Some(pattern) = self.orders[order].and_then(|p| self.patterns.get(p)) else {
+                order += 1;
+                // a break continues at its row in the pattern after the skipped entry
+                continue;
+            };
+            if row >= pattern.len() {
+                order += 1;
+                row = 0;
+                continue;
+            }
+            if !visited.insert((order, row, loop_count.clone())) {
+                break;
+            }
+
+            let cells = &pattern[row];
+            for cell in cells {
+                match cell.effect {
+                    Effect::Speed(s) => speed = s as u32,
+                    Effect::Tempo(t) => tempo = t as u32,
+                    _ => {}
+                }
+            }
+            let tick_length = 2.5 / tempo as f64;
+
+            let (mut jump, mut break_row, mut repeat, mut loop_to) = (None, None, 0, None);
+            for (channel, cell) in cells.iter().enumerate() {
+                let mut delay = 0;
+                match cell.effect {
+                    Effect::Jump(o) => jump = Some(o as usize),
+                    Effect::Break(r) => break_row = Some(r as usize),
+                    Effect::NoteDelay(d) => delay = d as u32,
+                    Effect::PatternDelay(d) => repeat = d as u32,
+                    Effect::LoopStart => loop_start[channel] = row,
+                    Effect::Loop(count) => {
+                        if loop_count[channel] == 0 {
+                            loop_count[channel] = count;
+                            loop_to = Some(loop_start[channel]);
+                        } else {
+                            loop_count[channel] -= 1;
+                            if loop_count[channel] > 0 {
+                                loop_to = Some(loop_start[channel]);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+                if cell.instrument != 0 {
+                    last_instrument[channel] = cell.instrument;
+                }
+                if let Some(key) = cell.key
+                    && delay < speed
+                {
+                    notes.push((seconds + delay as f64 * tick_length, last_instrument[channel], key));
+                }
+            }
+            seconds += (speed * (1 + repeat)) as f64 * tick_length;
+
+            if let Some(target) = loop_to {
+                row = target;
+            } else if jump.is_some() || break_row.is_some() {
+                order = jump.unwrap_or(order + 1);
+                row = break_row.unwrap_or(0);
+            } else {
+                row += 1;
+            }
+        }
+        (notes, seconds)
+    }
+}
+
+/// Which `InstrumentKind` plays each sample or instrument number.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct InstrumentMap {
+    pub kinds: HashMap<u16, InstrumentKind>,
+    /// Kind for numbers not in `kinds`; their notes are dropped if `None`.
+    pub default: Option<InstrumentKind>,
+}
+
+impl InstrumentMap {
+    /// Parses entries like `1=harp`, several separated by commas.
+    pub fn parse(entries: &[String], default: Option<InstrumentKind>) -> Result<Self, TrackerError> {
+        let mut kinds = HashMap::new();
+        for entry in entries.iter().flat_map(|e| e.split(',')).filter(|e| !e.is_empty()) {
+            let invalid = || TrackerError::InvalidMapping(entry.to_string());
+            let (number, name) = entry.split_once('=').ok_or_else(invalid)?;
+            let number = number.trim().parse().map_err(|_| invalid())?;
+            let kind = InstrumentKind::from_name(name.trim()).ok_or_else(invalid)?;
+            kinds.insert(number, kind);
+        }
+        Ok(Self { kinds, default })
+    }
+
+    pub fn kind(&self, instrument: u16) -> Option<InstrumentKind> {
+        self.kinds.get(&instrument).copied().or(self.default)
+    }
+}
+
+/// Converts the module to a song with one track per mapped instrument kind.
+pub fn module_to_song(module: &Module, map: &InstrumentMap) -> Song {
+    let (notes, seconds) = module.play();
+    let mut song = Song::new();
+    for kind in InstrumentKind::ALL {
+        let mut track = Track::new();
+        for (time, instrument, key) in &notes {
+            if map.kind(*instrument) == Some(kind) {
+                track.push(Note::new(u7::from(*key), utils::seconds_to_ticks(*time)));
+            }
+        }
+        if !track.is_empty() {
+            track.sort_by_key(|n| n.start_timing);
+            song.add_track(Instruments::new(kind, track));
+        }
+    }
+    song.end = utils::seconds_to_ticks(seconds);
+    song
+}
+
+pub fn read_module(path: &Path) -> Result<Module, Box<dyn std::error::Error>> {
+    Ok(parse_module(&std::fs::read(path)?)?)
+}
+
+/// Reads a module like `mid_to_track`. `song.xm#3` takes only the notes of instrument 3;
+/// without it every note is in the track.
+pub fn module_to_track(path: &str) -> Result<Track, Box<dyn std::error::Error>> {
+    let (file, selector) = path.split_once('#').unwrap_or((path, ""));
+    let only: Option<u16> = if selector.is_empty() {
+        None
+    } else {
+        Some(selector.parse().map_err(|_| TrackerError::InvalidMapping(selector.to_string()))?)
+    };
+    let (notes, _) = read_module(Path::new(file))?.play();
+    let mut track = Track::new();
+    for (time, instrument, key) in notes {
+        if only.is_none_or(|o| o == instrument) {
+            track.push(Note::new(u7::from(key), utils::seconds_to_ticks(time)));
+        }
+    }
+    track.sort_by_key(|n| n.start_timing);
+    Ok(track)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 4 channel .mod with one pattern, filled by `cells` as `(row, channel, [bytes])`.
+    fn mod_file(cells: &[(usize, usize, [u8; 4])]) -> Vec<u8> {
+        let mut data = vec![0u8; 1084 + 64 * 4 * 4];
+        data[20..25].copy_from_slice(b"piano");
+        data[950] = 2;
+        data[952] = 0;
+        data[953] = 0;
+        data[1080..1084].copy_from_slice(b"M.K.");
+        for (row, channel, bytes) in cells {
+            let pos = 1084 + (row * 4 + channel) * 4;
+            data[pos..pos + 4].copy_from_slice(bytes);
+        }
+        data
+    }
+
+    /// Notes of `play` as `(milliseconds, instrument, key)`.
+    fn played(module: &Module) -> (Vec<(u32, u16, u8)>, u32) {
+        let (notes, seconds) = module.play();
+        let ms = |t: f64| (t * 1000.0).round() as u32;
+        (notes.iter().map(|(t, i, k)| (ms(*t), *i, *k)).collect(), ms(seconds))
+    }
+
+    /// A 2 channel .xm with one instrument and two 4 row patterns, given as packed data.
+    fn xm_file(patterns: &[&[u8]], orders: &[u8]) -> Vec<u8> {
+        let mut data = b"Extended Module: ".to_vec();
+        data.resize(60, 0);
+        data.extend(276u32.to_le_bytes());
+        for value in [orders.len() as u16, 0, 2, patterns.len() as u16, 1, 1, 6, 125] {
+            data.extend(value.to_le_bytes());
+        }
+        let mut table = orders.to_vec();
+        table.resize(256, 0);
+        data.extend(table);
+        for packed in patterns {
+            data.extend(9u32.to_le_bytes());
+            data.push(0);
+            data.extend(4u16.to_le_bytes());
+            data.extend((packed.len() as u16).to_le_bytes());
+            data.extend(*packed);
+        }
+        data.extend(29u32.to_le_bytes());
+        let mut name = b"lead".to_vec();
+        name.resize(22, 0);
+        data.extend(name);
+        data.extend([0, 0, 0]);
+        data
+    }
+
+    #[test]
+    fn test_play_xm() {
+        let first: &[u8] = &[
+            49, 1, 0, 0, 0, 0x98, 0x0F, 0x03, // C-4 unpacked, speed 3
+            0x80, 0x98, 0x0D, 0x01, // break to row 1
+            0x80, 0x80, 0x80, 0x80,
+        ];
+        let second: &[u8] = &[
+            0x83, 51, 1, 0x80, // skipped by the break
+            0x83, 53, 2, 0x98, 0x0B, 0x00, // E-4 with instrument 2, jump to the first order
+            0x80, 0x80, 0x80, 0x80,
+        ];
+        let module = parse_module(&xm_file(&[first, second], &[0, 1])).unwrap();
+        assert_eq!(module.names, vec![(1, "lead".to_string())]);
+        assert_eq!(played(&module), (vec![(0, 1, 60), (120, 2, 64)], 180));
+    }
+
+    /// A .it in sample mode with one sample named "kick", the given orders and patterns of 4 rows.
+    fn it_file(patterns: &[&[u8]], orders: &[u8]) -> Vec<u8> {
+        let mut data = b"IMPM".to_vec();
+        data.resize(0xC0, 0);
+        data[0x20..0x22].copy_from_slice(&(orders.len() as u16).to_le_bytes());
+        data[0x24..0x26].copy_from_slice(&1u16.to_le_bytes());
+        data[0x26..0x28].copy_from_slice(&(patterns.len() as u16).to_le_bytes());
+        data[0x32] = 6;
+        data[0x33] = 125;
+        data.extend(orders);
+        let sample = data.len() + 4 + patterns.len() * 4;
+        data.extend((sample as u32).to_le_bytes());
+        let mut offset = sample + 0x50;
+        for packed in patterns {
+            data.extend((offset as u32).to_le_bytes());
+            offset += 8 + packed.len();
+        }
+        let mut header = b"IMPS".to_vec();
+        header.resize(0x14, 0);
+        header.extend(b"kick");
+        header.resize(0x50, 0);
+        data.extend(header);
+        for packed in patterns {
+            data.extend((packed.len() as u16).to_le_bytes());
+            data.extend(4u16.to_le_bytes());
+            data.extend([0; 4]);
+            data.extend(*packed);
+        }
+        data
+    }
+
+    #[test]
+    fn test_play_it() {
+        let first: &[u8] = &[
+            0x81, 0x0B, 60, 1, 1, 3, 0x82, 0x08, 20, 0x32, 0, // C-5 with speed 3, tempo 50
+            0x81, 0x10, 0x82, 0x08, 3, 0x01, 0, // the last note again, break to row 1
+            0, 0,
+        ];
+        let second: &[u8] = &[
+            0x81, 0x03, 62, 1, 0,
+            0x81, 0x03, 64, 1, 0x82, 0x08, 2, 2, 0, // jump to order 2, counting the +++ entry
+            0, 0,
+        ];
+        let module = parse_module(&it_file(&[first, second], &[0, 254, 1, 255])).unwrap();
+        assert_eq!(module.names, vec![(1, "kick".to_string())]);
+        assert_eq!(played(&module), (vec![(0, 1, 60), (150, 1, 60), (300, 1, 64), (450, 1, 62)], 600));
+    }
+
+    #[test]
+    fn test_period_key() {
+        assert_eq!(period_key(428), Some(60));
+        assert_eq!(period_key(856), Some(48));
+        assert_eq!(period_key(404), Some(61));
+        assert_eq!(period_key(0), None);
+    }
+
+    #[test]
+    fn test_play_mod() {
+        // C-2 with sample 1 on row 0, sample 2 plays 2 ticks late on row 1, speed 3 on row 1,
+        // pattern break to row 62 on row 2, then the pattern plays again from row 62.
+        let data = mod_file(&[
+            (0, 0, [0x01, 0xAC, 0x10, 0x00]),
+            (1, 1, [0x01, 0x94, 0x2E, 0xD2]),
+            (1, 2, [0x00, 0x00, 0x0F, 0x03]),
+            (2, 0, [0x00, 0x00, 0x0D, 0x62]),
+            (63, 0, [0x01, 0xAC, 0x10, 0x00]),
+        ]);
+        let module = parse_module(&data).unwrap();
+        assert_eq!(module.names, vec![(1, "piano".to_string())]);
+        let (notes, seconds) = module.play();
+        // one row at speed 6 lasts 0.12 seconds, at speed 3 0.06
+        let rounded: Vec<(u32, u16, u8)> = notes.iter().map(|(t, i, k)| ((t * 1000.0).round() as u32, *i, *k)).collect();
+        assert_eq!(rounded, vec![(0, 1, 60), (160, 2, 61), (300, 1, 60)]);
+        assert!((seconds - 0.36).abs() < 1e-9);
+
+        let map = InstrumentMap::parse(&["1=harp".to_string()], None).unwrap();
+        let song = module_to_song(&module, &map);
+        assert_eq!(song.to_text(false).unwrap(), ";G3;G.");
+    }
+
+    #[test]
+    fn test_position_jump_stops_loop() {
+        let data = mod_file(&[(0, 0, [0x01, 0xAC, 0x10, 0x00]), (1, 0, [0x00, 0x00, 0x0B, 0x00])]);
+        let (notes, seconds) = parse_module(&data).unwrap().play();
+        assert_eq!(notes.len(), 1);
+        assert!((seconds - 0.24).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_delayed_note_sorted() {
+        // the note on channel 0 is delayed behind the one on channel 1 of the same row
+        let data = mod_file(&[(0, 0, [0x01, 0x94, 0x1E, 0xD5]), (0, 1, [0x01, 0xAC, 0x10, 0x00])]);
+        let map = InstrumentMap::parse(&["1=harp".to_string()], None).unwrap();
+        let song = module_to_song(&parse_module(&data).unwrap(), &map);
+        assert!(song.to_text(false).unwrap().starts_with(";G1;H"));
+    }
+
+    #[test]
+    fn test_instrument_map() {
+        let map = InstrumentMap::parse(&["1=harp,2=bassdrum".to_string()], Some(InstrumentKind::Pling)).unwrap();
+        assert_eq!(map.kind(2), Some(InstrumentKind::BassDrum));
+        assert_eq!(map.kind(5), Some(InstrumentKind::Pling));
+        assert!(InstrumentMap::parse(&["1=kazoo".to_string()], None).is_err());
+    }
+}
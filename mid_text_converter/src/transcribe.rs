@@ -0,0 +1,244 @@
+use crate::instruments::Track;
+use crate::note::Note;
+use crate::samples;
+use crate::utils;
+use midly::num::u7;
+use std::path::Path;
+
+/// Rate the recording is resampled to before analysis.
+const ANALYSIS_RATE: u32 = 16000;
+/// Frames analysed per second.
+const FRAME_RATE: u32 = 100;
+const MIN_FREQUENCY: f32 = 55.0;
+const MAX_FREQUENCY: f32 = 1760.0;
+/// Dip of the normalized difference YIN accepts as the period.
+const YIN_THRESHOLD: f32 = 0.15;
+/// Frames a new semitone has to hold to start a note without a new attack.
+const STABLE_FRAMES: usize = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TranscribeOptions {
+    /// Frames whose pitch confidence (1 - YIN dip) is below this are unvoiced.
+    pub confidence: f32,
+    /// RMS level below which a frame is silent.
+    pub silence: f32,
+    /// Level rise from one frame to the next that starts a new note on the same pitch.
+    pub onset_ratio: f32,
+    /// Notes shorter than this many seconds are dropped.
+    pub min_length: f32,
+}
+
+impl Default for TranscribeOptions {
+    fn default() -> Self {
+        Self { confidence: 0.85, silence: 0.02, onset_ratio: 1.8, min_length: 0.06 }
+    }
+}
+
+/// A note found in the recording.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TranscribedNote {
+    pub tick: u32,
+    /// Length in ticks, at least 1.
+    pub length: u32,
+    pub key: u8,
+    /// Mean pitch confidence of the note's frames.
+    pub confidence: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Frame {
+    /// Seconds at the centre of the frame.
+    time: f64,
+    rms: f32,
+    /// Fractional MIDI key and its confidence, if the frame is voiced.
+    pitch: Option<(f32, f32)>,
+}
+
+/// Finds the pitch of `frame` with YIN. `frame` must hold at least `2 * max_lag` samples.
+/// Returns the frequency and the confidence, 1 minus the normalized difference at the period.
+fn yin(frame: &[f32], sample_rate: u32, min_lag: usize, max_lag: usize) -> Option<(f32, f32)> {
+    let window = frame.len() - max_lag;
+    let mut cmnd = vec![1.0f32; max_lag + 1];
+    let mut sum = 0.0;
+    for lag in 1..=max_lag {
+        let d: f32 = (0..window).map(|j| (frame[j] - frame[j + lag]).powi(2)).sum();
+        sum += d;
+        cmnd[lag] = if sum > 0.0 { d * lag as f32 / sum } else { 1.0 };
+    }
+    let mut lag = (min_lag..=max_lag).find(|l| cmnd[*l] < YIN_THRESHOLD)
+        .or_else(|| (min_lag..=max_lag).min_by(|a, b| cmnd[*a].total_cmp(&cmnd[*b])))?;
+    while lag < max_lag && cmnd[lag + 1] < cmnd[lag] {
+        lag += 1;
+    }
+    let shift = if lag > min_lag && lag < max_lag {
+        let (a, b, c) = (cmnd[lag - 1], cmnd[lag], cmnd[lag + 1]);
+        let denominator = a - 2.0 * b + c;
+        if denominator.abs() > f32::EPSILON { 0.5 * (a - c) / denominator } else { 0.0 }
+    } else {
+        0.0
+    };
+    Some((sample_rate as f32 / (lag as f32 + shift), 1.0 - cmnd[lag].clamp(0.0, 1.0)))
+}
+
+fn frequency_to_key(frequency: f32) -> f32 {
+    69.0 + 12.0 * (frequency / 440.0).log2()
+}
+
+fn analyse(samples: &[f32], sample_rate: u32, options: &TranscribeOptions) -> Vec<Frame> {
+    let min_lag = (sample_rate as f32 / MAX_FREQUENCY) as usize;
+    let max_lag = (sample_rate as f32 / MIN_FREQUENCY).ceil() as usize;
+    let hop = (sample_rate / FRAME_RATE).max(1) as usize;
+    let mut frames = Vec::new();
+    let mut start = 0;
+    while start + 2 * max_lag <= samples.len() {
+        let frame = &samples[start..start + 2 * max_lag];
+        let rms = (frame.iter().map(|s| s * s).sum::<f32>() / frame.len() as f32).sqrt();
+        let pitch = if rms < options.silence {
+            None
+        } else {
+            yin(frame, sample_rate, min_lag, max_lag)
+                .filter(|(_, confidence)| *confidence >= options.confidence)
+                .map(|(frequency, confidence)| (frequency_to_key(frequency), confidence))
+        };
+        frames.push(Frame { time: (start + max_lag) as f64 / sample_rate as f64, rms, pitch });
+        start += hop;
+    }
+    frames
+}
+
+/// Cuts voiced frames into notes. A note starts after silence, on a sudden rise in level,
+/// or when the rounded key changes and holds for a few frames.
+fn segment(frames: &[Frame], options: &TranscribeOptions) -> Vec<(usize, usize)> {
+    let key = |f: &Frame| f.pitch.map(|(k, _)| k.round() as i32);
+    let mut notes = Vec::new();
+    let mut start: Option<usize> = None;
+    for i in 0..frames.len() {
+        let Some(current) = key(&frames[i]) else {
+            if let Some(s) = start.take() {
+                notes.push((s, i));
+            }
+            continue;
+        };
+        let Some(s) = start else {
+            start = Some(i);
+            continue;
+        };
+        let attack = i > 0 && frames[i].rms > frames[i - 1].rms * options.onset_ratio;
+        let note_key = key(&frames[s]);
+        let moved = note_key != Some(current)
+            && frames[i..].iter().take(STABLE_FRAMES).all(|f| key(f) == Some(current))
+            && frames.len() - i >= STABLE_FRAMES;
+        if attack || moved {
+            notes.push((s, i));
+            start = Some(i);
+        }
+    }
+    if let Some(s) = start {
+        notes.push((s, frames.len()));
+    }
+    notes
+}
+
+/// Transcribes a monophonic recording into notes on the tick grid.
+///
+/// Keys are the median rounded key of each note's frames. Notes that land on the same tick
+/// as the previous one are dropped, as the track can only start one of them.
+pub fn transcribe(samples: &[f32], sample_rate: u32, options: &TranscribeOptions) -> Vec<TranscribedNote> {
+    let (samples, sample_rate) = if sample_rate > ANALYSIS_RATE {
+        (samples::resample(samples, sample_rate as f32 / ANALYSIS_RATE as f32), ANALYSIS_RATE)
+    } else {
+        (samples.to_vec(), sample_rate)
+    };
+    let frames = analyse(&samples, sample_rate, options);
+    let frame_seconds = 1.0 / FRAME_RATE as f64;
+    let min_frames = (options.min_length as f64 / frame_seconds).ceil() as usize;
+
+    let mut notes: Vec<TranscribedNote> = Vec::new();
+    for (start, end) in segment(&frames, options) {
+        if end - start < min_frames.max(1) {
+            continue;
+        }
+        let mut keys: Vec<i32> = frames[start..end].iter().filter_map(|f| f.pitch.map(|(k, _)| k.round() as i32)).collect();
+        keys.sort_unstable();
+        let key = keys[keys.len() / 2].clamp(0, 127) as u8;
+        let confidence = frames[start..end].iter().filter_map(|f| f.pitch.map(|(_, c)| c)).sum::<f32>() / keys.len() as f32;
+        let tick = utils::seconds_to_ticks(frames[start].time);
+        let end_tick = utils::seconds_to_ticks(frames[end - 1].time + frame_seconds);
+        if notes.last().is_some_and(|n| n.tick == tick) {
+            continue;
+        }
+        notes.push(TranscribedNote { tick, length: end_tick.saturating_sub(tick).max(1), key, confidence });
+    }
+    notes
+}
+
+pub fn notes_to_track(notes: &[TranscribedNote]) -> Track {
+    Track(notes.iter().map(|n| Note::new(u7::from(n.key.min(127)), n.tick)).collect())
+}
+
+/// Reads a WAV or Ogg Vorbis recording, chosen by extension, and transcribes it.
+pub fn read_recording(path: &Path, options: &TranscribeOptions) -> Result<Vec<TranscribedNote>, Box<dyn std::error::Error>> {
+    let is_ogg = path.extension().is_some_and(|e| e.eq_ignore_ascii_case("ogg"));
+    let (samples, sample_rate) = if is_ogg { samples::read_ogg(path)? } else { samples::read_wav(path)? };
+    Ok(transcribe(&samples, sample_rate, options))
+}
+
+/// Transcribes a recording like `mid_to_track`.
+pub fn recording_to_track(path: &str, options: &TranscribeOptions) -> Result<Track, Box<dyn std::error::Error>> {
+    Ok(notes_to_track(&read_recording(Path::new(path), options)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    const RATE: u32 = 8000;
+
+    /// Sine tones of `(key, seconds, amplitude)` one after another.
+    fn melody(tones: &[(u8, f32, f32)]) -> Vec<f32> {
+        let mut samples = Vec::new();
+        for (key, seconds, amplitude) in tones {
+            let frequency = 440.0 * 2f32.powf((*key as f32 - 69.0) / 12.0);
+            let count = (seconds * RATE as f32) as usize;
+            samples.extend((0..count).map(|i| amplitude * (2.0 * PI * frequency * i as f32 / RATE as f32).sin()));
+        }
+        samples
+    }
+
+    #[test]
+    fn test_yin() {
+        let samples = melody(&[(69, 0.1, 0.5)]);
+        let (frequency, confidence) = yin(&samples[..2 * 146], RATE, 4, 146).unwrap();
+        assert!((frequency - 440.0).abs() < 2.0, "{}", frequency);
+        assert!(confidence > 0.9);
+    }
+
+    #[test]
+    fn test_transcribe_melody() {
+        let samples = melody(&[(0, 0.2, 0.0), (60, 0.5, 0.5), (64, 0.5, 0.5), (0, 0.3, 0.0), (67, 0.5, 0.5), (0, 0.2, 0.0)]);
+        let notes = transcribe(&samples, RATE, &TranscribeOptions::default());
+        let found: Vec<(u32, u8)> = notes.iter().map(|n| (n.tick, n.key)).collect();
+        assert_eq!(found, vec![(4, 60), (14, 64), (30, 67)]);
+        assert!(notes.iter().all(|n| n.confidence > 0.85));
+    }
+
+    #[test]
+    fn test_repeated_note_and_noise() {
+        // the same key struck twice, then noise that has no pitch
+        let mut samples = melody(&[(72, 0.4, 0.1), (72, 0.4, 0.6)]);
+        let mut seed = 1u32;
+        samples.extend((0..RATE / 2).map(|_| {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            (seed >> 8) as f32 / (1 << 23) as f32 - 1.0
+        }));
+        let notes = transcribe(&samples, RATE, &TranscribeOptions::default());
+        let found: Vec<(u32, u8)> = notes.iter().map(|n| (n.tick, n.key)).collect();
+        assert_eq!(found, vec![(0, 72), (8, 72)]);
+    }
+
+    #[test]
+    fn test_low_sample_rate() {
+        assert!(transcribe(&[0.0; 200], 50, &TranscribeOptions::default()).is_empty());
+    }
+}
@@ -1,3 +1,44 @@
+/// Number of output ticks played per second in game.
+pub const TICKS_PER_SECOND: u32 = 20;
+
+/// Number of MIDI ticks that make up one output tick.
+pub const MIDI_TICKS_PER_TICK: u32 = 12;
+
+/// Converts seconds to output ticks, rounding to the nearest tick.
+pub fn seconds_to_ticks(seconds: f64) -> u32 {
+    (seconds * TICKS_PER_SECOND as f64).round().max(0.0) as u32
+}
+
+/// Converts positions in quarter notes to ticks, following tempo changes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TempoMap {
+    initial: f64,
+    /// `(position in quarter notes, quarter notes per minute)`, sorted by position.
+    changes: Vec<(f64, f64)>,
+}
+
+impl TempoMap {
+    pub fn new(initial: f64, mut changes: Vec<(f64, f64)>) -> Self {
+        changes.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Self { initial, changes }
+    }
+
+    pub fn ticks(&self, quarters: f64) -> u32 {
+        let mut seconds = 0.0;
+        let mut position = 0.0;
+        let mut tempo = self.initial;
+        for (at, next) in &self.changes {
+            if *at >= quarters {
+                break;
+            }
+            seconds += (at - position) * 60.0 / tempo;
+            position = *at;
+            tempo = *next;
+        }
+        seconds_to_ticks(seconds + (quarters - position) * 60.0 / tempo)
+    }
+}
+
 pub fn tick_to_string(ticks: u32) -> String {
     if ticks == 0 {
         return String::new();
@@ -27,6 +68,28 @@ pub fn tick_to_string(ticks: u32) -> String {
     result
 }
 
+/// Splits a string into the characters played on each tick.
+/// Returns the `(tick, characters)` groups and the total length in ticks, including trailing silence.
+pub fn split_ticks(text: &str) -> (Vec<(u32, String)>, u32) {
+    let mut groups: Vec<(u32, String)> = Vec::new();
+    let mut current_tick = 0;
+
+    for c in text.chars() {
+        if let Some(digit) = c.to_digit(10) {
+            current_tick += digit * 2;
+        } else if c == '.' {
+            current_tick += 1;
+        } else {
+            match groups.last_mut() {
+                Some((tick, chars)) if *tick == current_tick => chars.push(c),
+                _ => groups.push((current_tick, c.to_string())),
+            }
+        }
+    }
+
+    (groups, current_tick)
+}
+
 pub fn merge_string(tracks: &Vec<String>) -> String {
     fn parse_track(track: &str) -> Vec<(u32, char)> {
         let mut events = Vec::new();
@@ -97,6 +160,23 @@ fn test_tick_conversion() {
     assert_eq!(tick_to_string(38), "991".to_string());
 }
 
+#[test]
+fn test_seconds_to_ticks() {
+    assert_eq!(seconds_to_ticks(0.0), 0);
+    assert_eq!(seconds_to_ticks(1.0), 20);
+    assert_eq!(seconds_to_ticks(0.52), 10);
+    assert_eq!(seconds_to_ticks(-1.0), 0);
+}
+
+#[test]
+fn test_split_ticks() {
+    assert_eq!(split_ticks(""), (vec![], 0));
+    assert_eq!(
+        split_ticks("G@G2I.+K3"),
+        (vec![(0, "G@G".to_string()), (4, "I".to_string()), (5, "+K".to_string())], 11)
+    );
+}
+
 #[test]
 fn test_merge_string() {
     assert_eq!(
@@ -119,3 +199,12 @@ fn test_merge_string() {
         "=GB1B9999999999999999999999999999999999999999999999996=G6=G3=G96=G9=G96=G9=G96=G".to_string()
     );
 }
+
+#[test]
+fn test_tempo_map() {
+    let map = TempoMap::new(120.0, vec![(4.0, 75.0), (0.0, 150.0)]);
+    assert_eq!(map.ticks(1.0), 8);
+    assert_eq!(map.ticks(4.0), 32);
+    assert_eq!(map.ticks(5.0), 48);
+    assert_eq!(TempoMap::new(120.0, Vec::new()).ticks(1.0), 10);
+}